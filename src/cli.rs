@@ -0,0 +1,157 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::golden::GoldenOptions;
+use crate::headless::HeadlessOptions;
+use crate::render::post::PostChain;
+use crate::resources::IdentifierError;
+use crate::scene::SceneKind;
+
+pub const USAGE: &str = "\
+Usage: rust-mc [--headless | --golden] [OPTIONS]
+
+Modes:
+    --headless           Render offscreen and write PNGs instead of opening a window
+    --golden             Render the golden scenes offscreen and compare them to the
+                         reference PNGs, exiting with 1 if any differ
+
+Headless options:
+    --frames N           Number of frames to render, one tick apart (default 1)
+    --size WIDTHxHEIGHT  Size of the rendered frames (default 854x480)
+    --output DIR         Directory the frames are written to (default headless)
+    --post-effect NAME   Post chain to apply, e.g. blur or minecraft:blur
+    --scene NAME         What to draw: empty, block, chunk or gui_text (default chunk)
+
+Golden options:
+    --references DIR     Directory of the reference PNGs (default golden)
+    --output DIR         Directory failed renders and diffs are written to
+                         (default golden_failures)
+    --tolerance N        Largest channel difference a pixel may have (default 2)
+    --bless              Replace the references with what is rendered
+
+    --help               Print this message";
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("unknown argument {0}")]
+    UnknownArgument(String),
+    #[error("{0} needs a value")]
+    MissingValue(&'static str),
+    #[error("invalid value {value:?} for {flag}")]
+    InvalidValue { flag: &'static str, value: String },
+    #[error("--headless and --golden can't be used together")]
+    ConflictingModes,
+    #[error("{flag} can only be used with {mode}")]
+    WrongMode {
+        flag: &'static str,
+        mode: &'static str,
+    },
+    #[error("invalid post effect {name}: {source}")]
+    PostEffect {
+        name: String,
+        source: IdentifierError,
+    },
+}
+
+pub enum Mode {
+    Window,
+    Headless(HeadlessOptions),
+    Golden(GoldenOptions),
+    Help,
+}
+
+// Parses the arguments after the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Mode, CliError> {
+    let mut args = args.into_iter();
+    let mut mode = None;
+    let mut headless = HeadlessOptions::new();
+    let mut golden = GoldenOptions::new();
+    // The first flag given that only one mode uses, an error in the other
+    let mut headless_flag = None;
+    let mut golden_flag = None;
+    let mut output_given = false;
+    while let Some(arg) = args.next() {
+        let flag = match arg.as_str() {
+            "--help" | "-h" => return Ok(Mode::Help),
+            "--headless" | "--golden" => {
+                let flag = if arg == "--headless" {
+                    "--headless"
+                } else {
+                    "--golden"
+                };
+                if mode.replace(flag).is_some_and(|previous| previous != flag) {
+                    return Err(CliError::ConflictingModes);
+                }
+                continue;
+            }
+            "--bless" => {
+                golden.bless = true;
+                golden_flag.get_or_insert("--bless");
+                continue;
+            }
+            "--frames" => "--frames",
+            "--size" => "--size",
+            "--output" => "--output",
+            "--post-effect" => "--post-effect",
+            "--scene" => "--scene",
+            "--references" => "--references",
+            "--tolerance" => "--tolerance",
+            _ => return Err(CliError::UnknownArgument(arg)),
+        };
+        let value = args.next().ok_or(CliError::MissingValue(flag))?;
+        let invalid = || CliError::InvalidValue {
+            flag,
+            value: value.clone(),
+        };
+        match flag {
+            "--frames" => headless.frames = value.parse().map_err(|_| invalid())?,
+            "--size" => {
+                let (width, height) = parse_size(&value).ok_or_else(invalid)?;
+                headless.width = width;
+                headless.height = height;
+            }
+            "--output" => {
+                headless.output = PathBuf::from(&value);
+                golden.output = PathBuf::from(&value);
+                output_given = true;
+                continue;
+            }
+            "--post-effect" => {
+                let effect =
+                    PostChain::identifier(&value).map_err(|source| CliError::PostEffect {
+                        name: value.clone(),
+                        source,
+                    })?;
+                headless.post_effect = Some(effect);
+            }
+            "--scene" => headless.scene = SceneKind::from_name(&value).ok_or_else(invalid)?,
+            "--references" => golden.references = PathBuf::from(&value),
+            _ => golden.tolerance = value.parse().map_err(|_| invalid())?,
+        }
+        if matches!(flag, "--references" | "--tolerance") {
+            golden_flag.get_or_insert(flag);
+        } else {
+            headless_flag.get_or_insert(flag);
+        }
+    }
+    let wrong_mode = |flag, mode| Err(CliError::WrongMode { flag, mode });
+    match (mode, headless_flag, golden_flag) {
+        (Some("--headless"), _, Some(flag)) => wrong_mode(flag, "--golden"),
+        (Some("--headless"), _, None) => Ok(Mode::Headless(headless)),
+        (Some(_), Some(flag), _) => wrong_mode(flag, "--headless"),
+        (Some(_), None, _) => Ok(Mode::Golden(golden)),
+        (None, Some(flag), _) => wrong_mode(flag, "--headless"),
+        (None, None, Some(flag)) => wrong_mode(flag, "--golden"),
+        (None, None, None) if output_given => wrong_mode("--output", "--headless or --golden"),
+        (None, None, None) => Ok(Mode::Window),
+    }
+}
+
+// WIDTHxHEIGHT with both sides above zero
+fn parse_size(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once('x')?;
+    let width = width.parse().ok().filter(|width| *width > 0)?;
+    let height = height.parse().ok().filter(|height| *height > 0)?;
+    Some((width, height))
+}
@@ -1,31 +1,533 @@
-use crate::render::VertexFormat;
-use gl33::global_loader::*;
+use std::fs;
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use gl33::*;
 use glutin::dpi::LogicalSize;
-use glutin::event::{Event, WindowEvent};
+use glutin::event::{ElementState, Event, ModifiersState, VirtualKeyCode, WindowEvent};
 use glutin::event_loop::{ControlFlow, EventLoop};
 use glutin::window::WindowBuilder;
 use glutin::{Api, ContextBuilder, GlRequest};
+use ultraviolet::projection::rh_yup;
+use ultraviolet::{Mat4, Vec2, Vec3};
 
-use crate::resources::{Identifier, Resources};
+use crate::golden::{self, GoldenError, GoldenOptions, Scene};
+use crate::headless::{HeadlessContext, HeadlessError, HeadlessOptions};
+use crate::render::atlas::TextureAtlas;
+use crate::render::chunk::ChunkMesh;
+use crate::render::font::Font;
+use crate::render::post::{PostChain, PostChainError};
+use crate::render::shader::Shader;
+use crate::render::texture::NativeImage;
+use crate::render::uniform::StandardUniforms;
+use crate::render::util::{self, BlendState, GlStateManager, RenderState};
+use crate::render::vertex_buffer::{BufferUsage, VertexBuffer};
+use crate::render::{BufferBuilder, DrawMode, VertexFormat};
+use crate::resources::reload::{ReloadListener, ReloadManager};
+use crate::resources::{Identifier, ResourceManager};
+use crate::scene::SceneKind;
+use crate::screenshot::{self, HugeScreenshot};
+use crate::settings::Settings;
 use crate::types::GLsizei;
 use crate::window::Framebuffer;
 
-pub struct Game {}
+pub struct Game {
+    settings: Settings,
+    resources: Arc<ResourceManager>,
+    reloader: ReloadManager,
+    uniforms: StandardUniforms,
+    scene: SceneKind,
+    // Where the world is drawn from this frame
+    camera: Vec3,
+    started: Instant,
+    // Game ticks run since started
+    ticks: u64,
+    // Created once the GL context exists
+    atlas: Option<TextureAtlas>,
+    blit_shader: Option<Shader>,
+    world_shader: Option<Shader>,
+    font: Option<Font>,
+    // Built from the scene on the first frame after the atlas (re)loads
+    chunk_mesh: Option<ChunkMesh>,
+    gui_buffer: Option<VertexBuffer>,
+    builder: BufferBuilder,
+    // Last post effect picked with F4 and the chain if it loaded
+    post_effect: Option<Identifier>,
+    post_chain: Option<PostChain>,
+    // Taken while drawing the next frame
+    screenshot: Option<ScreenshotRequest>,
+}
 
-fn load_end() {
-    let end_text_ident = Identifier::new("minecraft", "texts/end.txt");
-    let end_text = Resources::get_utf8(&end_text_ident).expect("end texts missing");
-    println!("{}", end_text);
+#[derive(Debug, Clone, Copy)]
+enum ScreenshotRequest {
+    // The main framebuffer as shown, post effects included
+    Window,
+    // The world rendered at HUGE_SCREENSHOT_SCALE times the window size
+    Huge,
+}
+
+fn load_end(resources: &ResourceManager) {
+    let end_text_ident = Identifier::minecraft("texts/end.txt").unwrap();
+    match resources.get_utf8(end_text_ident) {
+        Ok(end_text) => println!("{}", end_text),
+        Err(err) => eprintln!("Unable to load end text: {}", err),
+    }
+}
+
+fn save_screenshot(image: &NativeImage) {
+    match screenshot::save(image, Path::new(screenshot::DIRECTORY)) {
+        Ok(path) => println!("Saved screenshot as {}", path.display()),
+        Err(err) => eprintln!("Couldn't save screenshot: {}", err),
+    }
 }
 
 impl Game {
+    const RESOURCE_PACKS_DIR: &'static str = "resourcepacks";
+    const SETTINGS_FILE: &'static str = "options.txt";
+    const HUGE_SCREENSHOT_SCALE: u32 = 4;
+
     pub fn new() -> Game {
-        Game {}
+        let packs_dir = Path::new(Game::RESOURCE_PACKS_DIR);
+        let mut resources = ResourceManager::new();
+        resources.load_packs(packs_dir);
+        let mut reloader = ReloadManager::new();
+        if packs_dir.is_dir() {
+            reloader.watch(packs_dir);
+        }
+        Game {
+            settings: Settings::load(Path::new(Game::SETTINGS_FILE)),
+            resources: Arc::new(resources),
+            reloader,
+            uniforms: StandardUniforms::new(),
+            scene: SceneKind::Chunk,
+            camera: Vec3::zero(),
+            started: Instant::now(),
+            ticks: 0,
+            atlas: None,
+            blit_shader: None,
+            world_shader: None,
+            font: None,
+            chunk_mesh: None,
+            gui_buffer: None,
+            builder: BufferBuilder::new(4096),
+            post_effect: None,
+            post_chain: None,
+            screenshot: None,
+        }
+    }
+
+    fn reload_resources(&mut self) {
+        let listeners = Game::listeners(
+            &mut self.atlas,
+            [&mut self.blit_shader, &mut self.world_shader],
+            &mut self.font,
+            &mut self.post_chain,
+        );
+        self.reloader.begin(&self.resources, &listeners);
     }
 
-    pub fn start(&mut self) {
-        load_end();
+    fn poll_reload(&mut self, gl: &mut GlStateManager) {
+        let mut listeners = Game::listeners(
+            &mut self.atlas,
+            [&mut self.blit_shader, &mut self.world_shader],
+            &mut self.font,
+            &mut self.post_chain,
+        );
+        if let Some(resources) = self.reloader.poll(gl, &self.resources, &mut listeners) {
+            self.resources = resources;
+            // Sprites may have moved in the atlas
+            self.chunk_mesh = None;
+            self.check_world_shader();
+        }
+    }
+
+    // The world and GUI are built in POSITION_TEXTURE_COLOR, a pack's shader
+    // declaring other attributes would read them wrong
+    fn check_world_shader(&self) {
+        if let Some(shader) = &self.world_shader {
+            if !ptr::eq(shader.format, VertexFormat::POSITION_TEXTURE_COLOR) {
+                eprintln!(
+                    "World shader takes {} rather than {}, the world will draw wrongly",
+                    shader.format.attr_names.join(", "),
+                    VertexFormat::POSITION_TEXTURE_COLOR.attr_names.join(", ")
+                );
+            }
+        }
+    }
+
+    // Steps through 0 to MAX_MIPMAP_LEVELS, saved right away and applied by
+    // stitching the atlas again
+    fn cycle_mipmap_levels(&mut self) {
+        let levels = (self.settings.mipmap_levels + 1) % (Settings::MAX_MIPMAP_LEVELS + 1);
+        self.settings.mipmap_levels = levels;
+        if let Err(err) = self.settings.save(Path::new(Game::SETTINGS_FILE)) {
+            eprintln!("Unable to save settings: {}", err);
+        }
+        if let Some(atlas) = &mut self.atlas {
+            println!("Mipmap levels: {} (was {})", levels, atlas.mipmap_levels());
+            atlas.set_mipmap_levels(levels);
+            self.reload_resources();
+        }
+    }
+
+    // Everything built from resources that currently exists
+    fn listeners<'a>(
+        atlas: &'a mut Option<TextureAtlas>,
+        shaders: [&'a mut Option<Shader>; 2],
+        font: &'a mut Option<Font>,
+        post_chain: &'a mut Option<PostChain>,
+    ) -> Vec<&'a mut dyn ReloadListener> {
+        let mut listeners: Vec<&mut dyn ReloadListener> = Vec::new();
+        if let Some(atlas) = atlas {
+            listeners.push(atlas);
+        }
+        for shader in shaders.into_iter().flatten() {
+            listeners.push(shader);
+        }
+        if let Some(font) = font {
+            listeners.push(font);
+        }
+        if let Some(chain) = post_chain {
+            listeners.push(chain);
+        }
+        listeners
+    }
+
+    const TICKS_PER_SECOND: f32 = 20.0;
+    const TICKS_PER_DAY: f32 = 24000.0;
+    // Ticks skipped rather than run when a frame takes too long
+    const MAX_TICKS_PER_FRAME: u64 = 10;
+    // Frames are drawn at most this often, the loop sleeps in between
+    const FRAME_INTERVAL: Duration = Duration::from_micros(16_667);
+
+    // Game ticks since started, including the part of the current one
+    fn elapsed_ticks(&self) -> f32 {
+        self.started.elapsed().as_secs_f32() * Game::TICKS_PER_SECOND
+    }
+
+    // Runs every game tick that has elapsed since the last frame
+    fn run_ticks(&mut self, gl: &mut GlStateManager) {
+        let elapsed = self.elapsed_ticks() as u64;
+        if elapsed - self.ticks > Game::MAX_TICKS_PER_FRAME {
+            self.ticks = elapsed - Game::MAX_TICKS_PER_FRAME;
+        }
+        self.run_ticks_until(gl, elapsed);
+    }
+
+    fn run_ticks_until(&mut self, gl: &mut GlStateManager, ticks: u64) {
+        while self.ticks < ticks {
+            self.tick(gl);
+            self.ticks += 1;
+        }
+    }
+
+    fn tick(&mut self, gl: &mut GlStateManager) {
+        if let Some(atlas) = &mut self.atlas {
+            atlas.tick(gl)
+        }
+    }
+
+    // Switches to the next post chain in shaders/post, or to none after the last
+    fn cycle_post_effect(&mut self, gl: &mut GlStateManager, width: GLsizei, height: GLsizei) {
+        let effects = self.resources.list(PostChain::DIRECTORY, ".json");
+        let next = match self.post_effect {
+            Some(current) => effects
+                .iter()
+                .position(|effect| *effect == current)
+                .map_or(0, |index| index + 1),
+            None => 0,
+        };
+        self.post_chain = None;
+        self.post_effect = effects.get(next).copied();
+        if let Some(effect) = self.post_effect {
+            match PostChain::load(gl, &self.resources, effect, width, height) {
+                Ok(chain) => self.post_chain = Some(chain),
+                Err(err) => eprintln!("Unable to load post effect: {}", err),
+            }
+        }
+    }
+
+    const FOV: f32 = 70.0;
+    const NEAR: f32 = 0.05;
+    const FAR: f32 = 256.0;
+    // GUI pixels are this many screen pixels wide
+    const GUI_SCALE: f32 = 2.0;
+    const GUI_MARGIN: f32 = 4.0;
+
+    // Draws the world and then the GUI into the bound main framebuffer
+    // zoom is applied after the projection to draw part of the view, the
+    // identity draws all of it
+    fn render(&mut self, gl: &mut GlStateManager, zoom: Mat4) {
+        let size = self.uniforms.screen_size;
+        let projection = rh_yup::perspective_gl(
+            Game::FOV.to_radians(),
+            size.x / size.y,
+            Game::NEAR,
+            Game::FAR,
+        );
+        self.uniforms.proj_mat = zoom * projection;
+        self.render_world(gl);
+        self.render_gui(gl, zoom);
+    }
+
+    fn render_world(&mut self, gl: &mut GlStateManager) {
+        let (shader, atlas) = match (&mut self.world_shader, &self.atlas) {
+            (Some(shader), Some(atlas)) => (shader, atlas),
+            _ => return,
+        };
+        if self.chunk_mesh.is_none() {
+            if let Some(section) = self.scene.section() {
+                match ChunkMesh::build(gl, &mut self.builder, &section, atlas, self.camera) {
+                    Ok(mesh) => self.chunk_mesh = Some(mesh),
+                    Err(err) => eprintln!("Unable to build the chunk mesh: {}", err),
+                }
+            }
+        }
+        if let Some(mesh) = &mut self.chunk_mesh {
+            if let Err(err) = mesh.resort(&mut self.builder, self.camera) {
+                eprintln!("Unable to sort the chunk mesh: {}", err);
+            }
+            mesh.draw(gl, shader, &self.uniforms);
+        }
+    }
+
+    // Draws the scene's text over the world in GUI pixels, the origin at the
+    // top left of the screen
+    fn render_gui(&mut self, gl: &mut GlStateManager, zoom: Mat4) {
+        let lines = self.scene.text();
+        let (shader, font) = match (&mut self.world_shader, &self.font) {
+            (Some(shader), Some(font)) if !lines.is_empty() => (shader, font),
+            _ => return,
+        };
+        let builder = &mut self.builder;
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION_TEXTURE_COLOR);
+        let mut y = Game::GUI_MARGIN;
+        for (line, color) in lines {
+            if let Err(err) = font.draw(builder, line, Game::GUI_MARGIN, y, *color) {
+                eprintln!("Unable to draw GUI text: {}", err);
+            }
+            y += Font::LINE_HEIGHT;
+        }
+        if let Err(err) = builder.end() {
+            eprintln!("Unable to draw GUI text: {}", err);
+            builder.reset();
+            return;
+        }
+        let buffer = self
+            .gui_buffer
+            .get_or_insert_with(|| VertexBuffer::new(gl, BufferUsage::Dynamic));
+        if let Some((parameters, data)) = builder.pop_data() {
+            buffer.upload(parameters, data);
+        }
+        let size = self.uniforms.screen_size / Game::GUI_SCALE;
+        let mut uniforms = self.uniforms.clone();
+        uniforms.proj_mat = zoom * rh_yup::orthographic_gl(0.0, size.x, size.y, 0.0, -1.0, 1.0);
+        uniforms.model_view_mat = Mat4::identity();
+        shader.set_sampler("Sampler0", font.texture().0);
+        let state = RenderState {
+            blend: Some(BlendState::TRANSLUCENT),
+            ..RenderState::OVERLAY
+        };
+        buffer.draw_with_shader(&mut gl.push_render_state(&state), shader, &uniforms);
+    }
+
+    fn render_to(&mut self, gl: &mut GlStateManager, fb: &Framebuffer, zoom: Mat4) {
+        fb.clear(gl);
+        fb.bind(gl, true);
+        self.render(gl, zoom);
+        fb.unbind(gl);
+    }
+
+    fn take_huge_screenshot(&mut self, gl: &mut GlStateManager, fb: &Framebuffer) {
+        let (width, height) = fb.viewport_size();
+        let (width, height) = (width as u32, height as u32);
+        let mut huge = HugeScreenshot::new(
+            width * Game::HUGE_SCREENSHOT_SCALE,
+            height * Game::HUGE_SCREENSHOT_SCALE,
+            width,
+            height,
+        );
+        while let Some(zoom) = huge.tile_projection() {
+            self.render_to(gl, fb, zoom);
+            huge.capture(gl, fb);
+        }
+        save_screenshot(&huge.finish());
+    }
+
+    // Updates the camera and the uniforms shared by every shader for the
+    // next frame
+    fn update_uniforms(&mut self, ticks: f32, width: u32, height: u32) {
+        let (eye, target) = self.scene.camera(ticks);
+        self.camera = eye;
+        self.uniforms.model_view_mat = Mat4::look_at(eye, target, Vec3::unit_y());
+        self.uniforms.game_time = (ticks % Game::TICKS_PER_DAY) / Game::TICKS_PER_DAY;
+        self.uniforms.screen_size = Vec2::new(width as f32, height as f32);
+    }
+
+    // Loads everything that needs the GL context once it is current
+    fn init_gl(&mut self, gl: &mut GlStateManager) {
+        self.atlas = Some(TextureAtlas::load(
+            gl,
+            &self.resources,
+            self.settings.mipmap_levels,
+        ));
+        match Shader::load(gl, &self.resources, "blit_screen") {
+            Ok(shader) => self.blit_shader = Some(shader),
+            Err(err) => eprintln!("Unable to load blit shader: {}", err),
+        }
+        match Shader::load(gl, &self.resources, "position_tex_color") {
+            Ok(shader) => self.world_shader = Some(shader),
+            Err(err) => eprintln!("Unable to load world shader: {}", err),
+        }
+        self.check_world_shader();
+        match Font::load(gl, &self.resources) {
+            Ok(font) => self.font = Some(font),
+            Err(err) => eprintln!("Unable to load font: {}", err),
+        }
+    }
+
+    // Frees everything init_gl and the post chain created, while the
+    // context is still current
+    fn release_gl(&mut self, gl: &mut GlStateManager) {
+        self.post_chain = None;
+        self.gui_buffer = None;
+        self.chunk_mesh = None;
+        self.font = None;
+        self.world_shader = None;
+        self.blit_shader = None;
+        self.atlas = None;
+        gl.delete_shared_indices();
+    }
+
+    // Renders options.frames frames one tick apart without a window and
+    // writes each to options.output as a PNG
+    pub fn run_headless(mut self, options: &HeadlessOptions) -> Result<(), HeadlessError> {
+        let context = HeadlessContext::new(options.width, options.height)?;
+        let mut gl = unsafe { util::load_gl(|name| context.get_proc_address(name)) };
+        self.scene = options.scene;
+        self.init_gl(&mut gl);
+        let result = self.render_headless(&mut gl, options);
+        self.release_gl(&mut gl);
+        result
+    }
+
+    fn render_headless(
+        &mut self,
+        gl: &mut GlStateManager,
+        options: &HeadlessOptions,
+    ) -> Result<(), HeadlessError> {
+        let (width, height) = (options.width as GLsizei, options.height as GLsizei);
+        let mut fb = Framebuffer::new(gl, width, height)?;
+        if let Some(effect) = options.post_effect {
+            self.load_post_effect(gl, effect, width, height)?;
+        }
+        fs::create_dir_all(&options.output).map_err(|source| HeadlessError::Io {
+            path: options.output.clone(),
+            source,
+        })?;
+        for frame in 0..options.frames {
+            let image = self.render_frame(gl, &mut fb, frame as u64);
+            screenshot::write(&image, &options.frame_path(frame))?;
+        }
+        Ok(())
+    }
+
+    fn load_post_effect(
+        &mut self,
+        gl: &mut GlStateManager,
+        effect: Identifier,
+        width: GLsizei,
+        height: GLsizei,
+    ) -> Result<(), PostChainError> {
+        let chain = PostChain::load(gl, &self.resources, effect, width, height)?;
+        self.post_effect = Some(effect);
+        self.post_chain = Some(chain);
+        Ok(())
+    }
+
+    // Runs the game up to ticks and reads back the frame drawn then, post
+    // effect included
+    fn render_frame(
+        &mut self,
+        gl: &mut GlStateManager,
+        fb: &mut Framebuffer,
+        ticks: u64,
+    ) -> NativeImage {
+        let (width, height) = fb.viewport_size();
+        self.run_ticks_until(gl, ticks);
+        self.update_uniforms(ticks as f32, width as u32, height as u32);
+        self.render_to(gl, fb, Mat4::identity());
+        if let Some(chain) = &mut self.post_chain {
+            let seconds = ticks as f32 / Game::TICKS_PER_SECOND;
+            chain.process(gl, fb, &self.uniforms, seconds.fract());
+        }
+        screenshot::read_pixels(gl, fb)
+    }
+
+    // Renders every golden scene and compares it to its reference, printing
+    // the outcome of each. A scene that fails to render or compare fails on
+    // its own and the rest still run. Returns whether all of them passed
+    pub fn run_golden(mut self, options: &GoldenOptions) -> Result<bool, GoldenError> {
+        let context = HeadlessContext::new(
+            HeadlessOptions::DEFAULT_WIDTH,
+            HeadlessOptions::DEFAULT_HEIGHT,
+        )?;
+        let mut gl = unsafe { util::load_gl(|name| context.get_proc_address(name)) };
+        let mut passed = true;
+        for scene in golden::SCENES {
+            let outcome = self
+                .render_scene(&mut gl, scene)
+                .and_then(|image| golden::check(scene, &image, options));
+            self.release_gl(&mut gl);
+            match outcome {
+                Ok(outcome) => {
+                    println!("{}: {}", scene.name, outcome);
+                    passed &= outcome.passed();
+                }
+                Err(err) => {
+                    println!("{}: failed: {}", scene.name, err);
+                    passed = false;
+                }
+            }
+        }
+        Ok(passed)
+    }
+
+    // Every scene starts from tick 0 with freshly loaded resources so it
+    // renders the same whatever ran before it
+    fn render_scene(
+        &mut self,
+        gl: &mut GlStateManager,
+        scene: &Scene,
+    ) -> Result<NativeImage, GoldenError> {
+        self.ticks = 0;
+        self.scene = scene.kind;
+        self.init_gl(gl);
+        let (width, height) = (scene.width as GLsizei, scene.height as GLsizei);
+        let mut fb =
+            Framebuffer::new(gl, width, height).map_err(|source| GoldenError::Framebuffer {
+                scene: scene.name,
+                source,
+            })?;
+        if let Some(name) = scene.post_effect {
+            let effect =
+                PostChain::identifier(name).map_err(|source| GoldenError::PostEffectName {
+                    scene: scene.name,
+                    source,
+                })?;
+            self.load_post_effect(gl, effect, width, height)
+                .map_err(|source| GoldenError::PostEffect {
+                    scene: scene.name,
+                    source,
+                })?;
+        }
+        Ok(self.render_frame(gl, &mut fb, scene.ticks))
+    }
+
+    pub fn start(mut self) {
+        load_end(&self.resources);
         let el = EventLoop::new();
         let wb = WindowBuilder::new()
             .with_title("Rust MC")
@@ -36,36 +538,118 @@ impl Game {
             .build_windowed(wb, &el)
             .unwrap();
         let context = unsafe { context.make_current().unwrap() };
-        unsafe {
-            load_global_gl(&|ptr| {
-                let c_str = std::ffi::CStr::from_ptr(ptr as *const i8);
-                let r_str = c_str.to_str().unwrap();
-                context.get_proc_address(r_str) as _
-            });
-        }
+        let mut gl = unsafe { util::load_gl(|name| context.get_proc_address(name)) };
+        self.init_gl(&mut gl);
+
+        let fb_size = context.window().inner_size();
 
-        let mut fb_size = context.window().inner_size();
+        let mut fb = Framebuffer::new(&mut gl, fb_size.width as GLsizei, fb_size.height as GLsizei)
+            .expect("Unable to create the main framebuffer");
 
-        let fb = Framebuffer::new(fb_size.width as GLsizei, fb_size.height as GLsizei);
+        let mut debug_key_held = false;
+        let mut modifiers = ModifiersState::empty();
+        let mut next_frame = Instant::now();
 
         el.run(move |event, _, control_flow| {
-            *control_flow = ControlFlow::Wait;
+            *control_flow = ControlFlow::WaitUntil(next_frame);
 
             match event {
-                Event::LoopDestroyed => return,
+                Event::LoopDestroyed => self.release_gl(&mut gl),
                 Event::WindowEvent { event, .. } => match event {
-                    WindowEvent::Resized(physical_size) => context.resize(physical_size),
+                    WindowEvent::Resized(physical_size) => {
+                        context.resize(physical_size);
+                        // Minimising reports a zero size, keep the old attachments
+                        if physical_size.width > 0 && physical_size.height > 0 {
+                            let width = physical_size.width as GLsizei;
+                            let height = physical_size.height as GLsizei;
+                            if let Err(err) = fb.resize(&mut gl, width, height) {
+                                eprintln!("Unable to resize the main framebuffer: {}", err);
+                            }
+                            if let Some(chain) = &mut self.post_chain {
+                                if let Err(err) = chain.resize(&mut gl, width, height) {
+                                    eprintln!("Disabling post effect {}: {}", chain.name(), err);
+                                    self.post_chain = None;
+                                }
+                            }
+                        }
+                    }
                     WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                    WindowEvent::ModifiersChanged(state) => modifiers = state,
                     WindowEvent::KeyboardInput { input, .. } => {
-                        println!("{}", input.scancode);
+                        let pressed = input.state == ElementState::Pressed;
+                        match input.virtual_keycode {
+                            Some(VirtualKeyCode::F3) => debug_key_held = pressed,
+                            // F3 + T reloads resource packs
+                            Some(VirtualKeyCode::T) if pressed && debug_key_held => {
+                                self.reload_resources()
+                            }
+                            // F3 + M changes the mipmap levels of the atlas
+                            Some(VirtualKeyCode::M) if pressed && debug_key_held => {
+                                self.cycle_mipmap_levels()
+                            }
+                            // F2 takes a screenshot, Ctrl + F2 a huge one
+                            Some(VirtualKeyCode::F2) if pressed => {
+                                self.screenshot = Some(if modifiers.ctrl() {
+                                    ScreenshotRequest::Huge
+                                } else {
+                                    ScreenshotRequest::Window
+                                })
+                            }
+                            Some(VirtualKeyCode::F4) if pressed => {
+                                let size = context.window().inner_size();
+                                self.cycle_post_effect(
+                                    &mut gl,
+                                    size.width as GLsizei,
+                                    size.height as GLsizei,
+                                )
+                            }
+                            _ => (),
+                        }
                     }
                     _ => (),
                 },
-                Event::RedrawRequested(_) => unsafe {
-                    glClearColor(1f32, 1f32, 1f32, 1f32);
-                    glClear(GL_COLOR_BUFFER_BIT);
+                // Input wakes the loop early, only draw once the frame is due
+                Event::MainEventsCleared if Instant::now() >= next_frame => {
+                    next_frame = Instant::now() + Game::FRAME_INTERVAL;
+                    *control_flow = ControlFlow::WaitUntil(next_frame);
+                    self.poll_reload(&mut gl);
+                    self.run_ticks(&mut gl);
+                    context.window().request_redraw();
+                }
+                Event::RedrawRequested(_) => {
+                    let size = context.window().inner_size();
+                    self.update_uniforms(self.elapsed_ticks(), size.width, size.height);
+                    let screenshot = self.screenshot.take();
+                    if let Some(ScreenshotRequest::Huge) = screenshot {
+                        self.take_huge_screenshot(&mut gl, &fb);
+                    }
+                    self.render_to(&mut gl, &fb, Mat4::identity());
+                    if let Some(chain) = &mut self.post_chain {
+                        chain.process(
+                            &mut gl,
+                            &mut fb,
+                            &self.uniforms,
+                            self.started.elapsed().as_secs_f32().fract(),
+                        );
+                    }
+                    if let Some(ScreenshotRequest::Window) = screenshot {
+                        save_screenshot(&screenshot::read_pixels(&mut gl, &fb));
+                    }
+                    let backend = gl.backend().clone();
+                    backend.clear_color(0.0, 0.0, 0.0, 1.0);
+                    backend.clear(GL_COLOR_BUFFER_BIT);
+                    if let Some(shader) = &mut self.blit_shader {
+                        fb.draw(
+                            &mut gl,
+                            shader,
+                            &self.uniforms,
+                            size.width as GLsizei,
+                            size.height as GLsizei,
+                            true,
+                        );
+                    }
                     context.swap_buffers().unwrap();
-                },
+                }
                 _ => (),
             }
         });
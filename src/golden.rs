@@ -0,0 +1,326 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::headless::HeadlessError;
+use crate::render::post::PostChainError;
+use crate::render::texture::{ImageError, NativeImage};
+use crate::resources::IdentifierError;
+use crate::scene::SceneKind;
+use crate::screenshot::{self, ScreenshotError};
+use crate::window::FramebufferError;
+
+#[derive(Debug, Error)]
+pub enum GoldenError {
+    #[error(transparent)]
+    Headless(#[from] HeadlessError),
+    #[error("unable to create the framebuffer for scene {scene}: {source}")]
+    Framebuffer {
+        scene: &'static str,
+        source: FramebufferError,
+    },
+    #[error("invalid post effect in scene {scene}: {source}")]
+    PostEffectName {
+        scene: &'static str,
+        source: IdentifierError,
+    },
+    #[error("unable to load post effect for scene {scene}: {source}")]
+    PostEffect {
+        scene: &'static str,
+        source: PostChainError,
+    },
+    #[error("unable to read reference image {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("invalid reference image {path}: {source}")]
+    Reference { path: PathBuf, source: ImageError },
+    #[error("unable to create directory {path}: {source}")]
+    Directory { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    Screenshot(#[from] ScreenshotError),
+}
+
+// A fixed scene rendered from the fixed camera and compared to
+// <references>/<name>.png
+#[derive(Debug, Clone, Copy)]
+pub struct Scene {
+    pub name: &'static str,
+    pub width: u32,
+    pub height: u32,
+    // Game ticks run before the frame is captured
+    pub ticks: u64,
+    // Short post chain name, see PostChain::identifier
+    pub post_effect: Option<&'static str>,
+    pub kind: SceneKind,
+}
+
+pub const SCENES: &[Scene] = &[
+    Scene {
+        name: "empty_world",
+        width: 320,
+        height: 240,
+        ticks: 0,
+        post_effect: None,
+        kind: SceneKind::Empty,
+    },
+    Scene {
+        name: "block",
+        width: 320,
+        height: 240,
+        ticks: 0,
+        post_effect: None,
+        kind: SceneKind::Block,
+    },
+    // Stone seen through plain and stained glass and the glass through
+    // each other
+    Scene {
+        name: "chunk",
+        width: 320,
+        height: 240,
+        ticks: 0,
+        post_effect: None,
+        kind: SceneKind::Chunk,
+    },
+    Scene {
+        name: "gui_text",
+        width: 320,
+        height: 240,
+        ticks: 0,
+        post_effect: None,
+        kind: SceneKind::GuiText,
+    },
+    Scene {
+        name: "post_blur",
+        width: 320,
+        height: 240,
+        ticks: 0,
+        post_effect: Some("minecraft:blur"),
+        kind: SceneKind::Chunk,
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct GoldenOptions {
+    // Checked in reference PNGs
+    pub references: PathBuf,
+    // Where the rendered image and a diff go when a scene fails
+    pub output: PathBuf,
+    // Largest difference allowed in any channel of a pixel
+    pub tolerance: u8,
+    // Overwrite the references with what is rendered instead of comparing
+    pub bless: bool,
+}
+
+impl GoldenOptions {
+    pub const DEFAULT_REFERENCES: &'static str = "golden";
+    pub const DEFAULT_OUTPUT: &'static str = "golden_failures";
+    pub const DEFAULT_TOLERANCE: u8 = 2;
+
+    pub fn new() -> GoldenOptions {
+        GoldenOptions {
+            references: PathBuf::from(GoldenOptions::DEFAULT_REFERENCES),
+            output: PathBuf::from(GoldenOptions::DEFAULT_OUTPUT),
+            tolerance: GoldenOptions::DEFAULT_TOLERANCE,
+            bless: false,
+        }
+    }
+
+    pub fn reference_path(&self, scene: &Scene) -> PathBuf {
+        self.references.join(format!("{}.png", scene.name))
+    }
+}
+
+impl Default for GoldenOptions {
+    fn default() -> GoldenOptions {
+        GoldenOptions::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Passed,
+    Blessed(PathBuf),
+    MissingReference(PathBuf),
+    SizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+        image: PathBuf,
+    },
+    Mismatch {
+        mismatched: u64,
+        max_difference: u8,
+        image: PathBuf,
+        diff: PathBuf,
+    },
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        matches!(self, Outcome::Passed | Outcome::Blessed(_))
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::Blessed(path) => write!(f, "blessed {}", path.display()),
+            Outcome::MissingReference(path) => {
+                write!(f, "no reference at {}, run with --bless", path.display())
+            }
+            Outcome::SizeMismatch {
+                expected,
+                actual,
+                image,
+            } => write!(
+                f,
+                "rendered {}x{} but the reference is {}x{}, see {}",
+                actual.0,
+                actual.1,
+                expected.0,
+                expected.1,
+                image.display()
+            ),
+            Outcome::Mismatch {
+                mismatched,
+                max_difference,
+                image,
+                diff,
+            } => write!(
+                f,
+                "{} pixels differ by up to {}, see {} and {}",
+                mismatched,
+                max_difference,
+                image.display(),
+                diff.display()
+            ),
+        }
+    }
+}
+
+// Pixels of actual further than tolerance from expected in any channel
+pub struct Comparison {
+    pub mismatched: u64,
+    pub max_difference: u8,
+    // Mismatched pixels in red over a faded grayscale copy of actual
+    pub diff: NativeImage,
+}
+
+// Both images have to be the same size
+pub fn compare(expected: &NativeImage, actual: &NativeImage, tolerance: u8) -> Comparison {
+    let mut diff = NativeImage::new(actual.width(), actual.height());
+    let mut mismatched = 0;
+    let mut max_difference = 0;
+    for y in 0..actual.height() {
+        for x in 0..actual.width() {
+            let expected_pixel = expected.pixel(x, y);
+            let actual_pixel = actual.pixel(x, y);
+            let difference = expected_pixel
+                .iter()
+                .zip(actual_pixel)
+                .map(|(a, b)| a.abs_diff(b))
+                .max()
+                .unwrap_or(0);
+            max_difference = max_difference.max(difference);
+            let pixel = if difference > tolerance {
+                mismatched += 1;
+                [0xFF, 0, 0, 0xFF]
+            } else {
+                let [r, g, b, _] = actual_pixel.map(u32::from);
+                let gray = ((r * 3 + g * 6 + b) / 10 / 4 + 0xC0) as u8;
+                [gray, gray, gray, 0xFF]
+            };
+            diff.set_pixel(x, y, pixel);
+        }
+    }
+    Comparison {
+        mismatched,
+        max_difference,
+        diff,
+    }
+}
+
+// Compares a rendered scene to its reference, or replaces the reference
+// when blessing. Failures leave <name>.png and <name>_diff.png in output
+pub fn check(
+    scene: &Scene,
+    image: &NativeImage,
+    options: &GoldenOptions,
+) -> Result<Outcome, GoldenError> {
+    let reference_path = options.reference_path(scene);
+    if options.bless {
+        create_dir(&options.references)?;
+        screenshot::write(image, &reference_path)?;
+        return Ok(Outcome::Blessed(reference_path));
+    }
+    let data = match fs::read(&reference_path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(Outcome::MissingReference(reference_path))
+        }
+        Err(source) => {
+            return Err(GoldenError::Read {
+                path: reference_path,
+                source,
+            })
+        }
+    };
+    let reference = NativeImage::decode(&data).map_err(|source| GoldenError::Reference {
+        path: reference_path,
+        source,
+    })?;
+    let image_path = options.output.join(format!("{}.png", scene.name));
+    if (reference.width(), reference.height()) != (image.width(), image.height()) {
+        create_dir(&options.output)?;
+        screenshot::write(image, &image_path)?;
+        return Ok(Outcome::SizeMismatch {
+            expected: (reference.width(), reference.height()),
+            actual: (image.width(), image.height()),
+            image: image_path,
+        });
+    }
+    let comparison = compare(&reference, image, options.tolerance);
+    if comparison.mismatched == 0 {
+        return Ok(Outcome::Passed);
+    }
+    let diff_path = options.output.join(format!("{}_diff.png", scene.name));
+    create_dir(&options.output)?;
+    screenshot::write(image, &image_path)?;
+    screenshot::write(&comparison.diff, &diff_path)?;
+    Ok(Outcome::Mismatch {
+        mismatched: comparison.mismatched,
+        max_difference: comparison.max_difference,
+        image: image_path,
+        diff: diff_path,
+    })
+}
+
+fn create_dir(path: &Path) -> Result<(), GoldenError> {
+    fs::create_dir_all(path).map_err(|source| GoldenError::Directory {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Game;
+
+    #[test]
+    fn renders_every_scene_like_its_reference() {
+        let options = GoldenOptions::new();
+        match Game::new().run_golden(&options) {
+            Ok(passed) => assert!(
+                passed,
+                "golden scenes differ, see {}",
+                options.output.display()
+            ),
+            // Fails rather than passing without comparing anything when
+            // neither Mesa nor a display is available
+            Err(err) => panic!("Unable to render the golden scenes: {}", err),
+        }
+    }
+}
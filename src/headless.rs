@@ -0,0 +1,260 @@
+use std::ffi::c_void;
+use std::io;
+use std::path::PathBuf;
+use std::ptr;
+
+use glutin::dpi::PhysicalSize;
+use glutin::event_loop::EventLoop;
+#[cfg(target_os = "linux")]
+use glutin::platform::unix::HeadlessContextExt;
+use glutin::{
+    Api, Context, ContextBuilder, ContextError, CreationError, GlProfile, GlRequest, NotCurrent,
+    PossiblyCurrent,
+};
+#[cfg(target_os = "linux")]
+use khronos_egl as egl;
+use thiserror::Error;
+
+use crate::render::post::PostChainError;
+use crate::resources::Identifier;
+use crate::scene::SceneKind;
+use crate::screenshot::ScreenshotError;
+use crate::window::FramebufferError;
+
+#[derive(Debug, Error)]
+pub enum HeadlessError {
+    #[error("unable to create an offscreen GL context: {0}")]
+    Creation(#[from] CreationError),
+    #[error("unable to make the offscreen GL context current: {0}")]
+    Current(#[from] ContextError),
+    #[cfg(target_os = "linux")]
+    #[error("unable to load libEGL for a surfaceless context: {0}")]
+    EglLibrary(Box<dyn std::error::Error + Send + Sync>),
+    #[cfg(target_os = "linux")]
+    #[error("unable to create a surfaceless EGL context, {step} failed: {source}")]
+    Egl {
+        step: &'static str,
+        source: egl::Error,
+    },
+    #[error("unable to create the offscreen framebuffer: {0}")]
+    Framebuffer(#[from] FramebufferError),
+    #[error("unable to load post effect: {0}")]
+    PostEffect(#[from] PostChainError),
+    #[error("unable to create output directory {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    Screenshot(#[from] ScreenshotError),
+}
+
+// What to render without a window and where the frames go
+#[derive(Debug, Clone)]
+pub struct HeadlessOptions {
+    pub width: u32,
+    pub height: u32,
+    // Each frame is one game tick after the last, starting from tick 0
+    pub frames: u32,
+    pub output: PathBuf,
+    // Full identifier of a post chain, e.g. minecraft:shaders/post/blur.json
+    pub post_effect: Option<Identifier>,
+    pub scene: SceneKind,
+}
+
+impl HeadlessOptions {
+    pub const DEFAULT_WIDTH: u32 = 854;
+    pub const DEFAULT_HEIGHT: u32 = 480;
+    pub const DEFAULT_OUTPUT: &'static str = "headless";
+
+    pub fn new() -> HeadlessOptions {
+        HeadlessOptions {
+            width: HeadlessOptions::DEFAULT_WIDTH,
+            height: HeadlessOptions::DEFAULT_HEIGHT,
+            frames: 1,
+            output: PathBuf::from(HeadlessOptions::DEFAULT_OUTPUT),
+            post_effect: None,
+            scene: SceneKind::Chunk,
+        }
+    }
+
+    // PNG written for a frame, frame_0000.png for the first
+    pub fn frame_path(&self, frame: u32) -> PathBuf {
+        self.output.join(format!("frame_{:04}.png", frame))
+    }
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> HeadlessOptions {
+        HeadlessOptions::new()
+    }
+}
+
+// A current GL 3.3 context that draws to nothing but framebuffers
+pub enum HeadlessContext {
+    // Needs neither a display server nor an event loop
+    #[cfg(target_os = "linux")]
+    Egl(SurfacelessContext),
+    Glutin {
+        context: Box<Context<PossiblyCurrent>>,
+        // Only kept for contexts that need a display connection
+        _event_loop: Option<EventLoop<()>>,
+    },
+}
+
+impl HeadlessContext {
+    pub fn new(width: u32, height: u32) -> Result<HeadlessContext, HeadlessError> {
+        build_context(PhysicalSize::new(width, height))
+    }
+
+    pub fn get_proc_address(&self, name: &str) -> *const c_void {
+        match self {
+            #[cfg(target_os = "linux")]
+            HeadlessContext::Egl(context) => context.get_proc_address(name),
+            HeadlessContext::Glutin { context, .. } => context.get_proc_address(name),
+        }
+    }
+
+    fn glutin(
+        context: Context<NotCurrent>,
+        event_loop: Option<EventLoop<()>>,
+    ) -> Result<HeadlessContext, HeadlessError> {
+        let context = unsafe { context.make_current() }.map_err(|(_, err)| err)?;
+        Ok(HeadlessContext::Glutin {
+            context: Box::new(context),
+            _event_loop: event_loop,
+        })
+    }
+}
+
+fn context_builder() -> ContextBuilder<'static, NotCurrent> {
+    ContextBuilder::new()
+        .with_gl(GlRequest::Specific(Api::OpenGl, (3, 3)))
+        .with_gl_profile(GlProfile::Core)
+}
+
+// Mesa's surfaceless EGL platform is tried first as it works on any machine
+// with Mesa installed, then OSMesa. Without either only a display server is
+// left, so the EGL error is reported when DISPLAY and WAYLAND_DISPLAY are unset
+#[cfg(target_os = "linux")]
+fn build_context(size: PhysicalSize<u32>) -> Result<HeadlessContext, HeadlessError> {
+    let egl_error = match SurfacelessContext::new() {
+        Ok(context) => return Ok(HeadlessContext::Egl(context)),
+        Err(err) => err,
+    };
+    if let Ok(context) = context_builder().build_osmesa(size) {
+        return HeadlessContext::glutin(context, None);
+    }
+    if !has_display() {
+        return Err(egl_error);
+    }
+    let event_loop = EventLoop::new();
+    let context = context_builder().build_surfaceless(&event_loop)?;
+    HeadlessContext::glutin(context, Some(event_loop))
+}
+
+#[cfg(target_os = "linux")]
+fn has_display() -> bool {
+    std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn build_context(size: PhysicalSize<u32>) -> Result<HeadlessContext, HeadlessError> {
+    let event_loop = EventLoop::new();
+    let context = context_builder().build_headless(&event_loop, size)?;
+    HeadlessContext::glutin(context, Some(event_loop))
+}
+
+// EGL_PLATFORM_SURFACELESS_MESA from EGL_MESA_platform_surfaceless
+#[cfg(target_os = "linux")]
+const PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+
+// A core context made current without any surface on Mesa's surfaceless
+// platform, libEGL is loaded at runtime so it is only needed when used
+#[cfg(target_os = "linux")]
+pub struct SurfacelessContext {
+    egl: egl::DynamicInstance<egl::EGL1_5>,
+    display: egl::Display,
+    context: egl::Context,
+}
+
+#[cfg(target_os = "linux")]
+impl SurfacelessContext {
+    pub fn new() -> Result<SurfacelessContext, HeadlessError> {
+        let egl = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required() }
+            .map_err(|err| HeadlessError::EglLibrary(Box::new(err)))?;
+        let step = |step| move |source| HeadlessError::Egl { step, source };
+        let display = unsafe {
+            egl.get_platform_display(
+                PLATFORM_SURFACELESS_MESA,
+                egl::DEFAULT_DISPLAY,
+                &[egl::ATTRIB_NONE],
+            )
+        }
+        .map_err(step("eglGetPlatformDisplay"))?;
+        egl.initialize(display).map_err(step("eglInitialize"))?;
+        let context = SurfacelessContext::create(&egl, display).inspect_err(|_| {
+            let _ = egl.terminate(display);
+        })?;
+        Ok(SurfacelessContext {
+            egl,
+            display,
+            context,
+        })
+    }
+
+    fn create(
+        egl: &egl::DynamicInstance<egl::EGL1_5>,
+        display: egl::Display,
+    ) -> Result<egl::Context, HeadlessError> {
+        let step = |step| move |source| HeadlessError::Egl { step, source };
+        egl.bind_api(egl::OPENGL_API).map_err(step("eglBindAPI"))?;
+        // Surfaceless displays only have pbuffer configs
+        let config_attributes = [
+            egl::SURFACE_TYPE,
+            egl::PBUFFER_BIT,
+            egl::RENDERABLE_TYPE,
+            egl::OPENGL_BIT,
+            egl::NONE,
+        ];
+        let config = egl
+            .choose_first_config(display, &config_attributes)
+            .map_err(step("eglChooseConfig"))?
+            .ok_or(HeadlessError::Egl {
+                step: "eglChooseConfig",
+                source: egl::Error::BadConfig,
+            })?;
+        let context_attributes = [
+            egl::CONTEXT_MAJOR_VERSION,
+            3,
+            egl::CONTEXT_MINOR_VERSION,
+            3,
+            egl::CONTEXT_OPENGL_PROFILE_MASK,
+            egl::CONTEXT_OPENGL_CORE_PROFILE_BIT,
+            egl::NONE,
+        ];
+        let context = egl
+            .create_context(display, config, None, &context_attributes)
+            .map_err(step("eglCreateContext"))?;
+        if let Err(source) = egl.make_current(display, None, None, Some(context)) {
+            let _ = egl.destroy_context(display, context);
+            return Err(HeadlessError::Egl {
+                step: "eglMakeCurrent",
+                source,
+            });
+        }
+        Ok(context)
+    }
+
+    pub fn get_proc_address(&self, name: &str) -> *const c_void {
+        self.egl
+            .get_proc_address(name)
+            .map_or(ptr::null(), |function| function as *const c_void)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for SurfacelessContext {
+    fn drop(&mut self) {
+        let _ = self.egl.make_current(self.display, None, None, None);
+        let _ = self.egl.destroy_context(self.display, self.context);
+        let _ = self.egl.terminate(self.display);
+    }
+}
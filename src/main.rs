@@ -1,16 +1,45 @@
-extern crate core;
-extern crate core;
+use std::process;
 
+use crate::cli::Mode;
 use crate::game::Game;
 
+mod cli;
 mod game;
+mod golden;
+mod headless;
 mod math;
 mod render;
 mod resources;
+mod scene;
+mod screenshot;
+mod settings;
 mod types;
 mod window;
 
 fn main() {
-    let mut game = Game::new();
-    game.start();
+    let mode = match cli::parse(std::env::args().skip(1)) {
+        Ok(mode) => mode,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    match mode {
+        Mode::Window => Game::new().start(),
+        Mode::Headless(options) => {
+            if let Err(err) = Game::new().run_headless(&options) {
+                eprintln!("Headless rendering failed: {}", err);
+                process::exit(1);
+            }
+        }
+        Mode::Golden(options) => match Game::new().run_golden(&options) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(err) => {
+                eprintln!("Golden image check failed: {}", err);
+                process::exit(1);
+            }
+        },
+        Mode::Help => println!("{}", cli::USAGE),
+    }
 }
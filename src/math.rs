@@ -1 +1,3 @@
-
+pub fn round_up_to_multiple(value: usize, factor: usize) -> usize {
+    value.div_ceil(factor) * factor
+}
@@ -0,0 +1,261 @@
+use std::borrow::Cow;
+
+use serde::Deserialize;
+
+use crate::render::texture::NativeImage;
+
+// Contents of a sprite's .png.mcmeta
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpriteMetadata {
+    pub animation: Option<AnimationMetadata>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnimationMetadata {
+    // Ticks each frame is shown for unless the frame sets its own time
+    #[serde(default = "AnimationMetadata::default_frame_time")]
+    pub frametime: u32,
+    #[serde(default)]
+    pub interpolate: bool,
+    // Order frames are shown in, every frame in order when missing
+    pub frames: Option<Vec<FrameMetadata>>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+// Frames are either a plain index or an index with its own time
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum FrameMetadata {
+    Index(usize),
+    Timed { index: usize, time: u32 },
+}
+
+impl AnimationMetadata {
+    fn default_frame_time() -> u32 {
+        1
+    }
+
+    // Frames are square and as wide as the image unless sized explicitly
+    pub fn frame_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match (self.width, self.height) {
+            (Some(frame_width), Some(frame_height)) => (frame_width, frame_height),
+            (Some(frame_width), None) => (frame_width, height),
+            (None, Some(frame_height)) => (width, frame_height),
+            (None, None) => (width.min(height), width.min(height)),
+        }
+    }
+
+    // Index and time of each frame in the order they are shown
+    pub fn sequence(&self, frame_count: usize) -> Vec<AnimationFrame> {
+        match &self.frames {
+            Some(frames) => frames
+                .iter()
+                .map(|frame| match *frame {
+                    FrameMetadata::Index(index) => AnimationFrame {
+                        index,
+                        time: self.frametime,
+                    },
+                    FrameMetadata::Timed { index, time } => AnimationFrame { index, time },
+                })
+                .collect(),
+            None => (0..frame_count)
+                .map(|index| AnimationFrame {
+                    index,
+                    time: self.frametime,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnimationFrame {
+    pub index: usize,
+    // Ticks the frame is shown for, at least 1
+    pub time: u32,
+}
+
+// Splits an image into frames of the given size, left to right then top
+// to bottom
+pub fn split_frames(image: &NativeImage, frame_width: u32, frame_height: u32) -> Vec<NativeImage> {
+    let columns = image.width() / frame_width;
+    let rows = image.height() / frame_height;
+    (0..rows * columns)
+        .map(|frame| {
+            image.sub_image(
+                frame % columns * frame_width,
+                frame / columns * frame_height,
+                frame_width,
+                frame_height,
+            )
+        })
+        .collect()
+}
+
+// An animated sprite's frames and where they are uploaded in the atlas
+pub struct SpriteAnimation {
+    pub page: usize,
+    pub x: u32,
+    pub y: u32,
+    frames: Vec<NativeImage>,
+    sequence: Vec<AnimationFrame>,
+    interpolate: bool,
+    // Position in sequence and ticks spent on it
+    current: usize,
+    sub_frame: u32,
+}
+
+impl SpriteAnimation {
+    pub fn new(
+        frames: Vec<NativeImage>,
+        sequence: Vec<AnimationFrame>,
+        interpolate: bool,
+    ) -> SpriteAnimation {
+        SpriteAnimation {
+            page: 0,
+            x: 0,
+            y: 0,
+            frames,
+            sequence,
+            interpolate,
+            current: 0,
+            sub_frame: 0,
+        }
+    }
+
+    pub fn first_frame(&self) -> &NativeImage {
+        &self.frames[self.sequence[0].index]
+    }
+
+    // Advances one game tick, returns the image to upload if the sprite
+    // changed. Interpolated animations change every tick
+    pub fn tick(&mut self) -> Option<Cow<'_, NativeImage>> {
+        self.sub_frame += 1;
+        let frame = self.sequence[self.current];
+        if self.sub_frame >= frame.time {
+            self.sub_frame = 0;
+            self.current = (self.current + 1) % self.sequence.len();
+            let next = self.sequence[self.current];
+            if next.index != frame.index {
+                return Some(Cow::Borrowed(&self.frames[next.index]));
+            }
+            None
+        } else if self.interpolate {
+            let next = self.sequence[(self.current + 1) % self.sequence.len()];
+            if next.index == frame.index {
+                return None;
+            }
+            let delta = 1.0 - self.sub_frame as f64 / frame.time as f64;
+            Some(Cow::Owned(interpolate(
+                &self.frames[frame.index],
+                &self.frames[next.index],
+                delta,
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+// Blends the colour of two frames, alpha is kept from the current frame
+fn interpolate(current: &NativeImage, next: &NativeImage, delta: f64) -> NativeImage {
+    let mut image = NativeImage::new(current.width(), current.height());
+    for y in 0..current.height() {
+        for x in 0..current.width() {
+            let a = current.pixel(x, y);
+            let b = next.pixel(x, y);
+            let mix = |i: usize| (delta * a[i] as f64 + (1.0 - delta) * b[i] as f64) as u8;
+            image.set_pixel(x, y, [mix(0), mix(1), mix(2), a[3]]);
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1x1 frames whose red channel is 100 times their index
+    fn frames(count: u8) -> Vec<NativeImage> {
+        (0..count)
+            .map(|index| NativeImage::from_pixels(1, 1, vec![index * 100, 0, 0, 255]))
+            .collect()
+    }
+
+    fn sequence(frames: &[(usize, u32)]) -> Vec<AnimationFrame> {
+        frames
+            .iter()
+            .map(|&(index, time)| AnimationFrame { index, time })
+            .collect()
+    }
+
+    // Red channel of each upload over the given number of ticks
+    fn uploads(animation: &mut SpriteAnimation, ticks: usize) -> Vec<Option<u8>> {
+        (0..ticks)
+            .map(|_| animation.tick().map(|image| image.pixel(0, 0)[0]))
+            .collect()
+    }
+
+    #[test]
+    fn parses_frame_order_and_times() {
+        let metadata: SpriteMetadata = serde_json::from_str(
+            r#"{"animation": {"frametime": 3, "frames": [2, {"index": 0, "time": 5}, 1]}}"#,
+        )
+        .unwrap();
+        let animation = metadata.animation.unwrap();
+        let frames: Vec<_> = animation
+            .sequence(3)
+            .iter()
+            .map(|frame| (frame.index, frame.time))
+            .collect();
+        assert_eq!(frames, [(2, 3), (0, 5), (1, 3)]);
+        assert!(!animation.interpolate);
+
+        // Every frame in order for one tick each, square frames by default
+        let metadata: SpriteMetadata = serde_json::from_str(r#"{"animation": {}}"#).unwrap();
+        let animation = metadata.animation.unwrap();
+        let frames: Vec<_> = animation
+            .sequence(3)
+            .iter()
+            .map(|frame| (frame.index, frame.time))
+            .collect();
+        assert_eq!(frames, [(0, 1), (1, 1), (2, 1)]);
+        assert_eq!(animation.frame_size(16, 48), (16, 16));
+    }
+
+    #[test]
+    fn wraps_around_after_the_last_frame() {
+        let mut animation = SpriteAnimation::new(frames(2), sequence(&[(0, 1), (1, 2)]), false);
+        assert_eq!(
+            uploads(&mut animation, 5),
+            [Some(100), None, Some(0), Some(100), None]
+        );
+
+        // Showing the same frame twice in a row uploads nothing
+        let mut animation =
+            SpriteAnimation::new(frames(2), sequence(&[(0, 1), (0, 1), (1, 1)]), false);
+        assert_eq!(uploads(&mut animation, 3), [None, Some(100), Some(0)]);
+    }
+
+    #[test]
+    fn interpolates_towards_the_next_frame() {
+        let mut frames = frames(3);
+        frames[2] = NativeImage::from_pixels(1, 1, vec![200, 100, 0, 0]);
+        let mut animation = SpriteAnimation::new(frames, sequence(&[(0, 4), (2, 4)]), true);
+        let blended: Vec<_> = (0..4)
+            .map(|_| animation.tick().map(|image| image.pixel(0, 0)))
+            .collect();
+        // A quarter of the way per tick, keeping the current frame's alpha,
+        // then the next frame itself once its time comes
+        assert_eq!(
+            blended,
+            [
+                Some([50, 25, 0, 255]),
+                Some([100, 50, 0, 255]),
+                Some([150, 75, 0, 255]),
+                Some([200, 100, 0, 0]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,606 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use gl33::*;
+use thiserror::Error;
+
+use crate::render::animation::{split_frames, SpriteAnimation, SpriteMetadata};
+use crate::render::mipmap::{generate_mipmaps, max_mipmap_levels};
+use crate::render::texture::{set_mipmap_filter, ImageError, NativeImage};
+use crate::render::util::{GlStateManager, TextureDeleter};
+use crate::resources::reload::{PrepareTask, Prepared, ReloadListener};
+use crate::resources::{Identifier, ResourceError, ResourceManager};
+use crate::types::GLint;
+
+#[derive(Debug, Error)]
+pub enum SpriteError {
+    #[error(transparent)]
+    Resource(#[from] ResourceError),
+    #[error("unable to decode {identifier}: {source}")]
+    Image {
+        identifier: Identifier,
+        source: ImageError,
+    },
+    #[error("{identifier} is {width}x{height} which can't be split into {frame_width}x{frame_height} frames")]
+    FrameSize {
+        identifier: Identifier,
+        width: u32,
+        height: u32,
+        frame_width: u32,
+        frame_height: u32,
+    },
+    #[error("{identifier} shows frame {index} but only has {count} frames")]
+    FrameIndex {
+        identifier: Identifier,
+        index: usize,
+        count: usize,
+    },
+    #[error("{identifier} shows frame {index} for 0 ticks")]
+    FrameTime {
+        identifier: Identifier,
+        index: usize,
+    },
+    #[error("{identifier} is {width}x{height} which doesn't fit in a {max_size}x{max_size} atlas")]
+    TooLarge {
+        identifier: Identifier,
+        width: u32,
+        height: u32,
+        max_size: u32,
+    },
+}
+
+// Where a sprite was placed, UVs are normalized to its page
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite {
+    pub page: usize,
+    pub u0: f32,
+    pub v0: f32,
+    pub u1: f32,
+    pub v1: f32,
+}
+
+impl Sprite {
+    // Maps 0..1 across the sprite to a page UV
+    pub fn u(&self, value: f32) -> f32 {
+        self.u0 + (self.u1 - self.u0) * value
+    }
+
+    pub fn v(&self, value: f32) -> f32 {
+        self.v0 + (self.v1 - self.v0) * value
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Placement {
+    page: usize,
+    x: u32,
+    y: u32,
+}
+
+// Shelf packs rectangles tallest first into power of two pages no larger
+// than max_size. Returns the size of each page and the placement of each
+// rectangle in the order given, every rectangle must fit within max_size
+fn stitch(sizes: &[(u32, u32)], max_size: u32) -> (Vec<(u32, u32)>, Vec<Placement>) {
+    let mut remaining: Vec<usize> = (0..sizes.len()).collect();
+    remaining.sort_by_key(|index| (Reverse(sizes[*index].1), Reverse(sizes[*index].0)));
+    let mut pages = Vec::new();
+    let mut placements = vec![Placement::default(); sizes.len()];
+    while !remaining.is_empty() {
+        let area: u64 = remaining
+            .iter()
+            .map(|index| sizes[*index].0 as u64 * sizes[*index].1 as u64)
+            .sum();
+        let widest = remaining.iter().map(|index| sizes[*index].0).max().unwrap();
+        let width = ((area as f64).sqrt().ceil() as u32)
+            .max(widest)
+            .next_power_of_two()
+            .min(max_size);
+        let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+        let mut leftover = Vec::new();
+        for index in remaining {
+            let (sprite_width, sprite_height) = sizes[index];
+            if x + sprite_width > width {
+                x = 0;
+                y += shelf_height;
+                shelf_height = 0;
+            }
+            if y + sprite_height > max_size {
+                leftover.push(index);
+                continue;
+            }
+            placements[index] = Placement {
+                page: pages.len(),
+                x,
+                y,
+            };
+            x += sprite_width;
+            shelf_height = shelf_height.max(sprite_height);
+        }
+        pages.push((width, (y + shelf_height).next_power_of_two()));
+        remaining = leftover;
+    }
+    (pages, placements)
+}
+
+// Stitched pages and sprites built off the render thread
+pub struct PreparedAtlas {
+    pages: Vec<NativeImage>,
+    // Levels 1 and up of each page
+    mipmaps: Vec<Vec<NativeImage>>,
+    mipmap_levels: u32,
+    sprites: HashMap<Identifier, Sprite>,
+    animations: Vec<SpriteAnimation>,
+}
+
+struct AtlasPage {
+    texture: TextureUnit,
+}
+
+// Every block and item texture stitched into as few textures as possible.
+// Sprites are named by their path under textures without the extension,
+// e.g. minecraft:block/stone for assets/minecraft/textures/block/stone.png
+pub struct TextureAtlas {
+    max_size: u32,
+    // Levels requested, fewer are generated when a sprite is too small
+    requested_mipmap_levels: u32,
+    mipmap_levels: u32,
+    pages: Vec<AtlasPage>,
+    sprites: HashMap<Identifier, Sprite>,
+    missing: Sprite,
+    animations: Vec<SpriteAnimation>,
+    deleter: TextureDeleter,
+}
+
+impl TextureAtlas {
+    const DIRECTORIES: [&'static str; 2] = ["textures/block", "textures/item"];
+    const MISSING_SIZE: u32 = 16;
+
+    pub fn missing_sprite() -> Identifier {
+        Identifier::minecraft("missingno").unwrap()
+    }
+
+    // Loads and uploads the atlas immediately, used at startup before the
+    // first reload
+    pub fn load(
+        gl: &mut GlStateManager,
+        resources: &ResourceManager,
+        mipmap_levels: u32,
+    ) -> TextureAtlas {
+        let max_size = gl.max_supported_texture_size() as u32;
+        let prepared = TextureAtlas::prepare_sprites(resources, max_size, mipmap_levels);
+        let missing = prepared.sprites[&TextureAtlas::missing_sprite()];
+        let mut atlas = TextureAtlas {
+            max_size,
+            requested_mipmap_levels: mipmap_levels,
+            mipmap_levels: 0,
+            pages: Vec::new(),
+            sprites: HashMap::new(),
+            missing,
+            animations: Vec::new(),
+            deleter: gl.texture_deleter(),
+        };
+        atlas.upload(gl, prepared);
+        atlas
+    }
+
+    fn load_sprite(
+        resources: &ResourceManager,
+        identifier: Identifier,
+        max_size: u32,
+    ) -> Result<(NativeImage, Option<SpriteAnimation>), SpriteError> {
+        let resource = resources.get(identifier)?;
+        let image = NativeImage::decode(&resource.data)
+            .map_err(|source| SpriteError::Image { identifier, source })?;
+        let (image, animation) = TextureAtlas::load_animation(resources, identifier, image)?;
+        if image.width() > max_size || image.height() > max_size {
+            return Err(SpriteError::TooLarge {
+                identifier,
+                width: image.width(),
+                height: image.height(),
+                max_size,
+            });
+        }
+        Ok((image, animation))
+    }
+
+    // Splits the image into frames when its .png.mcmeta has an animation
+    // section, the returned image is the first frame shown
+    fn load_animation(
+        resources: &ResourceManager,
+        identifier: Identifier,
+        image: NativeImage,
+    ) -> Result<(NativeImage, Option<SpriteAnimation>), SpriteError> {
+        let path = format!("{}.mcmeta", identifier.path());
+        let metadata = Identifier::new(identifier.namespace(), &path).unwrap();
+        let metadata = match resources.get_json::<SpriteMetadata>(metadata) {
+            Ok(SpriteMetadata {
+                animation: Some(value),
+            }) => value,
+            Ok(_) | Err(ResourceError::NotFound(_)) => return Ok((image, None)),
+            Err(err) => return Err(err.into()),
+        };
+        let (width, height) = (image.width(), image.height());
+        let (frame_width, frame_height) = metadata.frame_size(width, height);
+        if frame_width == 0
+            || frame_height == 0
+            || width % frame_width != 0
+            || height % frame_height != 0
+        {
+            return Err(SpriteError::FrameSize {
+                identifier,
+                width,
+                height,
+                frame_width,
+                frame_height,
+            });
+        }
+        let frames = split_frames(&image, frame_width, frame_height);
+        let sequence = metadata.sequence(frames.len());
+        for frame in &sequence {
+            if frame.index >= frames.len() {
+                return Err(SpriteError::FrameIndex {
+                    identifier,
+                    index: frame.index,
+                    count: frames.len(),
+                });
+            }
+            if frame.time == 0 {
+                return Err(SpriteError::FrameTime {
+                    identifier,
+                    index: frame.index,
+                });
+            }
+        }
+        if sequence.len() < 2 {
+            let index = sequence.first().map_or(0, |frame| frame.index);
+            return Ok((frames[index].clone(), None));
+        }
+        let animation = SpriteAnimation::new(frames, sequence, metadata.interpolate);
+        Ok((animation.first_frame().clone(), Some(animation)))
+    }
+
+    // Decodes and stitches every sprite without touching GL, sprites that
+    // fail to load are replaced by the missing sprite with a warning
+    pub fn prepare_sprites(
+        resources: &ResourceManager,
+        max_size: u32,
+        mipmap_levels: u32,
+    ) -> PreparedAtlas {
+        let mut names = vec![TextureAtlas::missing_sprite()];
+        let mut images = vec![NativeImage::missing(
+            TextureAtlas::MISSING_SIZE,
+            TextureAtlas::MISSING_SIZE,
+        )];
+        let mut animations = vec![None];
+        for directory in TextureAtlas::DIRECTORIES {
+            for identifier in resources.list(directory, ".png") {
+                let path = identifier.path();
+                let path = &path["textures/".len()..path.len() - ".png".len()];
+                let name = Identifier::new(identifier.namespace(), path).unwrap();
+                match TextureAtlas::load_sprite(resources, identifier, max_size) {
+                    Ok((image, animation)) => {
+                        names.push(name);
+                        images.push(image);
+                        animations.push(animation);
+                    }
+                    Err(err) => eprintln!("Using missing texture for {}: {}", name, err),
+                }
+            }
+        }
+        let sizes: Vec<(u32, u32)> = images
+            .iter()
+            .map(|image| (image.width(), image.height()))
+            .collect();
+        let supported = max_mipmap_levels(sizes.iter().copied());
+        if supported < mipmap_levels {
+            eprintln!(
+                "Limiting atlas mipmap levels from {} to {} to fit the smallest sprite",
+                mipmap_levels, supported
+            );
+        }
+        let mipmap_levels = mipmap_levels.min(supported);
+        let (page_sizes, placements) = stitch(&sizes, max_size);
+        let mut pages: Vec<NativeImage> = page_sizes
+            .iter()
+            .map(|(width, height)| NativeImage::new(*width, *height))
+            .collect();
+        let mut sprites = HashMap::with_capacity(names.len());
+        for ((name, image), placement) in names.into_iter().zip(&images).zip(&placements) {
+            let page = &mut pages[placement.page];
+            page.copy_from(image, placement.x, placement.y);
+            let (page_width, page_height) = (page.width() as f32, page.height() as f32);
+            sprites.insert(
+                name,
+                Sprite {
+                    page: placement.page,
+                    u0: placement.x as f32 / page_width,
+                    v0: placement.y as f32 / page_height,
+                    u1: (placement.x + image.width()) as f32 / page_width,
+                    v1: (placement.y + image.height()) as f32 / page_height,
+                },
+            );
+        }
+        let animations = animations
+            .into_iter()
+            .zip(placements)
+            .filter_map(|(animation, placement)| {
+                let mut animation = animation?;
+                animation.page = placement.page;
+                animation.x = placement.x;
+                animation.y = placement.y;
+                Some(animation)
+            })
+            .collect();
+        let mipmaps = pages
+            .iter()
+            .map(|page| generate_mipmaps(page, mipmap_levels))
+            .collect();
+        PreparedAtlas {
+            pages,
+            mipmaps,
+            mipmap_levels,
+            sprites,
+            animations,
+        }
+    }
+
+    // Replaces the current pages with the prepared ones
+    pub fn upload(&mut self, gl: &mut GlStateManager, prepared: PreparedAtlas) {
+        gl.delete_textures(&self.take_page_textures());
+        for (image, mipmaps) in prepared.pages.iter().zip(&prepared.mipmaps) {
+            let texture = gl.gen_texture();
+            gl.bind_texture(texture);
+            set_mipmap_filter(gl, prepared.mipmap_levels);
+            let backend = gl.backend();
+            backend.tex_parameter_i(
+                GL_TEXTURE_2D,
+                GL_TEXTURE_WRAP_S,
+                GL_CLAMP_TO_EDGE.0 as GLint,
+            );
+            backend.tex_parameter_i(
+                GL_TEXTURE_2D,
+                GL_TEXTURE_WRAP_T,
+                GL_CLAMP_TO_EDGE.0 as GLint,
+            );
+            image.upload(gl, 0);
+            for (level, mipmap) in mipmaps.iter().enumerate() {
+                mipmap.upload(gl, level as GLint + 1);
+            }
+            self.pages.push(AtlasPage { texture });
+        }
+        gl.bind_texture(GL_ZERO);
+        self.missing = prepared.sprites[&TextureAtlas::missing_sprite()];
+        self.sprites = prepared.sprites;
+        self.animations = prepared.animations;
+        self.mipmap_levels = prepared.mipmap_levels;
+    }
+
+    // Takes effect on the next reload
+    pub fn set_mipmap_levels(&mut self, levels: u32) {
+        self.requested_mipmap_levels = levels;
+    }
+
+    pub fn mipmap_levels(&self) -> u32 {
+        self.mipmap_levels
+    }
+
+    // Advances every animated sprite by one game tick
+    pub fn tick(&mut self, gl: &mut GlStateManager) {
+        for animation in &mut self.animations {
+            let (page, x, y) = (animation.page, animation.x, animation.y);
+            if let Some(image) = animation.tick() {
+                gl.bind_texture(self.pages[page].texture);
+                let mipmaps = generate_mipmaps(&image, self.mipmap_levels);
+                image.upload_sub(gl, 0, x as GLint, y as GLint);
+                for (level, mipmap) in (1..).zip(&mipmaps) {
+                    mipmap.upload_sub(gl, level, (x >> level) as GLint, (y >> level) as GLint);
+                }
+            }
+        }
+        gl.bind_texture(GL_ZERO);
+    }
+
+    fn take_page_textures(&mut self) -> Vec<TextureUnit> {
+        self.pages.drain(..).map(|page| page.texture).collect()
+    }
+
+    // Sprites that don't exist map to the missing sprite
+    pub fn sprite(&self, identifier: Identifier) -> &Sprite {
+        self.sprites.get(&identifier).unwrap_or(&self.missing)
+    }
+
+    pub fn texture(&self, page: usize) -> TextureUnit {
+        self.pages[page].texture
+    }
+}
+
+impl ReloadListener for TextureAtlas {
+    fn name(&self) -> &str {
+        "texture atlas"
+    }
+
+    fn prepare(&self) -> PrepareTask {
+        let max_size = self.max_size;
+        let mipmap_levels = self.requested_mipmap_levels;
+        Box::new(move |resources| {
+            let prepared = TextureAtlas::prepare_sprites(resources, max_size, mipmap_levels);
+            Ok(Box::new(prepared) as Prepared)
+        })
+    }
+
+    fn apply(&mut self, gl: &mut GlStateManager, prepared: Prepared) {
+        let prepared = prepared.downcast::<PreparedAtlas>().unwrap();
+        self.upload(gl, *prepared)
+    }
+}
+
+impl Drop for TextureAtlas {
+    fn drop(&mut self) {
+        let textures = self.take_page_textures();
+        if !textures.is_empty() {
+            self.deleter.delete(&textures);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+    use crate::resources::pack::{Pack, PackMetadata};
+
+    // Pack of files kept in memory, all under the test namespace
+    struct MemoryPack(Vec<(String, Vec<u8>)>);
+
+    impl Pack for MemoryPack {
+        fn name(&self) -> &str {
+            "memory"
+        }
+
+        fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+            let file = self.0.iter().find(|(name, _)| name == path);
+            Ok(file.map(|(_, data)| data.clone()))
+        }
+
+        fn metadata(&self) -> Option<&PackMetadata> {
+            None
+        }
+
+        fn namespaces(&self) -> io::Result<Vec<String>> {
+            Ok(vec!["test".to_string()])
+        }
+
+        fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+            let prefix = format!("{}/", directory);
+            let paths = self.0.iter().map(|(name, _)| name);
+            Ok(paths
+                .filter(|name| name.starts_with(&prefix))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn overlaps(a: ((u32, u32), Placement), b: ((u32, u32), Placement)) -> bool {
+        let (((a_width, a_height), a), ((b_width, b_height), b)) = (a, b);
+        a.page == b.page
+            && a.x < b.x + b_width
+            && b.x < a.x + a_width
+            && a.y < b.y + b_height
+            && b.y < a.y + a_height
+    }
+
+    #[test]
+    fn places_sprites_without_overlapping() {
+        let sizes = [
+            (16, 16),
+            (32, 16),
+            (16, 32),
+            (8, 8),
+            (64, 64),
+            (16, 16),
+            (8, 24),
+        ];
+        let (pages, placements) = stitch(&sizes, 1024);
+        assert_eq!(pages.len(), 1);
+        let (width, height) = pages[0];
+        assert!(width.is_power_of_two() && height.is_power_of_two());
+        let rectangles: Vec<_> = sizes.iter().copied().zip(placements).collect();
+        for (index, &((sprite_width, sprite_height), placement)) in rectangles.iter().enumerate() {
+            assert!(placement.x + sprite_width <= width);
+            assert!(placement.y + sprite_height <= height);
+            for &other in &rectangles[index + 1..] {
+                assert!(!overlaps(rectangles[index], other));
+            }
+        }
+    }
+
+    #[test]
+    fn grows_the_page_and_spills_onto_new_ones() {
+        assert_eq!(stitch(&[(16, 16)], 1024).0, [(16, 16)]);
+        // Four sprites square up into a larger page
+        assert_eq!(stitch(&[(16, 16); 4], 1024).0, [(32, 32)]);
+        // A fifth doesn't fit below max_size and starts a second page
+        let (pages, placements) = stitch(&[(16, 16); 5], 32);
+        assert_eq!(pages, [(32, 32), (16, 16)]);
+        assert_eq!(
+            placements
+                .iter()
+                .filter(|placement| placement.page == 0)
+                .count(),
+            4
+        );
+        assert_eq!(
+            (placements[4].page, placements[4].x, placements[4].y),
+            (1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_missing_sprite() {
+        let mut image = NativeImage::new(16, 16);
+        image.set_pixel(0, 0, [255, 255, 255, 255]);
+        let mut resources = ResourceManager::new();
+        resources.add_pack(Box::new(MemoryPack(vec![
+            (
+                "assets/test/textures/block/good.png".to_string(),
+                image.encode_png().unwrap(),
+            ),
+            (
+                "assets/test/textures/block/broken.png".to_string(),
+                b"not a png".to_vec(),
+            ),
+        ])));
+        let prepared = TextureAtlas::prepare_sprites(&resources, 1024, 0);
+        let missing = prepared.sprites[&TextureAtlas::missing_sprite()];
+        let page = &prepared.pages[missing.page];
+        let (width, height) = (page.width() as f32, page.height() as f32);
+        let (x, y) = (missing.u0 * width, missing.v0 * height);
+        assert_eq!(
+            (missing.u1 * width - x, missing.v1 * height - y),
+            (16.0, 16.0)
+        );
+        let checker = page.sub_image(x as u32, y as u32, 16, 16);
+        assert_eq!(checker, NativeImage::missing(16, 16));
+        assert!(prepared
+            .sprites
+            .contains_key(&Identifier::new("test", "block/good").unwrap()));
+        assert!(!prepared
+            .sprites
+            .contains_key(&Identifier::new("test", "block/broken").unwrap()));
+
+        // Sprites that didn't load or don't exist map to the missing sprite
+        let gl = GlStateManager::new(Rc::new(RecordingGl::new()));
+        let atlas = TextureAtlas {
+            max_size: 1024,
+            requested_mipmap_levels: 0,
+            mipmap_levels: 0,
+            pages: Vec::new(),
+            sprites: prepared.sprites,
+            missing,
+            animations: Vec::new(),
+            deleter: gl.texture_deleter(),
+        };
+        for path in ["block/broken", "block/absent"] {
+            let sprite = atlas.sprite(Identifier::new("test", path).unwrap());
+            assert_eq!(
+                (sprite.page, sprite.u0, sprite.v0),
+                (missing.page, missing.u0, missing.v0)
+            );
+        }
+    }
+
+    #[test]
+    fn limits_mipmap_levels_to_the_smallest_sprite() {
+        let mut resources = ResourceManager::new();
+        resources.add_pack(Box::new(MemoryPack(vec![(
+            "assets/test/textures/item/small.png".to_string(),
+            NativeImage::new(8, 8).encode_png().unwrap(),
+        )])));
+        let prepared = TextureAtlas::prepare_sprites(&resources, 1024, 4);
+        assert_eq!(prepared.mipmap_levels, 3);
+        assert!(prepared.mipmaps.iter().all(|mipmaps| mipmaps.len() == 3));
+    }
+}
@@ -0,0 +1,611 @@
+use std::ffi::CString;
+use std::ptr::{null, null_mut};
+
+use gl33::global_loader::*;
+use gl33::*;
+
+use crate::render::util::GlThread;
+use crate::types::{GLboolean, GLfloat, GLint, GLsizei, GLsizeiptr, GLuint};
+
+// The GL calls made by the state manager, framebuffers, vertex buffers and
+// shaders. Objects are created and deleted one at a time and pointers are
+// replaced by slices and byte offsets, so an implementation doesn't need a
+// real context, see RecordingGl
+pub trait GlBackend {
+    fn get_error(&self) -> GLenum;
+    fn get_integer(&self, name: GLenum) -> GLint;
+
+    fn enable(&self, cap: EnableCap);
+    fn disable(&self, cap: EnableCap);
+    fn depth_func(&self, func: DepthFunction);
+    fn depth_mask(&self, mask: bool);
+    fn blend_func(&self, src: BlendingFactor, dst: BlendingFactor);
+    fn blend_func_separate(
+        &self,
+        src_rgb: BlendingFactor,
+        dst_rgb: BlendingFactor,
+        src_alpha: BlendingFactor,
+        dst_alpha: BlendingFactor,
+    );
+    fn blend_equation(&self, equation: BlendEquationModeEXT);
+    fn cull_face(&self, mode: CullFaceMode);
+    fn polygon_offset(&self, factor: GLfloat, units: GLfloat);
+    fn logic_op(&self, op: LogicOp);
+    fn viewport(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei);
+    fn color_mask(&self, red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean);
+    fn stencil_func(&self, func: StencilFunction, ref_: GLint, mask: GLuint);
+    fn stencil_mask(&self, mask: GLuint);
+    fn stencil_op(&self, sfail: StencilOp, dpfail: StencilOp, dppass: StencilOp);
+    fn clear_color(&self, red: GLfloat, green: GLfloat, blue: GLfloat, alpha: GLfloat);
+    fn clear_depth(&self, depth: f64);
+    fn clear(&self, mask: GLbitfield);
+
+    fn gen_texture(&self) -> GLuint;
+    fn delete_textures(&self, textures: &[GLuint]);
+    fn active_texture(&self, unit: TextureUnit);
+    fn bind_texture(&self, target: TextureTarget, texture: GLuint);
+    fn tex_parameter_i(&self, target: TextureTarget, name: TextureParameterName, value: GLint);
+    fn tex_parameter_f(&self, target: TextureTarget, name: TextureParameterName, value: GLfloat);
+    // Without pixels the level is allocated and left undefined
+    #[allow(clippy::too_many_arguments)]
+    fn tex_image_2d(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        internal_format: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: Option<&[u8]>,
+    );
+    #[allow(clippy::too_many_arguments)]
+    fn tex_sub_image_2d(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        x: GLint,
+        y: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: &[u8],
+    );
+    fn pixel_store_i(&self, name: PixelStoreParameter, value: GLint);
+    // pixels has to hold the whole level in format and type_
+    fn get_tex_image(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: &mut [u8],
+    );
+    fn get_tex_level_parameter_i(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        name: GetTextureParameter,
+    ) -> GLint;
+
+    fn gen_framebuffer(&self) -> GLuint;
+    fn delete_framebuffer(&self, framebuffer: GLuint);
+    fn bind_framebuffer(&self, target: FramebufferTarget, framebuffer: GLuint);
+    fn framebuffer_texture_2d(
+        &self,
+        target: FramebufferTarget,
+        attachment: FramebufferAttachment,
+        texture_target: TextureTarget,
+        texture: GLuint,
+        level: GLint,
+    );
+    fn check_framebuffer_status(&self, target: FramebufferTarget) -> FramebufferStatus;
+
+    fn gen_buffer(&self) -> GLuint;
+    fn delete_buffer(&self, buffer: GLuint);
+    fn bind_buffer(&self, target: BufferTargetARB, buffer: GLuint);
+    fn buffer_data(&self, target: BufferTargetARB, data: &[u8], usage: BufferUsageARB);
+    fn gen_vertex_array(&self) -> GLuint;
+    fn delete_vertex_array(&self, vertex_array: GLuint);
+    fn bind_vertex_array(&self, vertex_array: GLuint);
+    fn enable_vertex_attrib_array(&self, index: GLuint);
+    fn disable_vertex_attrib_array(&self, index: GLuint);
+    // offset is in bytes from the start of the bound GL_ARRAY_BUFFER
+    fn vertex_attrib_pointer(
+        &self,
+        index: GLuint,
+        size: GLint,
+        type_: VertexAttribPointerType,
+        normalized: bool,
+        stride: GLsizei,
+        offset: usize,
+    );
+    fn vertex_attrib_i_pointer(
+        &self,
+        index: GLuint,
+        size: GLint,
+        type_: VertexAttribIType,
+        stride: GLsizei,
+        offset: usize,
+    );
+    // offset is in bytes from the start of the bound GL_ELEMENT_ARRAY_BUFFER
+    fn draw_elements(
+        &self,
+        mode: PrimitiveType,
+        count: GLsizei,
+        type_: DrawElementsType,
+        offset: usize,
+    );
+
+    fn create_shader(&self, kind: ShaderType) -> GLuint;
+    fn delete_shader(&self, shader: GLuint);
+    fn shader_source(&self, shader: GLuint, source: &str);
+    fn compile_shader(&self, shader: GLuint);
+    fn get_shader_i(&self, shader: GLuint, name: ShaderParameterName) -> GLint;
+    fn shader_info_log(&self, shader: GLuint) -> String;
+    fn create_program(&self) -> GLuint;
+    fn delete_program(&self, program: GLuint);
+    fn attach_shader(&self, program: GLuint, shader: GLuint);
+    fn detach_shader(&self, program: GLuint, shader: GLuint);
+    fn bind_attrib_location(&self, program: GLuint, index: GLuint, name: &str);
+    fn link_program(&self, program: GLuint);
+    fn get_program_i(&self, program: GLuint, name: ProgramPropertyARB) -> GLint;
+    fn program_info_log(&self, program: GLuint) -> String;
+    fn use_program(&self, program: GLuint);
+    fn get_uniform_location(&self, program: GLuint, name: &str) -> GLint;
+    // One ivecN, vecN or matN of the given size
+    fn uniform_i(&self, location: GLint, size: usize, values: &[GLint]);
+    fn uniform_f(&self, location: GLint, size: usize, values: &[GLfloat]);
+    fn uniform_matrix(&self, location: GLint, size: usize, values: &[GLfloat]);
+}
+
+// Calls straight through to the loaded GL functions
+pub struct NativeGl {
+    _thread: GlThread,
+}
+
+impl NativeGl {
+    pub fn new(thread: GlThread) -> NativeGl {
+        NativeGl { _thread: thread }
+    }
+}
+
+fn info_log_string(mut log: Vec<u8>) -> String {
+    if let Some(end) = log.iter().position(|c| *c == 0) {
+        log.truncate(end);
+    }
+    String::from_utf8_lossy(&log).trim_end().to_string()
+}
+
+// Holding a GlThread means the context is current, so the unsafe blocks
+// below only have to check pointer arguments
+impl GlBackend for NativeGl {
+    fn get_error(&self) -> GLenum {
+        unsafe { glGetError() }
+    }
+
+    fn get_integer(&self, name: GLenum) -> GLint {
+        let mut value = 0;
+        unsafe { glGetIntegerv(name, &mut value) }
+        value
+    }
+
+    fn enable(&self, cap: EnableCap) {
+        unsafe { glEnable(cap) }
+    }
+
+    fn disable(&self, cap: EnableCap) {
+        unsafe { glDisable(cap) }
+    }
+
+    fn depth_func(&self, func: DepthFunction) {
+        unsafe { glDepthFunc(func) }
+    }
+
+    fn depth_mask(&self, mask: bool) {
+        unsafe { glDepthMask(mask as GLboolean) }
+    }
+
+    fn blend_func(&self, src: BlendingFactor, dst: BlendingFactor) {
+        unsafe { glBlendFunc(src, dst) }
+    }
+
+    fn blend_func_separate(
+        &self,
+        src_rgb: BlendingFactor,
+        dst_rgb: BlendingFactor,
+        src_alpha: BlendingFactor,
+        dst_alpha: BlendingFactor,
+    ) {
+        unsafe { glBlendFuncSeparate(src_rgb, dst_rgb, src_alpha, dst_alpha) }
+    }
+
+    fn blend_equation(&self, equation: BlendEquationModeEXT) {
+        unsafe { glBlendEquation(equation) }
+    }
+
+    fn cull_face(&self, mode: CullFaceMode) {
+        unsafe { glCullFace(mode) }
+    }
+
+    fn polygon_offset(&self, factor: GLfloat, units: GLfloat) {
+        unsafe { glPolygonOffset(factor, units) }
+    }
+
+    fn logic_op(&self, op: LogicOp) {
+        unsafe { glLogicOp(op) }
+    }
+
+    fn viewport(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        unsafe { glViewport(x, y, width, height) }
+    }
+
+    fn color_mask(&self, red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) {
+        unsafe { glColorMask(red, green, blue, alpha) }
+    }
+
+    fn stencil_func(&self, func: StencilFunction, ref_: GLint, mask: GLuint) {
+        unsafe { glStencilFunc(func, ref_, mask) }
+    }
+
+    fn stencil_mask(&self, mask: GLuint) {
+        unsafe { glStencilMask(mask) }
+    }
+
+    fn stencil_op(&self, sfail: StencilOp, dpfail: StencilOp, dppass: StencilOp) {
+        unsafe { glStencilOp(sfail, dpfail, dppass) }
+    }
+
+    fn clear_color(&self, red: GLfloat, green: GLfloat, blue: GLfloat, alpha: GLfloat) {
+        unsafe { glClearColor(red, green, blue, alpha) }
+    }
+
+    fn clear_depth(&self, depth: f64) {
+        unsafe { glClearDepth(depth) }
+    }
+
+    fn clear(&self, mask: GLbitfield) {
+        unsafe { glClear(mask) }
+    }
+
+    fn gen_texture(&self) -> GLuint {
+        let mut texture = 0;
+        unsafe { glGenTextures(1, &mut texture) }
+        texture
+    }
+
+    fn delete_textures(&self, textures: &[GLuint]) {
+        unsafe { glDeleteTextures(textures.len() as GLsizei, textures.as_ptr()) }
+    }
+
+    fn active_texture(&self, unit: TextureUnit) {
+        unsafe { glActiveTexture(unit) }
+    }
+
+    fn bind_texture(&self, target: TextureTarget, texture: GLuint) {
+        unsafe { glBindTexture(target, texture) }
+    }
+
+    fn tex_parameter_i(&self, target: TextureTarget, name: TextureParameterName, value: GLint) {
+        unsafe { glTexParameteri(target, name, value) }
+    }
+
+    fn tex_parameter_f(&self, target: TextureTarget, name: TextureParameterName, value: GLfloat) {
+        unsafe { glTexParameterf(target, name, value) }
+    }
+
+    fn tex_image_2d(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        internal_format: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: Option<&[u8]>,
+    ) {
+        let pixels = pixels.map_or(null(), |pixels| pixels.as_ptr().cast());
+        unsafe {
+            glTexImage2D(
+                target,
+                level,
+                internal_format,
+                width,
+                height,
+                0,
+                format,
+                type_,
+                pixels,
+            )
+        }
+    }
+
+    fn tex_sub_image_2d(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        x: GLint,
+        y: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: &[u8],
+    ) {
+        unsafe {
+            glTexSubImage2D(
+                target,
+                level,
+                x,
+                y,
+                width,
+                height,
+                format,
+                type_,
+                pixels.as_ptr().cast(),
+            )
+        }
+    }
+
+    fn pixel_store_i(&self, name: PixelStoreParameter, value: GLint) {
+        unsafe { glPixelStorei(name, value) }
+    }
+
+    fn get_tex_image(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: &mut [u8],
+    ) {
+        unsafe { glGetTexImage(target, level, format, type_, pixels.as_mut_ptr().cast()) }
+    }
+
+    fn get_tex_level_parameter_i(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        name: GetTextureParameter,
+    ) -> GLint {
+        let mut value = 0;
+        unsafe { glGetTexLevelParameteriv(target, level, name, &mut value) }
+        value
+    }
+
+    fn gen_framebuffer(&self) -> GLuint {
+        let mut framebuffer = 0;
+        unsafe { glGenFramebuffers(1, &mut framebuffer) }
+        framebuffer
+    }
+
+    fn delete_framebuffer(&self, framebuffer: GLuint) {
+        unsafe { glDeleteFramebuffers(1, &framebuffer) }
+    }
+
+    fn bind_framebuffer(&self, target: FramebufferTarget, framebuffer: GLuint) {
+        unsafe { glBindFramebuffer(target, framebuffer) }
+    }
+
+    fn framebuffer_texture_2d(
+        &self,
+        target: FramebufferTarget,
+        attachment: FramebufferAttachment,
+        texture_target: TextureTarget,
+        texture: GLuint,
+        level: GLint,
+    ) {
+        unsafe { glFramebufferTexture2D(target, attachment, texture_target, texture, level) }
+    }
+
+    fn check_framebuffer_status(&self, target: FramebufferTarget) -> FramebufferStatus {
+        unsafe { glCheckFramebufferStatus(target) }
+    }
+
+    fn gen_buffer(&self) -> GLuint {
+        let mut buffer = 0;
+        unsafe { glGenBuffers(1, &mut buffer) }
+        buffer
+    }
+
+    fn delete_buffer(&self, buffer: GLuint) {
+        unsafe { glDeleteBuffers(1, &buffer) }
+    }
+
+    fn bind_buffer(&self, target: BufferTargetARB, buffer: GLuint) {
+        unsafe { glBindBuffer(target, buffer) }
+    }
+
+    fn buffer_data(&self, target: BufferTargetARB, data: &[u8], usage: BufferUsageARB) {
+        unsafe {
+            glBufferData(
+                target,
+                data.len() as GLsizeiptr,
+                data.as_ptr().cast(),
+                usage,
+            )
+        }
+    }
+
+    fn gen_vertex_array(&self) -> GLuint {
+        let mut vertex_array = 0;
+        unsafe { glGenVertexArrays(1, &mut vertex_array) }
+        vertex_array
+    }
+
+    fn delete_vertex_array(&self, vertex_array: GLuint) {
+        unsafe { glDeleteVertexArrays(1, &vertex_array) }
+    }
+
+    fn bind_vertex_array(&self, vertex_array: GLuint) {
+        glBindVertexArray(vertex_array)
+    }
+
+    fn enable_vertex_attrib_array(&self, index: GLuint) {
+        unsafe { glEnableVertexAttribArray(index) }
+    }
+
+    fn disable_vertex_attrib_array(&self, index: GLuint) {
+        unsafe { glDisableVertexAttribArray(index) }
+    }
+
+    fn vertex_attrib_pointer(
+        &self,
+        index: GLuint,
+        size: GLint,
+        type_: VertexAttribPointerType,
+        normalized: bool,
+        stride: GLsizei,
+        offset: usize,
+    ) {
+        unsafe {
+            glVertexAttribPointer(
+                index,
+                size,
+                type_,
+                normalized as GLboolean,
+                stride,
+                offset as *const _,
+            )
+        }
+    }
+
+    fn vertex_attrib_i_pointer(
+        &self,
+        index: GLuint,
+        size: GLint,
+        type_: VertexAttribIType,
+        stride: GLsizei,
+        offset: usize,
+    ) {
+        unsafe { glVertexAttribIPointer(index, size, type_, stride, offset as *const _) }
+    }
+
+    fn draw_elements(
+        &self,
+        mode: PrimitiveType,
+        count: GLsizei,
+        type_: DrawElementsType,
+        offset: usize,
+    ) {
+        unsafe { glDrawElements(mode, count, type_, offset as *const _) }
+    }
+
+    fn create_shader(&self, kind: ShaderType) -> GLuint {
+        glCreateShader(kind)
+    }
+
+    fn delete_shader(&self, shader: GLuint) {
+        glDeleteShader(shader)
+    }
+
+    fn shader_source(&self, shader: GLuint, source: &str) {
+        let length = source.len() as GLint;
+        let source = source.as_ptr();
+        unsafe { glShaderSource(shader, 1, &source, &length) }
+    }
+
+    fn compile_shader(&self, shader: GLuint) {
+        glCompileShader(shader)
+    }
+
+    fn get_shader_i(&self, shader: GLuint, name: ShaderParameterName) -> GLint {
+        let mut value = 0;
+        unsafe { glGetShaderiv(shader, name, &mut value) }
+        value
+    }
+
+    fn shader_info_log(&self, shader: GLuint) -> String {
+        let length = self.get_shader_i(shader, GL_INFO_LOG_LENGTH);
+        let mut log = vec![0u8; length.max(1) as usize];
+        unsafe { glGetShaderInfoLog(shader, log.len() as GLsizei, null_mut(), log.as_mut_ptr()) }
+        info_log_string(log)
+    }
+
+    fn create_program(&self) -> GLuint {
+        glCreateProgram()
+    }
+
+    fn delete_program(&self, program: GLuint) {
+        glDeleteProgram(program)
+    }
+
+    fn attach_shader(&self, program: GLuint, shader: GLuint) {
+        glAttachShader(program, shader)
+    }
+
+    fn detach_shader(&self, program: GLuint, shader: GLuint) {
+        unsafe { glDetachShader(program, shader) }
+    }
+
+    fn bind_attrib_location(&self, program: GLuint, index: GLuint, name: &str) {
+        let name = CString::new(name).unwrap();
+        unsafe { glBindAttribLocation(program, index, name.as_ptr().cast()) }
+    }
+
+    fn link_program(&self, program: GLuint) {
+        glLinkProgram(program)
+    }
+
+    fn get_program_i(&self, program: GLuint, name: ProgramPropertyARB) -> GLint {
+        let mut value = 0;
+        unsafe { glGetProgramiv(program, name, &mut value) }
+        value
+    }
+
+    fn program_info_log(&self, program: GLuint) -> String {
+        let length = self.get_program_i(program, GL_INFO_LOG_LENGTH);
+        let mut log = vec![0u8; length.max(1) as usize];
+        unsafe { glGetProgramInfoLog(program, log.len() as GLsizei, null_mut(), log.as_mut_ptr()) }
+        info_log_string(log)
+    }
+
+    fn use_program(&self, program: GLuint) {
+        glUseProgram(program)
+    }
+
+    fn get_uniform_location(&self, program: GLuint, name: &str) -> GLint {
+        let name = CString::new(name).unwrap();
+        unsafe { glGetUniformLocation(program, name.as_ptr().cast()) }
+    }
+
+    fn uniform_i(&self, location: GLint, size: usize, values: &[GLint]) {
+        assert!(values.len() >= size);
+        let values = values.as_ptr();
+        unsafe {
+            match size {
+                1 => glUniform1iv(location, 1, values),
+                2 => glUniform2iv(location, 1, values),
+                3 => glUniform3iv(location, 1, values),
+                _ => glUniform4iv(location, 1, values),
+            }
+        }
+    }
+
+    fn uniform_f(&self, location: GLint, size: usize, values: &[GLfloat]) {
+        assert!(values.len() >= size);
+        let values = values.as_ptr();
+        unsafe {
+            match size {
+                1 => glUniform1fv(location, 1, values),
+                2 => glUniform2fv(location, 1, values),
+                3 => glUniform3fv(location, 1, values),
+                _ => glUniform4fv(location, 1, values),
+            }
+        }
+    }
+
+    fn uniform_matrix(&self, location: GLint, size: usize, values: &[GLfloat]) {
+        assert!(values.len() >= size * size);
+        let values = values.as_ptr();
+        unsafe {
+            match size {
+                2 => glUniformMatrix2fv(location, 1, 0, values),
+                3 => glUniformMatrix3fv(location, 1, 0, values),
+                _ => glUniformMatrix4fv(location, 1, 0, values),
+            }
+        }
+    }
+}
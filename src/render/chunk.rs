@@ -0,0 +1,486 @@
+use gl33::*;
+use thiserror::Error;
+use ultraviolet::Vec3;
+
+use crate::render::atlas::TextureAtlas;
+use crate::render::shader::Shader;
+use crate::render::uniform::StandardUniforms;
+use crate::render::util::{GlStateManager, RenderState};
+use crate::render::vertex_buffer::{BufferUsage, VertexBuffer};
+use crate::render::{BufferBuilder, DrawMode, State, VertexError, VertexFormat};
+use crate::resources::Identifier;
+
+#[derive(Debug, Error)]
+pub enum ChunkMeshError {
+    #[error(transparent)]
+    Vertex(#[from] VertexError),
+    // Faces are drawn with a single atlas page bound
+    #[error("sprites {0} and {1} are on different atlas pages")]
+    SplitPages(Identifier, Identifier),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    Air,
+    Stone,
+    Glass,
+    RedStainedGlass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    Solid,
+    Translucent,
+}
+
+impl Block {
+    // Sprite in the block atlas, None for blocks that aren't drawn
+    fn sprite(self) -> Option<&'static str> {
+        match self {
+            Block::Air => None,
+            Block::Stone => Some("block/stone"),
+            Block::Glass => Some("block/glass"),
+            Block::RedStainedGlass => Some("block/red_stained_glass"),
+        }
+    }
+
+    fn layer(self) -> Layer {
+        match self {
+            Block::Glass | Block::RedStainedGlass => Layer::Translucent,
+            _ => Layer::Solid,
+        }
+    }
+
+    // Hides the faces of every block next to it
+    fn is_opaque(self) -> bool {
+        self == Block::Stone
+    }
+}
+
+// A 16x16x16 cube of blocks, everything outside it is air
+pub struct Section {
+    blocks: Vec<Block>,
+}
+
+impl Section {
+    pub const SIZE: i32 = 16;
+
+    pub fn new() -> Section {
+        Section {
+            blocks: vec![Block::Air; (Section::SIZE * Section::SIZE * Section::SIZE) as usize],
+        }
+    }
+
+    fn index(x: i32, y: i32, z: i32) -> Option<usize> {
+        let range = 0..Section::SIZE;
+        if range.contains(&x) && range.contains(&y) && range.contains(&z) {
+            Some(((y * Section::SIZE + z) * Section::SIZE + x) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, x: i32, y: i32, z: i32) -> Block {
+        Section::index(x, y, z).map_or(Block::Air, |index| self.blocks[index])
+    }
+
+    // Positions outside the section are ignored
+    pub fn set(&mut self, x: i32, y: i32, z: i32, block: Block) {
+        if let Some(index) = Section::index(x, y, z) {
+            self.blocks[index] = block;
+        }
+    }
+
+    // Fills the box between both corners, inclusive
+    pub fn fill(&mut self, from: (i32, i32, i32), to: (i32, i32, i32), block: Block) {
+        for y in from.1..=to.1 {
+            for z in from.2..=to.2 {
+                for x in from.0..=to.0 {
+                    self.set(x, y, z, block);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Section {
+    fn default() -> Section {
+        Section::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Face {
+    offset: (i32, i32, i32),
+    // Brightness of the face so the sides of a block can be told apart
+    shade: f32,
+    // Counter-clockwise seen from outside, starting at the top left of the
+    // sprite and going down
+    corners: [(f32, f32, f32); 4],
+}
+
+const FACES: [Face; 6] = [
+    // Up
+    Face {
+        offset: (0, 1, 0),
+        shade: 1.0,
+        corners: [
+            (0.0, 1.0, 0.0),
+            (0.0, 1.0, 1.0),
+            (1.0, 1.0, 1.0),
+            (1.0, 1.0, 0.0),
+        ],
+    },
+    // Down
+    Face {
+        offset: (0, -1, 0),
+        shade: 0.5,
+        corners: [
+            (0.0, 0.0, 1.0),
+            (0.0, 0.0, 0.0),
+            (1.0, 0.0, 0.0),
+            (1.0, 0.0, 1.0),
+        ],
+    },
+    // North
+    Face {
+        offset: (0, 0, -1),
+        shade: 0.8,
+        corners: [
+            (1.0, 1.0, 0.0),
+            (1.0, 0.0, 0.0),
+            (0.0, 0.0, 0.0),
+            (0.0, 1.0, 0.0),
+        ],
+    },
+    // South
+    Face {
+        offset: (0, 0, 1),
+        shade: 0.8,
+        corners: [
+            (0.0, 1.0, 1.0),
+            (0.0, 0.0, 1.0),
+            (1.0, 0.0, 1.0),
+            (1.0, 1.0, 1.0),
+        ],
+    },
+    // West
+    Face {
+        offset: (-1, 0, 0),
+        shade: 0.6,
+        corners: [
+            (0.0, 1.0, 0.0),
+            (0.0, 0.0, 0.0),
+            (0.0, 0.0, 1.0),
+            (0.0, 1.0, 1.0),
+        ],
+    },
+    // East
+    Face {
+        offset: (1, 0, 0),
+        shade: 0.6,
+        corners: [
+            (1.0, 1.0, 1.0),
+            (1.0, 0.0, 1.0),
+            (1.0, 0.0, 0.0),
+            (1.0, 1.0, 0.0),
+        ],
+    },
+];
+
+const CORNER_UVS: [(f32, f32); 4] = [(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)];
+
+// The atlas page holding the sprite of every block drawn in section
+fn sprite_page(section: &Section, atlas: &TextureAtlas) -> Result<usize, ChunkMeshError> {
+    let mut found: Option<(Identifier, usize)> = None;
+    for block in &section.blocks {
+        let identifier = match block.sprite() {
+            Some(name) => Identifier::minecraft(name).unwrap(),
+            None => continue,
+        };
+        let page = atlas.sprite(identifier).page;
+        match found {
+            Some((first, first_page)) if first_page != page => {
+                return Err(ChunkMeshError::SplitPages(first, identifier))
+            }
+            Some(_) => {}
+            None => found = Some((identifier, page)),
+        }
+    }
+    Ok(found.map_or(0, |(_, page)| page))
+}
+
+// Writes the faces of every block in layer that aren't hidden by an opaque
+// neighbour or one of the same block
+fn put_faces(
+    builder: &mut BufferBuilder,
+    section: &Section,
+    atlas: &TextureAtlas,
+    layer: Layer,
+) -> Result<(), VertexError> {
+    for y in 0..Section::SIZE {
+        for z in 0..Section::SIZE {
+            for x in 0..Section::SIZE {
+                let block = section.get(x, y, z);
+                let sprite = match block.sprite() {
+                    Some(name) if block.layer() == layer => {
+                        atlas.sprite(Identifier::minecraft(name).unwrap())
+                    }
+                    _ => continue,
+                };
+                for face in &FACES {
+                    let (dx, dy, dz) = face.offset;
+                    let neighbour = section.get(x + dx, y + dy, z + dz);
+                    if neighbour.is_opaque() || neighbour == block {
+                        continue;
+                    }
+                    let shade = (face.shade * 255.0) as u8;
+                    for ((cx, cy, cz), (u, v)) in face.corners.iter().zip(CORNER_UVS) {
+                        builder
+                            .vertex(x as f32 + cx, y as f32 + cy, z as f32 + cz)?
+                            .uv(sprite.u(u), sprite.v(v))?
+                            .color(shade, shade, shade, 0xFF)?
+                            .end_vertex()?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The faces of a section uploaded once for drawing every frame. Translucent
+// faces are sorted back to front from the camera and sorted again, without
+// uploading the vertices, when it moves
+pub struct ChunkMesh {
+    solid: VertexBuffer,
+    translucent: VertexBuffer,
+    // Sort state of the translucent faces and where they were sorted from
+    translucent_state: Option<State>,
+    camera: Vec3,
+    // Atlas page every block sprite is on, build fails when they are split
+    // over several
+    texture: TextureUnit,
+}
+
+impl ChunkMesh {
+    pub fn build(
+        gl: &GlStateManager,
+        builder: &mut BufferBuilder,
+        section: &Section,
+        atlas: &TextureAtlas,
+        camera: Vec3,
+    ) -> Result<ChunkMesh, ChunkMeshError> {
+        let page = sprite_page(section, atlas)?;
+        let translucent_state = match put_layers(builder, section, atlas, camera) {
+            Ok(state) => state,
+            Err(err) => {
+                // Drops the half built batches so the builder can begin again
+                builder.reset();
+                return Err(err.into());
+            }
+        };
+        let mut solid = VertexBuffer::new(gl, BufferUsage::Static);
+        let mut translucent = VertexBuffer::new(gl, BufferUsage::Static);
+        upload_next(builder, &mut solid);
+        upload_next(builder, &mut translucent);
+        Ok(ChunkMesh {
+            solid,
+            translucent,
+            translucent_state,
+            camera,
+            texture: atlas.texture(page),
+        })
+    }
+
+    // Sorts the translucent faces again if the camera moved since they were
+    pub fn resort(&mut self, builder: &mut BufferBuilder, camera: Vec3) -> Result<(), VertexError> {
+        if camera == self.camera {
+            return Ok(());
+        }
+        let state = match self.translucent_state.take() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION_TEXTURE_COLOR);
+        builder.restore_state(state);
+        builder.set_camera_position(camera);
+        if let Err(err) = builder.end() {
+            builder.reset();
+            return Err(err);
+        }
+        self.translucent_state = builder.pop_state();
+        self.camera = camera;
+        upload_next(builder, &mut self.translucent);
+        Ok(())
+    }
+
+    // Solid faces first so the translucent ones blend over them. shader has
+    // to take VertexFormat::POSITION_TEXTURE_COLOR and a Sampler0
+    pub fn draw(&self, gl: &mut GlStateManager, shader: &mut Shader, uniforms: &StandardUniforms) {
+        shader.set_sampler("Sampler0", self.texture.0);
+        self.solid.draw_with_shader(
+            &mut gl.push_render_state(&RenderState::OPAQUE),
+            shader,
+            uniforms,
+        );
+        self.translucent.draw_with_shader(
+            &mut gl.push_render_state(&RenderState::TRANSLUCENT),
+            shader,
+            uniforms,
+        );
+    }
+}
+
+// Queues the solid faces then the translucent ones sorted from camera,
+// returning the sort state of the latter
+fn put_layers(
+    builder: &mut BufferBuilder,
+    section: &Section,
+    atlas: &TextureAtlas,
+    camera: Vec3,
+) -> Result<Option<State>, VertexError> {
+    builder.begin(DrawMode::Quads, VertexFormat::POSITION_TEXTURE_COLOR);
+    put_faces(builder, section, atlas, Layer::Solid)?;
+    builder.end()?;
+    builder.begin(DrawMode::Quads, VertexFormat::POSITION_TEXTURE_COLOR);
+    builder.set_camera_position(camera);
+    put_faces(builder, section, atlas, Layer::Translucent)?;
+    builder.end()?;
+    Ok(builder.pop_state())
+}
+
+fn upload_next(builder: &mut BufferBuilder, buffer: &mut VertexBuffer) {
+    if let Some((parameters, data)) = builder.pop_data() {
+        buffer.upload(parameters, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+    use crate::resources::ResourceManager;
+
+    // Targets of the buffers uploaded as static, the shared sequential index
+    // buffer is dynamic and left out
+    fn static_uploads(backend: &RecordingGl) -> Vec<i64> {
+        backend
+            .take_calls()
+            .into_iter()
+            .filter(|call| call.function == "glBufferData")
+            .filter(|call| call.args[2] == GL_STATIC_DRAW.0 as i64)
+            .map(|call| call.args[0])
+            .collect()
+    }
+
+    #[test]
+    fn culls_faces_hidden_by_neighbours() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let atlas = TextureAtlas::load(&mut gl, &ResourceManager::new(), 0);
+        let mut section = Section::new();
+        // Two stones then two glass blocks in a row
+        section.fill((0, 0, 0), (1, 0, 0), Block::Stone);
+        section.fill((2, 0, 0), (3, 0, 0), Block::Glass);
+        let mut builder = BufferBuilder::new(256);
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION_TEXTURE_COLOR);
+        put_faces(&mut builder, &section, &atlas, Layer::Solid).unwrap();
+        put_faces(&mut builder, &section, &atlas, Layer::Translucent).unwrap();
+        builder.end().unwrap();
+        let (parameters, _) = builder.pop_data().unwrap();
+        // Touching faces of the same block are hidden, and so is the glass
+        // behind the stone but not the stone behind the glass
+        assert_eq!(parameters.count, (5 + 5 + 4 + 5) * 4);
+    }
+
+    #[test]
+    fn uploads_once_and_resorts_only_indices() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let atlas = TextureAtlas::load(&mut gl, &ResourceManager::new(), 0);
+        let mut section = Section::new();
+        section.set(0, 0, 0, Block::Stone);
+        section.set(2, 0, 0, Block::Glass);
+        let mut builder = BufferBuilder::new(256);
+        backend.take_calls();
+        let mut mesh = ChunkMesh::build(
+            &gl,
+            &mut builder,
+            &section,
+            &atlas,
+            Vec3::new(8.0, 8.0, 8.0),
+        )
+        .unwrap();
+        let array = GL_ARRAY_BUFFER.0 as i64;
+        let element = GL_ELEMENT_ARRAY_BUFFER.0 as i64;
+        // Solid faces are drawn with the shared indices, translucent ones
+        // with their own sorted indices
+        assert_eq!(static_uploads(&backend), [array, array, element]);
+
+        mesh.resort(&mut builder, Vec3::new(8.0, 8.0, 8.0)).unwrap();
+        assert!(static_uploads(&backend).is_empty());
+        mesh.resort(&mut builder, Vec3::new(-8.0, 0.0, 0.0))
+            .unwrap();
+        assert_eq!(static_uploads(&backend), [element]);
+    }
+
+    #[test]
+    fn rejects_sprites_on_different_pages() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let resources = ResourceManager::new();
+        let mut atlas = TextureAtlas::load(&mut gl, &resources, 0);
+        // Restitched with room for a single sprite per page
+        atlas.upload(&mut gl, TextureAtlas::prepare_sprites(&resources, 16, 0));
+        let mut section = Section::new();
+        section.set(0, 0, 0, Block::Stone);
+        let mut builder = BufferBuilder::new(256);
+        assert!(ChunkMesh::build(&gl, &mut builder, &section, &atlas, Vec3::zero()).is_ok());
+
+        section.set(2, 0, 0, Block::Glass);
+        let result = ChunkMesh::build(&gl, &mut builder, &section, &atlas, Vec3::zero());
+        assert!(matches!(result, Err(ChunkMeshError::SplitPages(..))));
+    }
+
+    #[test]
+    fn draws_translucent_faces_blended_after_solid_ones() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let resources = ResourceManager::new();
+        let atlas = TextureAtlas::load(&mut gl, &resources, 0);
+        let mut shader = Shader::load(&gl, &resources, "position_tex_color").unwrap();
+        let mut section = Section::new();
+        section.set(0, 0, 0, Block::Stone);
+        section.set(2, 0, 0, Block::RedStainedGlass);
+        let mut builder = BufferBuilder::new(256);
+        let mesh = ChunkMesh::build(&gl, &mut builder, &section, &atlas, Vec3::zero()).unwrap();
+        backend.take_calls();
+        mesh.draw(&mut gl, &mut shader, &StandardUniforms::new());
+        let calls: Vec<&str> = backend
+            .take_calls()
+            .iter()
+            .filter(|call| match call.function {
+                "glEnable" | "glDisable" => call.args[0] == GL_BLEND.0 as i64,
+                "glDrawElements" | "glUseProgram" => true,
+                _ => false,
+            })
+            .map(|call| call.function)
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "glUseProgram",
+                "glDrawElements",
+                "glUseProgram",
+                "glEnable",
+                "glUseProgram",
+                "glDrawElements",
+                "glUseProgram",
+                "glDisable",
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use gl33::*;
+use thiserror::Error;
+
+use crate::render::texture::{ImageError, NativeImage};
+use crate::render::util::{GlStateManager, TextureDeleter};
+use crate::render::{BufferBuilder, VertexError};
+use crate::resources::reload::{PrepareTask, Prepared, ReloadListener};
+use crate::resources::{Identifier, ResourceError, ResourceManager};
+use crate::types::GLint;
+
+#[derive(Debug, Error)]
+pub enum FontError {
+    #[error("unable to read the font texture: {0}")]
+    Resource(#[from] ResourceError),
+    #[error("invalid font texture: {0}")]
+    Image(#[from] ImageError),
+    #[error("font texture is {0}x{1} but has to be a square multiple of 16")]
+    Size(u32, u32),
+}
+
+// The ASCII glyphs of textures/font/ascii.png, a 16x16 grid of cells
+// indexed by code point. Glyphs are as wide as their rightmost opaque column
+pub struct Font {
+    texture: TextureUnit,
+    // Cell size in texels, 8 for the default texture
+    cell: u32,
+    widths: [u32; 256],
+    deleter: TextureDeleter,
+}
+
+impl Font {
+    pub const TEXTURE: &'static str = "textures/font/ascii.png";
+    // Height of a line in GUI pixels, glyphs are scaled to 8 whatever the
+    // cell size
+    pub const LINE_HEIGHT: f32 = 9.0;
+    const GLYPH_SIZE: f32 = 8.0;
+    const SPACE_WIDTH: u32 = 3;
+
+    pub fn load(gl: &mut GlStateManager, resources: &ResourceManager) -> Result<Font, FontError> {
+        let image = Font::read(resources)?;
+        let mut font = Font {
+            texture: gl.gen_texture(),
+            cell: 0,
+            widths: [0; 256],
+            deleter: gl.texture_deleter(),
+        };
+        font.upload(gl, &image);
+        Ok(font)
+    }
+
+    fn read(resources: &ResourceManager) -> Result<NativeImage, FontError> {
+        let identifier = Identifier::minecraft(Font::TEXTURE).unwrap();
+        let image = NativeImage::decode(&resources.get(identifier)?.data)?;
+        if image.width() != image.height() || image.width() % 16 != 0 || image.width() == 0 {
+            return Err(FontError::Size(image.width(), image.height()));
+        }
+        Ok(image)
+    }
+
+    fn upload(&mut self, gl: &mut GlStateManager, image: &NativeImage) {
+        gl.bind_texture(self.texture);
+        image.upload(gl, 0);
+        let backend = gl.backend();
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, GL_NEAREST.0 as GLint);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_NEAREST.0 as GLint);
+        gl.bind_texture(GL_ZERO);
+        self.cell = image.width() / 16;
+        for (code, width) in self.widths.iter_mut().enumerate() {
+            *width = glyph_width(image, self.cell, code as u32);
+        }
+        self.widths[b' ' as usize] = Font::SPACE_WIDTH * self.cell / 8;
+    }
+
+    pub fn texture(&self) -> TextureUnit {
+        self.texture
+    }
+
+    // Advance of a glyph in GUI pixels, one column of spacing included
+    fn advance(&self, code: u8) -> f32 {
+        (self.widths[code as usize] as f32 / self.cell as f32 + 1.0 / 8.0) * Font::GLYPH_SIZE
+    }
+
+    // Writes a quad per glyph with its top left corner at x, y in GUI pixels.
+    // builder has to be building quads of VertexFormat::POSITION_TEXTURE_COLOR
+    pub fn draw(
+        &self,
+        builder: &mut BufferBuilder,
+        text: &str,
+        x: f32,
+        y: f32,
+        color: [u8; 4],
+    ) -> Result<(), VertexError> {
+        let [r, g, b, a] = color;
+        let texel = 1.0 / (self.cell * 16) as f32;
+        let mut left = x;
+        for code in text.bytes().filter(u8::is_ascii) {
+            let width = self.widths[code as usize];
+            if width > 0 {
+                let u0 = (code as u32 % 16 * self.cell) as f32 * texel;
+                let v0 = (code as u32 / 16 * self.cell) as f32 * texel;
+                let u1 = u0 + width as f32 * texel;
+                let v1 = v0 + self.cell as f32 * texel;
+                let right = left + width as f32 / self.cell as f32 * Font::GLYPH_SIZE;
+                let bottom = y + Font::GLYPH_SIZE;
+                for (px, py, u, v) in [
+                    (left, y, u0, v0),
+                    (left, bottom, u0, v1),
+                    (right, bottom, u1, v1),
+                    (right, y, u1, v0),
+                ] {
+                    builder
+                        .vertex(px, py, 0.0)?
+                        .uv(u, v)?
+                        .color(r, g, b, a)?
+                        .end_vertex()?;
+                }
+            }
+            left += self.advance(code);
+        }
+        Ok(())
+    }
+}
+
+// Columns up to and including the rightmost with any opaque texel, 0 for
+// empty cells
+fn glyph_width(image: &NativeImage, cell: u32, code: u32) -> u32 {
+    let (x, y) = (code % 16 * cell, code / 16 * cell);
+    (0..cell)
+        .rev()
+        .find(|column| (0..cell).any(|row| image.pixel(x + column, y + row)[3] > 0))
+        .map_or(0, |column| column + 1)
+}
+
+// Uploaded again into the same texture so drawn text never sees it missing
+impl ReloadListener for Font {
+    fn name(&self) -> &str {
+        "font"
+    }
+
+    fn prepare(&self) -> PrepareTask {
+        Box::new(|resources| Ok(Box::new(Font::read(resources)?) as Prepared))
+    }
+
+    fn apply(&mut self, gl: &mut GlStateManager, prepared: Prepared) {
+        let image = prepared.downcast::<NativeImage>().unwrap();
+        self.upload(gl, &image);
+    }
+}
+
+impl Drop for Font {
+    fn drop(&mut self) {
+        self.deleter.delete(&[self.texture]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_glyphs_by_their_opaque_columns() {
+        let mut image = NativeImage::new(128, 128);
+        // 'A' is cell 1 of row 4, three columns wide with a gap in the middle
+        for (column, row) in [(0, 2), (2, 5)] {
+            image.set_pixel(8 + column, 32 + row, [0xFF; 4]);
+        }
+        assert_eq!(glyph_width(&image, 8, b'A' as u32), 3);
+        assert_eq!(glyph_width(&image, 8, b'B' as u32), 0);
+    }
+}
@@ -0,0 +1,191 @@
+use gl33::*;
+
+use crate::render::backend::GlBackend;
+use crate::render::util::delete_buffer;
+use crate::render::{DrawMode, IntType};
+use crate::types::GLuint;
+
+// Index buffer for vertices drawn in order, shared between every
+// VertexBuffer with the same DrawMode. Grows when a draw needs more
+// indices than it holds, existing indices never change so buffers
+// uploaded earlier stay valid
+pub struct SharedIndexBuffer {
+    id: GLuint,
+    // Vertices consumed by each repeat of the pattern
+    vertex_stride: usize,
+    pattern: &'static [usize],
+    index_type: IntType,
+    index_count: usize,
+}
+
+impl SharedIndexBuffer {
+    const fn new(vertex_stride: usize, pattern: &'static [usize]) -> SharedIndexBuffer {
+        SharedIndexBuffer {
+            id: 0,
+            vertex_stride,
+            pattern,
+            index_type: IntType::Byte,
+            index_count: 0,
+        }
+    }
+
+    pub fn has_storage(&self, index_count: usize) -> bool {
+        index_count <= self.index_count
+    }
+
+    pub fn index_type(&self) -> IntType {
+        self.index_type
+    }
+
+    // Binds to GL_ELEMENT_ARRAY_BUFFER of the bound vertex array, growing
+    // the buffer first if it holds less than index_count indices
+    pub fn bind(&mut self, backend: &dyn GlBackend, index_count: usize) {
+        if self.id == 0 {
+            self.id = backend.gen_buffer();
+        }
+        backend.bind_buffer(GL_ELEMENT_ARRAY_BUFFER, self.id);
+        if self.has_storage(index_count) {
+            return;
+        }
+        let pattern_length = self.pattern.len();
+        // Double to avoid regrowing on every slightly larger draw
+        let index_count = (index_count * 2).next_multiple_of(pattern_length);
+        let vertex_count = index_count / pattern_length * self.vertex_stride;
+        let index_type = IntType::get_smallest_for(vertex_count);
+        let mut data = Vec::with_capacity(index_count * index_type.size());
+        for i in 0..index_count {
+            let index = i / pattern_length * self.vertex_stride + self.pattern[i % pattern_length];
+            match index_type {
+                IntType::Byte => data.push(index as u8),
+                IntType::Short => data.extend_from_slice(&(index as u16).to_ne_bytes()),
+                IntType::Int => data.extend_from_slice(&(index as u32).to_ne_bytes()),
+            }
+        }
+        backend.buffer_data(GL_ELEMENT_ARRAY_BUFFER, &data, GL_DYNAMIC_DRAW);
+        self.index_type = index_type;
+        self.index_count = index_count;
+    }
+
+    // Bound again later the buffer starts over from a new id
+    fn delete(&mut self, backend: &dyn GlBackend) {
+        if self.id != 0 {
+            delete_buffer(backend, self.id);
+        }
+        self.id = 0;
+        self.index_type = IntType::Byte;
+        self.index_count = 0;
+    }
+}
+
+// The shared index buffers of one context, owned by its GlStateManager
+pub struct SharedIndexBuffers {
+    sequential: SharedIndexBuffer,
+    // Four vertices drawn as two triangles
+    quads: SharedIndexBuffer,
+    // Four vertices of a line drawn as a two triangle quad
+    lines: SharedIndexBuffer,
+}
+
+impl SharedIndexBuffers {
+    // Nothing is created until the first bind
+    pub const fn new() -> SharedIndexBuffers {
+        SharedIndexBuffers {
+            sequential: SharedIndexBuffer::new(1, &[0]),
+            quads: SharedIndexBuffer::new(4, &[0, 1, 2, 2, 3, 0]),
+            lines: SharedIndexBuffer::new(4, &[0, 1, 2, 3, 2, 1]),
+        }
+    }
+
+    fn get(&mut self, mode: DrawMode) -> &mut SharedIndexBuffer {
+        match mode {
+            DrawMode::Quads => &mut self.quads,
+            DrawMode::Lines => &mut self.lines,
+            _ => &mut self.sequential,
+        }
+    }
+
+    // Binds the buffer for mode with room for index_count indices and
+    // returns its current index type
+    pub fn bind(&mut self, backend: &dyn GlBackend, mode: DrawMode, index_count: usize) -> IntType {
+        let buffer = self.get(mode);
+        buffer.bind(backend, index_count);
+        buffer.index_type()
+    }
+
+    // The index type can widen when the buffer grows so it is looked up
+    // again for every draw
+    pub fn index_type(&mut self, mode: DrawMode) -> IntType {
+        self.get(mode).index_type()
+    }
+
+    pub fn delete(&mut self, backend: &dyn GlBackend) {
+        self.sequential.delete(backend);
+        self.quads.delete(backend);
+        self.lines.delete(backend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+
+    // Sizes in bytes of the index data uploaded since the last call
+    fn uploads(backend: &RecordingGl) -> Vec<i64> {
+        backend
+            .take_calls()
+            .into_iter()
+            .filter(|call| call.function == "glBufferData")
+            .map(|call| call.args[1])
+            .collect()
+    }
+
+    #[test]
+    fn grows_only_when_a_draw_needs_more_indices() {
+        let backend = RecordingGl::new();
+        let mut shared = SharedIndexBuffers::new();
+        // Twice the 6 indices asked for
+        assert_eq!(shared.bind(&backend, DrawMode::Quads, 6), IntType::Byte);
+        assert_eq!(uploads(&backend), [12]);
+        assert_eq!(shared.bind(&backend, DrawMode::Quads, 12), IntType::Byte);
+        assert!(uploads(&backend).is_empty());
+        assert_eq!(shared.bind(&backend, DrawMode::Quads, 18), IntType::Byte);
+        assert_eq!(uploads(&backend), [36]);
+        // Other modes have buffers of their own
+        shared.bind(&backend, DrawMode::Triangles, 3);
+        assert_eq!(uploads(&backend), [6]);
+    }
+
+    #[test]
+    fn widens_the_index_type_as_it_grows() {
+        let backend = RecordingGl::new();
+        let mut shared = SharedIndexBuffers::new();
+        // 600 indices over 400 vertices
+        assert_eq!(shared.bind(&backend, DrawMode::Quads, 300), IntType::Short);
+        assert_eq!(uploads(&backend), [600 * 2]);
+        // 120000 indices over 80000 vertices
+        assert_eq!(shared.bind(&backend, DrawMode::Quads, 60000), IntType::Int);
+        assert_eq!(uploads(&backend), [120000 * 4]);
+        assert_eq!(shared.index_type(DrawMode::Quads), IntType::Int);
+    }
+
+    #[test]
+    fn starts_over_after_being_deleted() {
+        let backend = RecordingGl::new();
+        let mut shared = SharedIndexBuffers::new();
+        shared.bind(&backend, DrawMode::Quads, 60000);
+        shared.delete(&backend);
+        let calls = backend.take_calls();
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|call| call.function == "glDeleteBuffers")
+                .count(),
+            1
+        );
+        assert_eq!(shared.index_type(DrawMode::Quads), IntType::Byte);
+        shared.bind(&backend, DrawMode::Quads, 6);
+        assert_eq!(backend.count("glGenBuffers"), 1);
+        assert_eq!(uploads(&backend), [12]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::OnceLock;
+
+use crate::render::texture::NativeImage;
+
+const GAMMA: f32 = 2.2;
+
+// sRGB channel value to linear intensity
+fn linear_table() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0.0; 256];
+        for (value, linear) in table.iter_mut().enumerate() {
+            *linear = (value as f32 / 255.0).powf(GAMMA);
+        }
+        table
+    })
+}
+
+fn to_srgb(linear: f32) -> u8 {
+    (linear.powf(1.0 / GAMMA) * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+// Averages a 2x2 block. Colours are averaged in linear space and weighted
+// by alpha so fully transparent texels (e.g. between the leaves of a cutout
+// texture) don't darken their neighbours
+fn blend(pixels: [[u8; 4]; 4]) -> [u8; 4] {
+    let table = linear_table();
+    let total_alpha: u32 = pixels.iter().map(|pixel| pixel[3] as u32).sum();
+    let mut result = [0; 4];
+    for channel in 0..3 {
+        let value = if total_alpha == 0 {
+            pixels
+                .iter()
+                .map(|pixel| table[pixel[channel] as usize])
+                .sum::<f32>()
+                / 4.0
+        } else {
+            pixels
+                .iter()
+                .map(|pixel| table[pixel[channel] as usize] * pixel[3] as f32)
+                .sum::<f32>()
+                / total_alpha as f32
+        };
+        result[channel] = to_srgb(value);
+    }
+    result[3] = ((total_alpha + 2) / 4) as u8;
+    result
+}
+
+fn downsample(image: &NativeImage) -> NativeImage {
+    let width = (image.width() / 2).max(1);
+    let height = (image.height() / 2).max(1);
+    let mut result = NativeImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            // Clamp for the odd edge of images smaller than 2 in a dimension
+            let x0 = (x * 2).min(image.width() - 1);
+            let y0 = (y * 2).min(image.height() - 1);
+            let x1 = (x * 2 + 1).min(image.width() - 1);
+            let y1 = (y * 2 + 1).min(image.height() - 1);
+            result.set_pixel(
+                x,
+                y,
+                blend([
+                    image.pixel(x0, y0),
+                    image.pixel(x1, y0),
+                    image.pixel(x0, y1),
+                    image.pixel(x1, y1),
+                ]),
+            );
+        }
+    }
+    result
+}
+
+// Mip levels 1 to levels of image, each half the size of the last
+pub fn generate_mipmaps(image: &NativeImage, levels: u32) -> Vec<NativeImage> {
+    let mut mipmaps: Vec<NativeImage> = Vec::with_capacity(levels as usize);
+    for _ in 0..levels {
+        let next = downsample(mipmaps.last().unwrap_or(image));
+        mipmaps.push(next);
+    }
+    mipmaps
+}
+
+// The most levels that keep every sprite of the given sizes from blending
+// into its neighbours, sprites are only aligned to their own size
+pub fn max_mipmap_levels(sizes: impl Iterator<Item = (u32, u32)>) -> u32 {
+    sizes
+        .map(|(width, height)| (width | height).trailing_zeros())
+        .min()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_cutout_colours_next_to_transparent_texels() {
+        let leaf = [40, 160, 20, 255];
+        let blended = blend([leaf, [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert_eq!(blended, [40, 160, 20, 64]);
+        // Fully transparent blocks still get a colour for filtering
+        assert_eq!(blend([[90, 90, 90, 0]; 4]), [90, 90, 90, 0]);
+    }
+
+    #[test]
+    fn averages_in_linear_space() {
+        let (white, black) = ([255, 255, 255, 255], [0, 0, 0, 255]);
+        // Half the light of white, which is brighter than the sRGB midpoint
+        assert_eq!(blend([white, black, white, black]), [186, 186, 186, 255]);
+    }
+
+    #[test]
+    fn halves_each_level_down_to_one_texel() {
+        let mipmaps = generate_mipmaps(&NativeImage::new(8, 2), 4);
+        let sizes: Vec<_> = mipmaps
+            .iter()
+            .map(|mipmap| (mipmap.width(), mipmap.height()))
+            .collect();
+        assert_eq!(sizes, [(4, 1), (2, 1), (1, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn caps_levels_by_the_smallest_sprite() {
+        assert_eq!(max_mipmap_levels([(16, 16), (32, 64)].into_iter()), 4);
+        assert_eq!(
+            max_mipmap_levels([(16, 16), (8, 16), (32, 32)].into_iter()),
+            3
+        );
+        // Sprites are only aligned to their own size, so 48 allows 4 levels
+        assert_eq!(max_mipmap_levels([(16, 48)].into_iter()), 4);
+        assert_eq!(max_mipmap_levels([(16, 16), (6, 16)].into_iter()), 1);
+        assert_eq!(max_mipmap_levels(std::iter::empty()), 0);
+    }
+}
@@ -1,56 +1,43 @@
-use core::panicking::panic;
-use core::slice::SlicePattern;
-use std::collections::HashMap;
-use std::f32::NAN;
-use std::io::Cursor;
-use std::path::Prefix::Verbatim;
-use std::ptr;
-use std::ptr::eq;
-
-use gl33::global_loader::{
-    glDisableVertexAttribArray, glDrawElements, glEnableVertexAttribArray, glVertexAttribIPointer,
-    glVertexAttribPointer,
-};
 use gl33::*;
-use glutin::event::VirtualKeyCode::V;
-use glutin::window::CursorIcon::VerticalText;
 use ultraviolet::Vec3;
 use crate::math::round_up_to_multiple;
+use crate::render::backend::GlBackend;
 
 use crate::types::{GLint, GLsizei, GLuint};
+use thiserror::Error;
 
+pub mod animation;
+pub mod atlas;
+pub mod backend;
+pub mod chunk;
+pub mod font;
+pub mod index_buffer;
+pub mod mipmap;
+pub mod post;
+pub mod preprocessor;
+#[cfg(test)]
+pub mod recording_gl;
 pub mod shader;
+pub mod texture;
+pub mod uniform;
 pub mod util;
+pub mod vertex_buffer;
+pub mod vertex_format;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 enum DataType {
     Float,
     UByte,
     Byte,
-    UShort,
     Short,
-    UInt,
-    Int,
 }
 
 impl DataType {
-    fn length(&self) -> usize {
+    const fn length(&self) -> usize {
         match self {
             DataType::UByte | DataType::Byte => 1,
-            DataType::UShort | DataType::Short => 2,
-            DataType::UInt | DataType::Int | DataType::Float => 4,
-        }
-    }
-
-    fn name(&self) -> &'static str {
-        match self {
-            DataType::Float => "float",
-            DataType::UByte => "unsigned byte",
-            DataType::Byte => "byte",
-            DataType::UShort => "unsigned short",
-            DataType::Short => "short",
-            DataType::UInt => "unsigned int",
-            DataType::Int => "int",
+            DataType::Short => 2,
+            DataType::Float => 4,
         }
     }
 
@@ -59,10 +46,7 @@ impl DataType {
             DataType::Float => GL_FLOAT,
             DataType::UByte => GL_UNSIGNED_BYTE,
             DataType::Byte => GL_BYTE,
-            DataType::UShort => GL_UNSIGNED_SHORT,
             DataType::Short => GL_SHORT,
-            DataType::UInt => GL_UNSIGNED_INT,
-            DataType::Int => GL_INT,
         }
     }
 }
@@ -77,7 +61,7 @@ impl DataType {
 //     Padding,
 // }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 enum ElementType {
     Position,
     Normal,
@@ -86,8 +70,8 @@ enum ElementType {
     Padding,
 }
 
-#[derive(Debug, Clone, Copy)]
-struct VertexFormatElement {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VertexFormatElement {
     texture_index: u8,
     data_type: DataType,
     type_: ElementType,
@@ -95,20 +79,20 @@ struct VertexFormatElement {
 }
 
 impl VertexFormatElement {
-    const POSITION: &'static VertexFormatElement =
+    pub const POSITION: &'static VertexFormatElement =
         &VertexFormatElement::new(0, DataType::Float, ElementType::Position, 3);
-    const COLOR: &'static VertexFormatElement =
+    pub const COLOR: &'static VertexFormatElement =
         &VertexFormatElement::new(0, DataType::UByte, ElementType::Color, 4);
-    const TEXTURE: &'static VertexFormatElement =
+    pub const TEXTURE: &'static VertexFormatElement =
         &VertexFormatElement::new(0, DataType::Float, ElementType::UV, 2);
-    const OVERLAY: &'static VertexFormatElement =
+    pub const OVERLAY: &'static VertexFormatElement =
         &VertexFormatElement::new(1, DataType::Short, ElementType::UV, 2);
-    const LIGHT: &'static VertexFormatElement =
+    pub const LIGHT: &'static VertexFormatElement =
         &VertexFormatElement::new(2, DataType::Short, ElementType::UV, 2);
-    const NORMAL: &'static VertexFormatElement =
+    pub const NORMAL: &'static VertexFormatElement =
         &VertexFormatElement::new(0, DataType::Byte, ElementType::Normal, 3);
-    const PADDING: &'static VertexFormatElement =
-        &VertexFormatElement::new(0, DataType::Byte, ElementType::Normal, 1);
+    pub const PADDING: &'static VertexFormatElement =
+        &VertexFormatElement::new(0, DataType::Byte, ElementType::Padding, 1);
 }
 
 impl VertexFormatElement {
@@ -126,213 +110,304 @@ impl VertexFormatElement {
         }
     }
 
-    unsafe fn start_drawing(&self, element_index: GLuint, stride: GLsizei, pointer: usize) {
+    fn start_drawing(
+        &self,
+        backend: &dyn GlBackend,
+        element_index: GLuint,
+        stride: GLsizei,
+        pointer: usize,
+    ) {
         let data_type = &self.data_type;
         match self.type_ {
             ElementType::Position => {
-                glEnableVertexAttribArray(element_index);
-                glVertexAttribPointer(
+                backend.enable_vertex_attrib_array(element_index);
+                backend.vertex_attrib_pointer(
                     element_index,
                     self.size,
                     data_type.gl(),
-                    0,
+                    false,
                     stride,
-                    pointer as *const _,
+                    pointer,
                 );
             }
             ElementType::Normal | ElementType::Color => {
-                glEnableVertexAttribArray(element_index);
-                glVertexAttribPointer(
+                backend.enable_vertex_attrib_array(element_index);
+                backend.vertex_attrib_pointer(
                     element_index,
                     self.size,
                     data_type.gl(),
-                    1,
+                    true,
                     stride,
-                    pointer as *const _,
+                    pointer,
                 );
             }
             ElementType::UV => {
-                glEnableVertexAttribArray(element_index);
+                backend.enable_vertex_attrib_array(element_index);
                 let gl_type = data_type.gl();
                 if gl_type == GL_FLOAT {
-                    glVertexAttribPointer(
+                    backend.vertex_attrib_pointer(
                         element_index,
                         self.size,
                         gl_type,
-                        0,
+                        false,
                         stride,
-                        pointer as *const _,
+                        pointer,
                     );
                 } else {
-                    glVertexAttribIPointer(
-                        element_index,
-                        self.size,
-                        gl_type,
-                        stride,
-                        pointer as *const _,
-                    );
+                    backend.vertex_attrib_i_pointer(element_index, self.size, gl_type, stride, pointer);
                 }
             }
             ElementType::Padding => {}
         }
     }
 
-    unsafe fn end_drawing(&self, element_index: GLuint) {
+    fn end_drawing(&self, backend: &dyn GlBackend, element_index: GLuint) {
         match self.type_ {
             ElementType::Padding => {}
-            _ => glDisableVertexAttribArray(element_index),
+            _ => backend.disable_vertex_attrib_array(element_index),
         }
     }
 
-    fn byte_length(&self) -> usize {
+    const fn byte_length(&self) -> usize {
         self.data_type.length() * self.size as usize
     }
+
+    // Name of the BufferBuilder method writing this element
+    fn name(&self) -> &'static str {
+        match (self.type_, self.texture_index) {
+            (ElementType::Position, _) => "vertex",
+            (ElementType::Normal, _) => "normal",
+            (ElementType::Color, _) => "color",
+            (ElementType::UV, 0) => "uv",
+            (ElementType::UV, 1) => "overlay",
+            (ElementType::UV, _) => "light",
+            (ElementType::Padding, _) => "padding",
+        }
+    }
 }
 
-#[derive(Debug)]
+// Compared and hashed by value so formats built at runtime match the
+// constants with the same layout
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct VertexFormat {
     pub attr_names: &'static [&'static str],
     pub elements: &'static [&'static VertexFormatElement],
     pub size: usize,
-    pub offsets: Vec<usize>,
 }
 
-pub struct C(pub u8);
-
 impl VertexFormat {
-    pub const A: C = C(0);
-
     const fn new(
         names: &'static [&'static str],
         values: &'static [&'static VertexFormatElement],
     ) -> VertexFormat {
-        let mut offsets = Vec::with_capacity(values.len());
         let mut size = 0;
-        for x in values {
-            offsets.push(size);
-            size += x.byte_length();
+        let mut i = 0;
+        while i < values.len() {
+            size += values[i].byte_length();
+            i += 1;
         }
         VertexFormat {
             attr_names: names,
             elements: values,
-            offsets,
             size,
         }
     }
 
-    fn get_vertex_size_integer(&self) -> usize {
-        self.size / 4
+    // Enables and points each attribute at its element, the vertex array
+    // and array buffer to set up must be bound
+    fn setup_state(&self, backend: &dyn GlBackend) {
+        let mut offset = 0;
+        for (index, element) in self.elements.iter().enumerate() {
+            element.start_drawing(backend, index as GLuint, self.size as GLsizei, offset);
+            offset += element.byte_length();
+        }
+    }
+
+    // Byte offset of the position within a vertex, runtime formats may put
+    // it after other elements or leave it out
+    fn position_offset(&self) -> Option<usize> {
+        let mut offset = 0;
+        for element in self.elements {
+            if element.type_ == ElementType::Position {
+                return Some(offset);
+            }
+            offset += element.byte_length();
+        }
+        None
+    }
+
+    fn clear_state(&self, backend: &dyn GlBackend) {
+        for (index, element) in self.elements.iter().enumerate() {
+            element.end_drawing(backend, index as GLuint);
+        }
     }
 }
 
 impl VertexFormat {
-    pub const BLIT_SCREEN: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "UV", "Color"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::COLOR,
-        ],
-    );
-    const POSITION_COLOR_TEXTURE_LIGHT_NORMAL: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "Color", "UV0", "UV2", "Normal", "Padding"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::LIGHT,
-            VertexFormatElement::NORMAL,
-            VertexFormatElement::PADDING,
-        ],
-    );
-    const POSITION_COLOR_TEXTURE_OVERLAY_LIGHT_NORMAL: &'static VertexFormat = &VertexFormat::new(
-        &[
-            "Position", "Color", "UV0", "UV1", "UV2", "Normal", "Padding",
-        ],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::OVERLAY,
-            VertexFormatElement::LIGHT,
-            VertexFormatElement::NORMAL,
-            VertexFormatElement::PADDING,
-        ],
-    );
-    const POSITION_TEXTURE_COLOR_LIGHT: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "UV0", "Color", "UV2"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::LIGHT,
-        ],
-    );
-    pub const POSITION: &'static VertexFormat =
-        &VertexFormat::new(&["Position"], &[VertexFormatElement::POSITION]);
-    const POSITION_TEXTURE: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "UV0"],
-        &[VertexFormatElement::POSITION, VertexFormatElement::TEXTURE],
-    );
-    const POSITION_TEXTURE_COLOR: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "UV0", "Color"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::COLOR,
-        ],
-    );
-    const POSITION_COLOR_TEXTURE: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "Color", "UV0"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::TEXTURE,
-        ],
-    );
-    const POSITION_COLOR: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "Color"],
-        &[VertexFormatElement::POSITION, VertexFormatElement::COLOR],
-    );
-    const POSITION_COLOR_LIGHT: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "Color", "UV2"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::LIGHT,
-        ],
-    );
-    const POSITION_COLOR_TEXTURE_LIGHT: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "Color", "UV0", "UV2"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::LIGHT,
-        ],
-    );
-    const POSITION_TEXTURE_LIGHT_COLOR: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "UV0", "UV2", "Color"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::LIGHT,
-            VertexFormatElement::COLOR,
-        ],
-    );
-    const POSITION_TEXTURE_COLOR_NORMAL: &'static VertexFormat = &VertexFormat::new(
-        &["Position", "UV0", "Color", "Normal", "Padding"],
-        &[
-            VertexFormatElement::POSITION,
-            VertexFormatElement::TEXTURE,
-            VertexFormatElement::COLOR,
-            VertexFormatElement::NORMAL,
-            VertexFormatElement::PADDING,
-        ],
-    );
+    // Each format lives in a static so every use of a constant shares one
+    // address, which the runtime format registry hands out again
+    pub const BLIT_SCREEN: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "UV", "Color"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::COLOR,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_COLOR_TEXTURE_LIGHT_NORMAL: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "Color", "UV0", "UV2", "Normal", "Padding"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::LIGHT,
+                VertexFormatElement::NORMAL,
+                VertexFormatElement::PADDING,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_COLOR_TEXTURE_OVERLAY_LIGHT_NORMAL: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &[
+                "Position", "Color", "UV0", "UV1", "UV2", "Normal", "Padding",
+            ],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::OVERLAY,
+                VertexFormatElement::LIGHT,
+                VertexFormatElement::NORMAL,
+                VertexFormatElement::PADDING,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_TEXTURE_COLOR_LIGHT: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "UV0", "Color", "UV2"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::LIGHT,
+            ],
+        );
+        &FORMAT
+    };
+    pub const POSITION: &'static VertexFormat = {
+        static FORMAT: VertexFormat =
+            VertexFormat::new(&["Position"], &[VertexFormatElement::POSITION]);
+        &FORMAT
+    };
+    const POSITION_TEXTURE: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "UV0"],
+            &[VertexFormatElement::POSITION, VertexFormatElement::TEXTURE],
+        );
+        &FORMAT
+    };
+    pub const POSITION_TEXTURE_COLOR: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "UV0", "Color"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::COLOR,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_COLOR_TEXTURE: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "Color", "UV0"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::TEXTURE,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_COLOR: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "Color"],
+            &[VertexFormatElement::POSITION, VertexFormatElement::COLOR],
+        );
+        &FORMAT
+    };
+    const POSITION_COLOR_LIGHT: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "Color", "UV2"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::LIGHT,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_COLOR_TEXTURE_LIGHT: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "Color", "UV0", "UV2"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::LIGHT,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_TEXTURE_LIGHT_COLOR: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "UV0", "UV2", "Color"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::LIGHT,
+                VertexFormatElement::COLOR,
+            ],
+        );
+        &FORMAT
+    };
+    const POSITION_TEXTURE_COLOR_NORMAL: &'static VertexFormat = {
+        static FORMAT: VertexFormat = VertexFormat::new(
+            &["Position", "UV0", "Color", "Normal", "Padding"],
+            &[
+                VertexFormatElement::POSITION,
+                VertexFormatElement::TEXTURE,
+                VertexFormatElement::COLOR,
+                VertexFormatElement::NORMAL,
+                VertexFormatElement::PADDING,
+            ],
+        );
+        &FORMAT
+    };
+    // Registered up front so runtime formats with the same layout reuse them
+    pub const BUILTIN: &'static [&'static VertexFormat] = &[
+        VertexFormat::BLIT_SCREEN,
+        VertexFormat::POSITION_COLOR_TEXTURE_LIGHT_NORMAL,
+        VertexFormat::POSITION_COLOR_TEXTURE_OVERLAY_LIGHT_NORMAL,
+        VertexFormat::POSITION_TEXTURE_COLOR_LIGHT,
+        VertexFormat::POSITION,
+        VertexFormat::POSITION_TEXTURE,
+        VertexFormat::POSITION_TEXTURE_COLOR,
+        VertexFormat::POSITION_COLOR_TEXTURE,
+        VertexFormat::POSITION_COLOR,
+        VertexFormat::POSITION_COLOR_LIGHT,
+        VertexFormat::POSITION_COLOR_TEXTURE_LIGHT,
+        VertexFormat::POSITION_TEXTURE_LIGHT_COLOR,
+        VertexFormat::POSITION_TEXTURE_COLOR_NORMAL,
+    ];
 }
 
-struct BufferBuilder {
+pub struct BufferBuilder {
     buffer: Vec<u8>,
     build_start: usize,
     last_parameter_index: usize,
@@ -342,13 +417,15 @@ struct BufferBuilder {
     textures: bool,
     has_overlay: bool,
     building: bool,
-    camera_offset: bool,
+    index_only: bool,
     element_offset: usize,
     vertex_count: usize,
     format: Option<&'static VertexFormat>,
     draw_mode: Option<DrawMode>,
     current_parameters: Option<Vec<Vec3>>,
     camera: Vec3,
+    // Sort state of the last batch ended, for pop_state once it has ended
+    last_state: Option<State>,
     parameters: Vec<DrawArrayParameters>,
 }
 
@@ -364,13 +441,14 @@ impl BufferBuilder {
             textures: false,
             has_overlay: false,
             building: false,
-            camera_offset: false,
+            index_only: false,
             element_offset: 0,
             vertex_count: 0,
             format: None,
             draw_mode: None,
             current_parameters: None,
             camera: Vec3::new(f32::NAN, f32::NAN, f32::NAN),
+            last_state: None,
             parameters: Vec::new(),
         }
     }
@@ -379,149 +457,390 @@ impl BufferBuilder {
         self.buffer.push(value);
     }
 
-    fn put_short(&mut self, value: u16) {
-        let v: [u8; 2] = u16::to_be_bytes(value);
-        self.buffer.push(v[0]);
-        self.buffer.push(v[1]);
+    // Values are written in native byte order as GL reads them unconverted
+    fn put_short(&mut self, value: i16) {
+        self.buffer.extend_from_slice(&value.to_ne_bytes());
     }
 
-    fn put_float(&mut self,  value: f32) {
-        let v: [u8; 4] = f32::to_be_bytes(value);
-        self.buffer.push(v[0]);
-        self.buffer.push(v[1]);
-        self.buffer.push(v[2]);
-        self.buffer.push(v[3]);
+    fn put_float(&mut self, value: f32) {
+        self.buffer.extend_from_slice(&value.to_ne_bytes());
     }
 
     fn get_float(&self, index: usize) -> f32 {
-        let bytes = self.buffer[index..(index + 4)] as [u8; 4];
-        f32::from_be_bytes(bytes)
+        let bytes: [u8; 4] = self.buffer[index..index + 4].try_into().unwrap();
+        f32::from_ne_bytes(bytes)
     }
 
+    // Sets where translucent quads are sorted from, only quads are sorted.
+    // May be called any time before end, the quads are sorted by end
     pub fn set_camera_position(&mut self, camera: Vec3) {
-        if self.draw_mode.is_some() {
-            if let DrawMode::Quads = self.draw_mode.unwrap() {
-                return;
-            }
-            self.camera = camera;
-            if self.current_parameters.is_none() {
-                self.current_parameters = Some(self.get_parameter_vec());
-            }
+        if !matches!(self.draw_mode, Some(DrawMode::Quads)) {
+            return;
         }
+        self.camera = camera;
+    }
+
+    // Formats without a position have nothing to sort by and stay sequential
+    fn is_sorting(&self) -> bool {
+        matches!(self.draw_mode, Some(DrawMode::Quads))
+            && !self.camera.x.is_nan()
+            && self.format.is_some_and(|format| format.position_offset().is_some())
     }
 
+    // Centre of each quad in the current batch, halfway between its first
+    // and third vertex. Empty if the format has no position
     pub fn get_parameter_vec(&self) -> Vec<Vec3> {
-        let i = self.build_start / 4;
-        let j = self.format.unwrap().get_vertex_size_integer();
-        let dms = self.draw_mode.unwrap().size();
-        let k = j * dms;
-        let l = self.vertex_count / dms;
-        let mut params = Vec::with_capacity(l);
-        for m in 0..l {
-            let f = self.get_float(i + m * k);
-            let g = self.get_float(i + m * k + 1);
-            let h = self.get_float(i + m * k + 2);
-            let n = self.get_float(i + m * k + j * 2);
-            let o = self.get_float(i + m * k + j * 2 + 1);
-            let p = self.get_float(i + m * k + j * 2 + 2);
-            let q = (f + n) / 2.0;
-            let r = (g + o) / 2.0;
-            let s = (h + p) / 2.0;
-            params.push(Vec3::new(q, r, s))
-        }
-        params
-    }
-
-    pub fn pop_state(&self) -> State {
-        State {
-            draw_mode: self.draw_mode,
-            vertex_count: self.vertex_count,
-            params: self.current_parameters.clone(),
-            camera: self.camera.clone(),
+        let format = self.format.unwrap();
+        let offset = match format.position_offset() {
+            Some(value) => value,
+            None => return Vec::new(),
+        };
+        let quad_size = format.size * DrawMode::Quads.size();
+        (0..self.vertex_count / DrawMode::Quads.size())
+            .map(|quad| {
+                let start = self.build_start + quad * quad_size + offset;
+                (self.get_position(start) + self.get_position(start + format.size * 2)) / 2.0
+            })
+            .collect()
+    }
+
+    fn get_position(&self, index: usize) -> Vec3 {
+        Vec3::new(
+            self.get_float(index),
+            self.get_float(index + 4),
+            self.get_float(index + 8),
+        )
+    }
+
+    // Writes the indices of every quad ordered furthest from the camera
+    // first so nearer translucent faces blend over those behind them
+    fn put_sorted_quad_indices(&mut self, index_type: IntType) {
+        let order = {
+            let centres = self.current_parameters.as_ref().unwrap();
+            let distances: Vec<f32> = centres
+                .iter()
+                .map(|centre| (*centre - self.camera).mag_sq())
+                .collect();
+            let mut order: Vec<usize> = (0..centres.len()).collect();
+            order.sort_by(|a, b| distances[*b].total_cmp(&distances[*a]));
+            order
+        };
+        for quad in order {
+            for offset in [0, 1, 2, 2, 3, 0] {
+                self.put_index(index_type, quad * 4 + offset);
+            }
         }
     }
 
-    fn clear_buffer(&mut self) {
-        self.buffer.fill(0);
+    fn put_index(&mut self, index_type: IntType, value: usize) {
+        match index_type {
+            IntType::Byte => self.put_byte(value as u8),
+            IntType::Short => self.buffer.extend_from_slice(&(value as u16).to_ne_bytes()),
+            IntType::Int => self.buffer.extend_from_slice(&(value as u32).to_ne_bytes()),
+        }
     }
 
+    // Saves what is needed to sort a batch again later, the batch being
+    // built or once it has ended the last one ended. None if it isn't sorted
+    pub fn pop_state(&self) -> Option<State> {
+        if !self.building {
+            return self.last_state.clone();
+        }
+        if !self.is_sorting() {
+            return None;
+        }
+        Some(State {
+            draw_mode: self.draw_mode.unwrap(),
+            vertex_count: self.vertex_count,
+            params: self
+                .current_parameters
+                .clone()
+                .unwrap_or_else(|| self.get_parameter_vec()),
+            camera: self.camera,
+        })
+    }
+
+    // Restores a saved batch so only its indices are rebuilt, the vertices
+    // already uploaded are left untouched. Call after begin
     pub fn restore_state(&mut self, state: State) {
-        self.clear_buffer();
-        self.draw_mode = state.draw_mode;
+        self.buffer.truncate(self.build_start);
+        self.draw_mode = Some(state.draw_mode);
         self.vertex_count = state.vertex_count;
         self.element_offset = self.build_start;
-        self.current_parameters = state.params;
+        self.current_parameters = Some(state.params);
         self.camera = state.camera;
-        self.camera_offset = true;
+        self.index_only = true;
     }
 
     pub fn begin(&mut self, mode: DrawMode, format: &'static VertexFormat) {
         if self.building {
             panic!("already building buffer cannot build twice");
         }
+        // Once every batch has been popped the buffer can be reused
+        if self.last_parameter_index == self.parameters.len() {
+            self.reset();
+        }
         self.building = true;
+        self.build_start = self.buffer.len();
         self.draw_mode = Some(mode);
         self.set_format(format);
         self.current_element = Some(format.elements[0]);
         self.current_element_id = 0;
-        self.clear_buffer();
+    }
+
+    // Finishes the current batch and queues it to be popped by pop_data,
+    // further batches can be begun before any are popped
+    pub fn end(&mut self) -> Result<(), VertexError> {
+        if !self.building {
+            return Err(VertexError::NotBuilding);
+        }
+        // The batch stays open so the vertex can be finished or the batch
+        // ended again without it
+        if self.current_element_id != 0 {
+            self.discard_vertex();
+            return Err(VertexError::UnfinishedVertex);
+        }
+        let draw_mode = self.draw_mode.unwrap();
+        let index_type = IntType::get_smallest_for(self.vertex_count);
+        // Centres are only taken now that every vertex has been written,
+        // a restored batch brings its own
+        let sequential = !self.is_sorting();
+        self.last_state = None;
+        if !sequential {
+            if self.current_parameters.is_none() {
+                self.current_parameters = Some(self.get_parameter_vec());
+            }
+            self.put_sorted_quad_indices(index_type);
+            self.last_state = Some(State {
+                draw_mode,
+                vertex_count: self.vertex_count,
+                params: self.current_parameters.clone().unwrap(),
+                camera: self.camera,
+            });
+        }
+        // Keep the start of every batch aligned to 4 bytes
+        let length = round_up_to_multiple(self.buffer.len() - self.build_start, 4);
+        self.buffer.resize(self.build_start + length, 0);
+        self.parameters.push(DrawArrayParameters {
+            vertex_format: self.format.unwrap(),
+            count: self.vertex_count,
+            vertex_count: draw_mode.get_size(self.vertex_count),
+            draw_mode,
+            element_format: index_type,
+            index_only: self.index_only,
+            sequential,
+        });
+        self.building = false;
+        self.vertex_count = 0;
+        self.current_element = None;
+        self.current_element_id = 0;
+        self.current_parameters = None;
+        self.camera = Vec3::new(f32::NAN, f32::NAN, f32::NAN);
+        self.index_only = false;
+        Ok(())
     }
 
     fn set_format(&mut self, format: &'static VertexFormat) {
-        if self.format.is_none() || eq(self.format.unwrap(), format) {
+        if self.format == Some(format) {
             return;
         }
         self.format = Some(format);
-        let bl = eq(
-            format,
-            VertexFormat::POSITION_COLOR_TEXTURE_OVERLAY_LIGHT_NORMAL,
-        );
-        let bl2 = eq(format, VertexFormat::POSITION_COLOR_TEXTURE_LIGHT_NORMAL);
+        let bl = format == VertexFormat::POSITION_COLOR_TEXTURE_OVERLAY_LIGHT_NORMAL;
+        let bl2 = format == VertexFormat::POSITION_COLOR_TEXTURE_LIGHT_NORMAL;
         self.textures = bl || bl2;
         self.has_overlay = bl;
     }
 
+    // Drops the batch being built and every queued batch, the allocation is
+    // kept for reuse. Lets a builder left mid-batch by an error begin again
     pub fn reset(&mut self) {
+        self.building = false;
+        self.vertex_count = 0;
+        self.current_element = None;
+        self.current_element_id = 0;
+        self.camera = Vec3::new(f32::NAN, f32::NAN, f32::NAN);
+        self.index_only = false;
+        self.last_state = None;
+        self.buffer.clear();
         self.build_start = 0;
         self.next_draw_start = 0;
         self.element_offset = 0;
         self.current_parameters = None;
         self.last_parameter_index = 0;
+        self.parameters.clear();
     }
 
-    pub fn pop_data(&mut self) -> (DrawArrayParameters, Vec<u8>)  {
-        let param = self.parameters[self.last_parameter_index];
+    // Pops the oldest finished batch in the order they were ended
+    pub fn pop_data(&mut self) -> Option<(DrawArrayParameters, &[u8])> {
+        let param = *self.parameters.get(self.last_parameter_index)?;
+        self.last_parameter_index += 1;
         let start = self.next_draw_start;
-        self.next_draw_start += round_up_to_multiple(param.get_draw_start() as i32, 4) as usize;
-        let end = self.next_draw_start;
-        if self.last_parameter_index == self.parameters.len() && self.vertex_count == 0 {
-            self.clear_buffer()
+        self.next_draw_start += round_up_to_multiple(param.get_draw_start(), 4);
+        Some((param, &self.buffer[start..self.next_draw_start]))
+    }
+
+    // Checks element is the next one expected by the format, on failure the
+    // vertex written so far is discarded
+    fn check_element(&mut self, element: &VertexFormatElement) -> Result<(), VertexError> {
+        if !self.building {
+            return Err(VertexError::NotBuilding);
         }
-        let values = self.buffer[start..end].to_vec();
-        (param, values)
+        let expected = match self.current_element {
+            Some(current) if current == element => return Ok(()),
+            Some(current) => current.name(),
+            None => "end_vertex",
+        };
+        self.discard_vertex();
+        Err(VertexError::UnexpectedElement {
+            expected,
+            found: element.name(),
+        })
     }
 
-    pub fn vertex(x: f32, y: f32, z: f32, r: f32, g: f32, b: f32, a: f32)
+    // Cuts the buffer back to the last complete vertex so a failed vertex
+    // leaves nothing behind
+    fn discard_vertex(&mut self) {
+        let format = self.format.unwrap();
+        let length = self.build_start + self.vertex_count * format.size;
+        self.buffer.truncate(length);
+        self.current_element_id = 0;
+        self.current_element = format.elements.first().copied();
+    }
+
+    // Moves to the next element, writing zeroes for any padding
+    fn next_element(&mut self) {
+        let elements = self.format.unwrap().elements;
+        self.current_element_id += 1;
+        while let Some(element) = elements.get(self.current_element_id) {
+            if element.type_ != ElementType::Padding {
+                break;
+            }
+            let length = self.buffer.len() + element.byte_length();
+            self.buffer.resize(length, 0);
+            self.current_element_id += 1;
+        }
+        self.current_element = elements.get(self.current_element_id).copied();
+    }
+
+    pub fn vertex(&mut self, x: f32, y: f32, z: f32) -> Result<&mut Self, VertexError> {
+        self.check_element(VertexFormatElement::POSITION)?;
+        self.put_float(x);
+        self.put_float(y);
+        self.put_float(z);
+        self.next_element();
+        Ok(self)
+    }
+
+    pub fn color(&mut self, r: u8, g: u8, b: u8, a: u8) -> Result<&mut Self, VertexError> {
+        self.check_element(VertexFormatElement::COLOR)?;
+        self.put_byte(r);
+        self.put_byte(g);
+        self.put_byte(b);
+        self.put_byte(a);
+        self.next_element();
+        Ok(self)
+    }
+
+    pub fn uv(&mut self, u: f32, v: f32) -> Result<&mut Self, VertexError> {
+        self.check_element(VertexFormatElement::TEXTURE)?;
+        self.put_float(u);
+        self.put_float(v);
+        self.next_element();
+        Ok(self)
+    }
+
+    pub fn end_vertex(&mut self) -> Result<(), VertexError> {
+        if !self.building {
+            return Err(VertexError::NotBuilding);
+        }
+        let format = self.format.unwrap();
+        if self.current_element.is_some() {
+            let missing = format.elements[self.current_element_id..]
+                .iter()
+                .filter(|element| element.type_ != ElementType::Padding)
+                .map(|element| element.name())
+                .collect();
+            self.discard_vertex();
+            return Err(VertexError::MissingElements(missing));
+        }
+        self.vertex_count += 1;
+        self.current_element_id = 0;
+        self.current_element = format.elements.first().copied();
+        Ok(())
+    }
+}
+
+// Writers for the elements of entity and lit block formats, which nothing
+// in the game draws yet
+#[allow(dead_code)]
+impl BufferBuilder {
+    pub fn overlay(&mut self, u: i16, v: i16) -> Result<&mut Self, VertexError> {
+        self.check_element(VertexFormatElement::OVERLAY)?;
+        self.put_short(u);
+        self.put_short(v);
+        self.next_element();
+        Ok(self)
+    }
+
+    pub fn light(&mut self, u: i16, v: i16) -> Result<&mut Self, VertexError> {
+        self.check_element(VertexFormatElement::LIGHT)?;
+        self.put_short(u);
+        self.put_short(v);
+        self.next_element();
+        Ok(self)
+    }
+
+    // Components are clamped to -1..1 and stored as normalized signed bytes
+    pub fn normal(&mut self, x: f32, y: f32, z: f32) -> Result<&mut Self, VertexError> {
+        self.check_element(VertexFormatElement::NORMAL)?;
+        for value in [x, y, z] {
+            self.put_byte((value.clamp(-1.0, 1.0) * 127.0) as i8 as u8);
+        }
+        self.next_element();
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VertexError {
+    #[error("not building, begin must be called before writing vertices")]
+    NotBuilding,
+    #[error("expected {expected} but {found} was written")]
+    UnexpectedElement {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("vertex ended without writing {}", .0.join(", "))]
+    MissingElements(Vec<&'static str>),
+    #[error("batch ended in the middle of a vertex")]
+    UnfinishedVertex,
 }
 
 #[derive(Clone, Copy)]
-struct DrawArrayParameters {
+pub struct DrawArrayParameters {
     vertex_format: &'static VertexFormat,
     count: usize,
     vertex_count: usize,
     draw_mode: DrawMode,
     element_format: IntType,
-    camera_offset: bool,
-    textured: bool,
+    // Only indices, sorted again for vertices uploaded earlier
+    index_only: bool,
+    // Drawn with the shared index buffer, so no indices follow the vertices
+    sequential: bool,
 }
 
 impl DrawArrayParameters {
+    // Index only batches are a resort of vertices uploaded earlier
     pub fn get_limit(&self) -> usize {
-        self.count * self.vertex_format.size
+        if self.index_only {
+            0
+        } else {
+            self.count * self.vertex_format.size
+        }
     }
 
     pub fn get_draw_length(&self) -> usize {
-        if self.textured {
+        if self.sequential {
             0
         } else {
             self.vertex_count * self.element_format.size()
@@ -533,15 +852,16 @@ impl DrawArrayParameters {
     }
 }
 
-struct State {
-    draw_mode: Option<DrawMode>,
+#[derive(Clone)]
+pub struct State {
+    draw_mode: DrawMode,
     vertex_count: usize,
-    params: Option<Vec<Vec3>>,
+    params: Vec<Vec3>,
     camera: Vec3,
 }
 
 #[derive(Copy, Clone)]
-enum DrawMode {
+pub enum DrawMode {
     Lines,
     LineStrip,
     DebugLines,
@@ -567,34 +887,35 @@ impl DrawMode {
 
     pub fn get_size(&self, vertex_count: usize) -> usize {
         match self {
-            DrawMode::Lines | DrawMode::Quads => vertex_count,
-            _ => vertex_count / 4 * 6,
+            DrawMode::Lines | DrawMode::Quads => vertex_count / 4 * 6,
+            _ => vertex_count,
         }
     }
 }
 
-impl Into<PrimitiveType> for DrawMode {
-    fn into(&self) -> PrimitiveType {
-        match self {
-            DrawMode::Lines | DrawMode::Triangles => GL_TRIANGLES,
-            DrawMode::LineStrip | DrawMode::TriangleStrip | DrawMode::Quads => GL_TRIANGLE_STRIP,
-            DrawMode::DebugLines => GL_LINE_STRIP,
-            DrawMode::DebugLineStrip => GL_LINES,
+// Lines and quads have no core profile primitive so are drawn as triangles
+impl From<DrawMode> for PrimitiveType {
+    fn from(mode: DrawMode) -> PrimitiveType {
+        match mode {
+            DrawMode::Lines | DrawMode::Triangles | DrawMode::Quads => GL_TRIANGLES,
+            DrawMode::LineStrip | DrawMode::TriangleStrip => GL_TRIANGLE_STRIP,
+            DrawMode::DebugLines => GL_LINES,
+            DrawMode::DebugLineStrip => GL_LINE_STRIP,
             DrawMode::TriangleFan => GL_TRIANGLE_FAN,
         }
     }
 }
 
-#[derive(Clone, Copy)]
-enum IntType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntType {
     Byte,
     Short,
     Int,
 }
 
-impl Into<VertexAttribIType> for IntType {
-    fn into(&self) -> VertexAttribIType {
-        match self {
+impl From<IntType> for DrawElementsType {
+    fn from(value: IntType) -> DrawElementsType {
+        match value {
             IntType::Byte => GL_UNSIGNED_BYTE,
             IntType::Short => GL_UNSIGNED_SHORT,
             IntType::Int => GL_UNSIGNED_INT,
@@ -612,7 +933,7 @@ impl IntType {
     }
 
     pub fn get_smallest_for(value: usize) -> IntType {
-        if (value & -0x10000) != 0 {
+        if (value & !0xFFFF) != 0 {
             IntType::Int
         } else if (value & 0xFF00) != 0 {
             IntType::Short
@@ -621,3 +942,289 @@ impl IntType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::vertex_format::VertexFormatBuilder;
+
+    // Two quads facing the camera, the first at z -1 and the second at z -5
+    fn build_quads(builder: &mut BufferBuilder) {
+        for z in [-1.0, -5.0] {
+            for (x, y) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                builder.vertex(x, y, z).unwrap().end_vertex().unwrap();
+            }
+        }
+    }
+
+    fn indices(builder: &mut BufferBuilder) -> Vec<u8> {
+        let (parameters, data) = builder.pop_data().unwrap();
+        data[parameters.get_limit()..parameters.get_draw_start()].to_vec()
+    }
+
+    #[test]
+    fn sorts_quads_back_to_front() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        // The camera may be set before the vertices are written
+        builder.set_camera_position(Vec3::zero());
+        build_quads(&mut builder);
+        builder.end().unwrap();
+        assert_eq!(indices(&mut builder), [4, 5, 6, 6, 7, 4, 0, 1, 2, 2, 3, 0]);
+
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        build_quads(&mut builder);
+        builder.set_camera_position(Vec3::zero());
+        builder.end().unwrap();
+        assert_eq!(indices(&mut builder), [4, 5, 6, 6, 7, 4, 0, 1, 2, 2, 3, 0]);
+    }
+
+    #[test]
+    fn leaves_unsorted_batches_sequential() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        build_quads(&mut builder);
+        builder.end().unwrap();
+        assert!(builder.pop_state().is_none());
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert!(parameters.sequential);
+        assert_eq!(data.len(), 8 * VertexFormat::POSITION.size);
+    }
+
+    #[test]
+    fn resorts_a_saved_state() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        builder.set_camera_position(Vec3::zero());
+        build_quads(&mut builder);
+        let building = builder.pop_state().unwrap();
+        builder.end().unwrap();
+        let ended = builder.pop_state().unwrap();
+        assert_eq!(building.params, ended.params);
+        assert_eq!(indices(&mut builder), [4, 5, 6, 6, 7, 4, 0, 1, 2, 2, 3, 0]);
+
+        // Moving behind both quads flips the order without new vertices
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        builder.restore_state(ended);
+        builder.set_camera_position(Vec3::new(0.5, 0.5, -10.0));
+        builder.end().unwrap();
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert_eq!(parameters.get_limit(), 0);
+        assert_eq!(data[..12], [0, 1, 2, 2, 3, 0, 4, 5, 6, 6, 7, 4]);
+    }
+
+    #[test]
+    fn writes_every_element_in_native_byte_order() {
+        let mut builder = BufferBuilder::new(64);
+        let format = VertexFormat::POSITION_COLOR_TEXTURE_OVERLAY_LIGHT_NORMAL;
+        builder.begin(DrawMode::Triangles, format);
+        builder
+            .vertex(1.0, 2.0, 3.0)
+            .unwrap()
+            .color(10, 20, 30, 40)
+            .unwrap()
+            .uv(0.5, 0.25)
+            .unwrap()
+            .overlay(3, 10)
+            .unwrap()
+            .light(0x0102, 240)
+            .unwrap()
+            .normal(2.0, -1.0, 0.0)
+            .unwrap()
+            .end_vertex()
+            .unwrap();
+        builder.end().unwrap();
+        let (parameters, data) = builder.pop_data().unwrap();
+        let mut expected = Vec::new();
+        for value in [1.0f32, 2.0, 3.0] {
+            expected.extend_from_slice(&value.to_ne_bytes());
+        }
+        expected.extend_from_slice(&[10, 20, 30, 40]);
+        for value in [0.5f32, 0.25] {
+            expected.extend_from_slice(&value.to_ne_bytes());
+        }
+        for value in [3i16, 10, 0x0102, 240] {
+            expected.extend_from_slice(&value.to_ne_bytes());
+        }
+        // The normal is clamped to 1 and followed by a zeroed padding byte
+        expected.extend_from_slice(&[127, -127i8 as u8, 0, 0]);
+        assert_eq!(expected.len(), format.size);
+        assert_eq!(data[..parameters.get_limit()], expected);
+    }
+
+    #[test]
+    fn fills_padding_between_vertices() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Triangles, VertexFormat::POSITION_TEXTURE_COLOR_NORMAL);
+        for _ in 0..2 {
+            builder
+                .vertex(0.0, 0.0, 0.0)
+                .unwrap()
+                .uv(0.0, 0.0)
+                .unwrap()
+                .color(0xFF, 0xFF, 0xFF, 0xFF)
+                .unwrap()
+                .normal(0.0, 1.0, 0.0)
+                .unwrap()
+                .end_vertex()
+                .unwrap();
+        }
+        builder.end().unwrap();
+        let (parameters, data) = builder.pop_data().unwrap();
+        let size = VertexFormat::POSITION_TEXTURE_COLOR_NORMAL.size;
+        assert_eq!(parameters.get_limit(), 2 * size);
+        // The second vertex starts right after the first one's padding
+        assert_eq!(data[size - 4..size], [0, 127, 0, 0]);
+        assert_eq!(data[2 * size - 4..2 * size], [0, 127, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_elements_out_of_order_and_discards_the_vertex() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Triangles, VertexFormat::POSITION_TEXTURE_COLOR);
+        let err = builder.vertex(0.0, 0.0, 0.0).unwrap().color(0, 0, 0, 0).map(|_| ()).unwrap_err();
+        assert!(matches!(
+            err,
+            VertexError::UnexpectedElement {
+                expected: "uv",
+                found: "color"
+            }
+        ));
+        // The next vertex starts over at the position
+        builder.vertex(1.0, 1.0, 1.0).unwrap().uv(0.0, 0.0).unwrap().color(0, 0, 0, 0).unwrap();
+        assert!(matches!(
+            builder.light(0, 0),
+            Err(VertexError::UnexpectedElement {
+                expected: "end_vertex",
+                found: "light"
+            })
+        ));
+        builder.vertex(2.0, 2.0, 2.0).unwrap().uv(0.0, 0.0).unwrap().color(0, 0, 0, 0).unwrap();
+        builder.end_vertex().unwrap();
+        builder.end().unwrap();
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert_eq!(parameters.count, 1);
+        assert_eq!(parameters.get_limit(), VertexFormat::POSITION_TEXTURE_COLOR.size);
+        assert_eq!(data[..4], 2.0f32.to_ne_bytes());
+    }
+
+    #[test]
+    fn reports_missing_elements_and_unfinished_vertices() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Triangles, VertexFormat::POSITION_TEXTURE_COLOR);
+        let err = builder.vertex(0.0, 0.0, 0.0).unwrap().end_vertex().unwrap_err();
+        let missing = match &err {
+            VertexError::MissingElements(missing) => missing.clone(),
+            _ => panic!("expected missing elements, got {}", err),
+        };
+        assert_eq!(missing, ["uv", "color"]);
+        assert_eq!(err.to_string(), "vertex ended without writing uv, color");
+
+        builder.vertex(0.0, 0.0, 0.0).unwrap();
+        assert!(matches!(builder.end(), Err(VertexError::UnfinishedVertex)));
+        // Neither half written vertex made it into the batch
+        builder.end().unwrap();
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert_eq!(parameters.count, 0);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn begins_again_after_a_reset() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        builder.set_camera_position(Vec3::zero());
+        builder.vertex(0.0, 0.0, 0.0).unwrap().end_vertex().unwrap();
+        builder.reset();
+        assert!(builder.pop_data().is_none());
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        build_quads(&mut builder);
+        builder.end().unwrap();
+        let (parameters, _) = builder.pop_data().unwrap();
+        assert_eq!(parameters.count, 8);
+        assert!(parameters.sequential);
+    }
+
+    #[test]
+    fn pops_queued_batches_of_different_formats_in_order() {
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION_TEXTURE_COLOR);
+        for (x, y) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+            builder.vertex(x, y, 0.0).unwrap().uv(x, y).unwrap().color(1, 2, 3, 4).unwrap();
+            builder.end_vertex().unwrap();
+        }
+        builder.end().unwrap();
+        builder.begin(DrawMode::Triangles, VertexFormat::POSITION_COLOR);
+        for x in [7.0, 8.0, 9.0] {
+            builder.vertex(x, 0.0, 0.0).unwrap().color(5, 6, 7, 8).unwrap().end_vertex().unwrap();
+        }
+        builder.end().unwrap();
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        builder.set_camera_position(Vec3::zero());
+        build_quads(&mut builder);
+        builder.end().unwrap();
+        let state = builder.pop_state().unwrap();
+        builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+        builder.restore_state(state);
+        builder.set_camera_position(Vec3::new(0.5, 0.5, -10.0));
+        builder.end().unwrap();
+
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert_eq!(parameters.vertex_format, VertexFormat::POSITION_TEXTURE_COLOR);
+        assert!(matches!(parameters.draw_mode, DrawMode::Quads));
+        assert!(parameters.sequential && !parameters.index_only);
+        assert_eq!((parameters.count, parameters.vertex_count), (4, 6));
+        assert_eq!(data.len(), 4 * VertexFormat::POSITION_TEXTURE_COLOR.size);
+
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert_eq!(parameters.vertex_format, VertexFormat::POSITION_COLOR);
+        assert!(matches!(parameters.draw_mode, DrawMode::Triangles));
+        assert!(parameters.sequential && !parameters.index_only);
+        assert_eq!(data.len(), 3 * VertexFormat::POSITION_COLOR.size);
+        assert_eq!(data[..4], 7.0f32.to_ne_bytes());
+        assert_eq!(data[12..16], [5, 6, 7, 8]);
+
+        // Sorted quads carry their indices after the vertices, padded so
+        // the next batch starts aligned
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert!(!parameters.sequential && !parameters.index_only);
+        assert_eq!(parameters.get_limit(), 8 * VertexFormat::POSITION.size);
+        assert_eq!(data.len(), parameters.get_limit() + 12);
+        assert_eq!(data[parameters.get_limit()..], [4, 5, 6, 6, 7, 4, 0, 1, 2, 2, 3, 0]);
+
+        let (parameters, data) = builder.pop_data().unwrap();
+        assert!(!parameters.sequential && parameters.index_only);
+        assert_eq!(data, [0, 1, 2, 2, 3, 0, 4, 5, 6, 6, 7, 4]);
+        assert!(builder.pop_data().is_none());
+    }
+
+    #[test]
+    fn sorts_by_the_position_wherever_the_format_puts_it() {
+        let format = VertexFormatBuilder::from_attributes(&["Color", "Normal", "Position"])
+            .and_then(VertexFormatBuilder::build)
+            .unwrap();
+        let mut builder = BufferBuilder::new(64);
+        builder.begin(DrawMode::Quads, format);
+        builder.set_camera_position(Vec3::zero());
+        for z in [-1.0, -5.0] {
+            for (x, y) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                builder.color(0, 0, 0, 0).unwrap().normal(0.0, 0.0, 1.0).unwrap();
+                builder.vertex(x, y, z).unwrap().end_vertex().unwrap();
+            }
+        }
+        builder.end().unwrap();
+        assert_eq!(indices(&mut builder), [4, 5, 6, 6, 7, 4, 0, 1, 2, 2, 3, 0]);
+
+        // Without a position the quads are left in order
+        let format = VertexFormatBuilder::from_attributes(&["Color"])
+            .and_then(VertexFormatBuilder::build)
+            .unwrap();
+        builder.begin(DrawMode::Quads, format);
+        builder.set_camera_position(Vec3::zero());
+        for _ in 0..4 {
+            builder.color(0, 0, 0, 0).unwrap().end_vertex().unwrap();
+        }
+        builder.end().unwrap();
+        assert!(builder.pop_data().unwrap().0.sequential);
+    }
+}
@@ -0,0 +1,843 @@
+use std::collections::HashMap;
+
+use gl33::*;
+use serde::Deserialize;
+use thiserror::Error;
+use ultraviolet::projection::rh_yup::orthographic_gl;
+use ultraviolet::Vec2;
+
+use crate::render::shader::{Shader, ShaderError, ShaderSource};
+use crate::render::texture::{ImageError, NativeImage};
+use crate::render::uniform::{StandardUniforms, Uniform, UniformKind};
+use crate::render::util::{GlStateManager, RenderState, TextureDeleter};
+use crate::render::vertex_buffer::{BufferUsage, VertexBuffer};
+use crate::render::{BufferBuilder, DrawMode, VertexError, VertexFormat};
+use crate::resources::reload::{PrepareTask, Prepared, ReloadListener};
+use crate::resources::{
+    Identifier, IdentifierError, ResourceError, ResourceLocation, ResourceManager,
+};
+use crate::types::{GLint, GLsizei};
+use crate::window::{Framebuffer, FramebufferError};
+
+#[derive(Debug, Error)]
+pub enum PostChainError {
+    #[error("unable to load post chain {chain}: {source}")]
+    Resource {
+        chain: String,
+        source: ResourceError,
+    },
+    #[error("target {target} is defined more than once in post chain {chain}")]
+    DuplicateTarget { chain: String, target: String },
+    #[error("post chain {chain} uses undefined target {target}")]
+    UnknownTarget { chain: String, target: String },
+    #[error("unable to create target {target} of post chain {chain}: {source}")]
+    Target {
+        chain: String,
+        target: String,
+        source: FramebufferError,
+    },
+    #[error("unable to load pass {pass} of post chain {chain}: {source}")]
+    Shader {
+        chain: String,
+        pass: String,
+        source: Box<ShaderError>,
+    },
+    #[error("pass {pass} of post chain {chain} sets uniform {uniform} which its program doesn't declare")]
+    UnknownUniform {
+        chain: String,
+        pass: String,
+        uniform: String,
+    },
+    #[error("uniform {uniform} in pass {pass} of post chain {chain} is {kind:?} and can't be set from {count} values")]
+    InvalidUniform {
+        chain: String,
+        pass: String,
+        uniform: String,
+        kind: UniformKind,
+        count: usize,
+    },
+    #[error("post chain {chain} has an invalid texture name {texture}: {source}")]
+    TextureIdentifier {
+        chain: String,
+        texture: String,
+        source: IdentifierError,
+    },
+    #[error("unable to load texture {texture} for post chain {chain}: {source}")]
+    TextureResource {
+        chain: String,
+        texture: Identifier,
+        source: ResourceError,
+    },
+    #[error("texture {texture} for post chain {chain} is not a valid image: {source}")]
+    Image {
+        chain: String,
+        texture: Identifier,
+        source: ImageError,
+    },
+}
+
+// assets/<ns>/shaders/post/<name>.json
+#[derive(Debug, Clone, Deserialize)]
+pub struct PostChainDefinition {
+    #[serde(default)]
+    pub targets: Vec<TargetDefinition>,
+    #[serde(default)]
+    pub passes: Vec<PassDefinition>,
+}
+
+// Targets named by a plain string follow the screen size
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TargetDefinition {
+    Screen(String),
+    Sized {
+        name: String,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl TargetDefinition {
+    fn name(&self) -> &str {
+        match self {
+            TargetDefinition::Screen(name) => name,
+            TargetDefinition::Sized { name, .. } => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PassDefinition {
+    // Program in shaders/program
+    pub name: String,
+    pub intarget: String,
+    pub outtarget: String,
+    #[serde(default)]
+    pub auxtargets: Vec<AuxTargetDefinition>,
+    #[serde(default)]
+    pub uniforms: Vec<PassUniformDefinition>,
+    #[serde(default)]
+    pub use_linear_filter: bool,
+}
+
+// An extra sampler input. The id is a target, a target with ":depth" to
+// read its depth or a texture in textures/effect
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuxTargetDefinition {
+    pub name: String,
+    pub id: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub bilinear: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PassUniformDefinition {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+struct PostTarget {
+    framebuffer: Framebuffer,
+    // Follows the screen size when false
+    fixed: bool,
+}
+
+enum AuxSource {
+    Color(String),
+    Depth(String),
+    Texture {
+        texture: TextureUnit,
+        width: u32,
+        height: u32,
+    },
+}
+
+struct AuxInput {
+    sampler: String,
+    source: AuxSource,
+}
+
+struct PostPass {
+    shader: Shader,
+    input: String,
+    output: String,
+    aux: Vec<AuxInput>,
+    // Set after the standard uniforms on every run
+    uniforms: Vec<PassUniformDefinition>,
+    linear: bool,
+}
+
+// Sets a uniform from the values in a pass definition, the uniform's kind is
+// returned when they don't fit it
+fn set_pass_uniform(target: &mut Uniform, values: &[f32]) -> Result<(), UniformKind> {
+    match target.kind() {
+        UniformKind::Float(count) if count == values.len() => target.set_floats(values),
+        UniformKind::Int(count) if count == values.len() => {
+            let values: Vec<i32> = values.iter().map(|value| *value as i32).collect();
+            target.set_ints(&values)
+        }
+        kind => return Err(kind),
+    }
+    Ok(())
+}
+
+// Everything a chain reads from resources, loaded without GL so it can be
+// prepared off the render thread
+pub struct PostChainSource {
+    identifier: Identifier,
+    definition: PostChainDefinition,
+    // Program of each pass, in order
+    programs: Vec<ShaderSource>,
+    // Decoded aux textures by id
+    textures: HashMap<String, NativeImage>,
+}
+
+impl PostChainSource {
+    pub fn load(
+        resources: &ResourceManager,
+        identifier: Identifier,
+    ) -> Result<PostChainSource, PostChainError> {
+        let chain = identifier.to_string();
+        let definition: PostChainDefinition =
+            resources
+                .get_json(identifier)
+                .map_err(|source| PostChainError::Resource {
+                    chain: chain.clone(),
+                    source,
+                })?;
+        let mut programs = Vec::with_capacity(definition.passes.len());
+        let mut textures = HashMap::new();
+        for pass in &definition.passes {
+            let program =
+                ShaderSource::load_in(resources, &pass.name, ShaderSource::PROGRAM_DIRECTORY)
+                    .map_err(|source| PostChainError::Shader {
+                        chain: chain.clone(),
+                        pass: pass.name.clone(),
+                        source: Box::new(source),
+                    })?;
+            programs.push(program);
+            for input in &pass.auxtargets {
+                let is_target = input.id.ends_with(":depth")
+                    || input.id == PostChain::MAIN_TARGET
+                    || definition
+                        .targets
+                        .iter()
+                        .any(|target| target.name() == input.id);
+                if !is_target && !textures.contains_key(&input.id) {
+                    let image = load_texture(resources, &chain, &input.id)?;
+                    textures.insert(input.id.clone(), image);
+                }
+            }
+        }
+        Ok(PostChainSource {
+            identifier,
+            definition,
+            programs,
+            textures,
+        })
+    }
+}
+
+// textures/effect/<id>.png
+fn load_texture(
+    resources: &ResourceManager,
+    chain: &str,
+    id: &str,
+) -> Result<NativeImage, PostChainError> {
+    let identifier = id
+        .parse::<ResourceLocation>()
+        .and_then(|location| {
+            Identifier::new(
+                location.namespace(),
+                &format!("{}/{}.png", PostChain::TEXTURE_DIRECTORY, location.path()),
+            )
+        })
+        .map_err(|source| PostChainError::TextureIdentifier {
+            chain: chain.to_string(),
+            texture: id.to_string(),
+            source,
+        })?;
+    let resource = resources
+        .get(identifier)
+        .map_err(|source| PostChainError::TextureResource {
+            chain: chain.to_string(),
+            texture: identifier,
+            source,
+        })?;
+    NativeImage::decode(&resource.data).map_err(|source| PostChainError::Image {
+        chain: chain.to_string(),
+        texture: identifier,
+        source,
+    })
+}
+
+// Passes run in order between offscreen targets after the world is drawn.
+// The main framebuffer is available to every pass as minecraft:main
+pub struct PostChain {
+    identifier: Identifier,
+    name: String,
+    targets: HashMap<String, PostTarget>,
+    passes: Vec<PostPass>,
+    // Loaded for aux inputs, deleted with the chain
+    textures: Vec<TextureUnit>,
+    deleter: TextureDeleter,
+    screen_width: GLsizei,
+    screen_height: GLsizei,
+    builder: BufferBuilder,
+    buffer: VertexBuffer,
+}
+
+fn find_target<'a>(
+    targets: &'a HashMap<String, PostTarget>,
+    main: &'a Framebuffer,
+    name: &str,
+) -> &'a Framebuffer {
+    if name == PostChain::MAIN_TARGET {
+        main
+    } else {
+        &targets[name].framebuffer
+    }
+}
+
+impl PostChain {
+    pub const DIRECTORY: &'static str = "shaders/post";
+    pub const MAIN_TARGET: &'static str = "minecraft:main";
+    const TEXTURE_DIRECTORY: &'static str = "textures/effect";
+
+    // Full identifier of a chain from its short name, so blur or
+    // minecraft:blur become minecraft:shaders/post/blur.json
+    pub fn identifier(name: &str) -> Result<Identifier, IdentifierError> {
+        let location = name.parse::<ResourceLocation>()?;
+        Identifier::new(
+            location.namespace(),
+            &format!("{}/{}.json", PostChain::DIRECTORY, location.path()),
+        )
+    }
+
+    // Loads a chain from its full identifier, e.g. minecraft:shaders/post/blur.json
+    pub fn load(
+        gl: &mut GlStateManager,
+        resources: &ResourceManager,
+        identifier: Identifier,
+        width: GLsizei,
+        height: GLsizei,
+    ) -> Result<PostChain, PostChainError> {
+        let source = PostChainSource::load(resources, identifier)?;
+        PostChain::build(gl, &source, width, height)
+    }
+
+    // Creates the targets, programs and textures of a loaded chain
+    pub fn build(
+        gl: &mut GlStateManager,
+        source: &PostChainSource,
+        width: GLsizei,
+        height: GLsizei,
+    ) -> Result<PostChain, PostChainError> {
+        // Built up in place so anything already created is freed on error
+        let mut chain = PostChain {
+            identifier: source.identifier,
+            name: source.identifier.to_string(),
+            targets: HashMap::new(),
+            passes: Vec::new(),
+            textures: Vec::new(),
+            deleter: gl.texture_deleter(),
+            screen_width: width,
+            screen_height: height,
+            builder: BufferBuilder::new(4),
+            buffer: VertexBuffer::new(gl, BufferUsage::Dynamic),
+        };
+        for target in &source.definition.targets {
+            chain.add_target(gl, target)?;
+        }
+        for (pass, program) in source.definition.passes.iter().zip(&source.programs) {
+            chain.add_pass(gl, source, pass, program)?;
+        }
+        Ok(chain)
+    }
+
+    fn add_target(
+        &mut self,
+        gl: &mut GlStateManager,
+        definition: &TargetDefinition,
+    ) -> Result<(), PostChainError> {
+        let name = definition.name();
+        if name == PostChain::MAIN_TARGET || self.targets.contains_key(name) {
+            return Err(PostChainError::DuplicateTarget {
+                chain: self.name.clone(),
+                target: name.to_string(),
+            });
+        }
+        let (width, height, fixed) = match *definition {
+            TargetDefinition::Screen(_) => (self.screen_width, self.screen_height, false),
+            TargetDefinition::Sized { width, height, .. } => {
+                (width as GLsizei, height as GLsizei, true)
+            }
+        };
+        let mut framebuffer =
+            Framebuffer::new(gl, width, height).map_err(|source| PostChainError::Target {
+                chain: self.name.clone(),
+                target: name.to_string(),
+                source,
+            })?;
+        framebuffer.set_clear_color(0.0, 0.0, 0.0, 0.0);
+        self.targets
+            .insert(name.to_string(), PostTarget { framebuffer, fixed });
+        Ok(())
+    }
+
+    fn check_target(&self, name: &str) -> Result<(), PostChainError> {
+        if name == PostChain::MAIN_TARGET || self.targets.contains_key(name) {
+            Ok(())
+        } else {
+            Err(PostChainError::UnknownTarget {
+                chain: self.name.clone(),
+                target: name.to_string(),
+            })
+        }
+    }
+
+    fn add_pass(
+        &mut self,
+        gl: &mut GlStateManager,
+        source: &PostChainSource,
+        definition: &PassDefinition,
+        program: &ShaderSource,
+    ) -> Result<(), PostChainError> {
+        self.check_target(&definition.intarget)?;
+        self.check_target(&definition.outtarget)?;
+        let mut shader = Shader::compile(gl, program).map_err(|source| PostChainError::Shader {
+            chain: self.name.clone(),
+            pass: definition.name.clone(),
+            source: Box::new(source),
+        })?;
+
+        for uniform in &definition.uniforms {
+            let target = match shader.uniform(&uniform.name) {
+                Some(value) => value,
+                None => {
+                    return Err(PostChainError::UnknownUniform {
+                        chain: self.name.clone(),
+                        pass: definition.name.clone(),
+                        uniform: uniform.name.clone(),
+                    })
+                }
+            };
+            if let Err(kind) = set_pass_uniform(target, &uniform.values) {
+                return Err(PostChainError::InvalidUniform {
+                    chain: self.name.clone(),
+                    pass: definition.name.clone(),
+                    uniform: uniform.name.clone(),
+                    kind,
+                    count: uniform.values.len(),
+                });
+            }
+        }
+
+        let mut aux = Vec::with_capacity(definition.auxtargets.len());
+        for input in &definition.auxtargets {
+            let aux_source = if let Some(target) = input.id.strip_suffix(":depth") {
+                self.check_target(target)?;
+                AuxSource::Depth(target.to_string())
+            } else if self.check_target(&input.id).is_ok() {
+                AuxSource::Color(input.id.clone())
+            } else {
+                // Loaded by PostChainSource for every id that isn't a target
+                self.load_texture(gl, input, &source.textures[&input.id])
+            };
+            aux.push(AuxInput {
+                sampler: input.name.clone(),
+                source: aux_source,
+            });
+        }
+
+        self.passes.push(PostPass {
+            shader,
+            input: definition.intarget.clone(),
+            output: definition.outtarget.clone(),
+            aux,
+            uniforms: definition.uniforms.clone(),
+            linear: definition.use_linear_filter,
+        });
+        Ok(())
+    }
+
+    fn load_texture(
+        &mut self,
+        gl: &mut GlStateManager,
+        input: &AuxTargetDefinition,
+        image: &NativeImage,
+    ) -> AuxSource {
+        let texture = gl.gen_texture();
+        self.textures.push(texture);
+        gl.bind_texture(texture);
+        let filter = if input.bilinear {
+            GL_LINEAR
+        } else {
+            GL_NEAREST
+        };
+        let backend = gl.backend();
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, filter.0 as GLint);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, filter.0 as GLint);
+        backend.tex_parameter_i(
+            GL_TEXTURE_2D,
+            GL_TEXTURE_WRAP_S,
+            GL_CLAMP_TO_EDGE.0 as GLint,
+        );
+        backend.tex_parameter_i(
+            GL_TEXTURE_2D,
+            GL_TEXTURE_WRAP_T,
+            GL_CLAMP_TO_EDGE.0 as GLint,
+        );
+        image.upload(gl, 0);
+        gl.bind_texture(GL_ZERO);
+        AuxSource::Texture {
+            texture,
+            width: input.width.unwrap_or(image.width()),
+            height: input.height.unwrap_or(image.height()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Resizes every target that follows the screen size
+    pub fn resize(
+        &mut self,
+        gl: &mut GlStateManager,
+        width: GLsizei,
+        height: GLsizei,
+    ) -> Result<(), FramebufferError> {
+        self.screen_width = width;
+        self.screen_height = height;
+        for target in self.targets.values_mut().filter(|target| !target.fixed) {
+            target.framebuffer.resize(gl, width, height)?;
+        }
+        Ok(())
+    }
+
+    fn set_filters(&mut self, gl: &mut GlStateManager, main: &mut Framebuffer, linear: bool) {
+        main.set_filter(gl, linear);
+        for target in self.targets.values_mut() {
+            target.framebuffer.set_filter(gl, linear);
+        }
+    }
+
+    // Runs every pass in order, time is the position within the current
+    // second from 0 to 1
+    pub fn process(
+        &mut self,
+        gl: &mut GlStateManager,
+        main: &mut Framebuffer,
+        uniforms: &StandardUniforms,
+        time: f32,
+    ) {
+        let mut linear = false;
+        for index in 0..self.passes.len() {
+            if self.passes[index].linear != linear {
+                linear = !linear;
+                self.set_filters(gl, main, linear);
+            }
+            let pass = &mut self.passes[index];
+            let input = find_target(&self.targets, main, &pass.input);
+            let output = find_target(&self.targets, main, &pass.output);
+            input.unbind(gl);
+            let (in_width, in_height) = input.texture_size();
+            let (out_width, out_height) = output.texture_size();
+            let (out_width, out_height) = (out_width as f32, out_height as f32);
+            gl.viewport(0, 0, out_width as GLsizei, out_height as GLsizei);
+
+            let shader = &mut pass.shader;
+            shader.set_standard_uniforms(uniforms);
+            shader.set_sampler("DiffuseSampler", input.color_texture().0);
+            for (index, aux) in pass.aux.iter().enumerate() {
+                let (texture, width, height) = match &aux.source {
+                    AuxSource::Color(name) => {
+                        let target = find_target(&self.targets, main, name);
+                        let (width, height) = target.texture_size();
+                        (target.color_texture(), width as u32, height as u32)
+                    }
+                    AuxSource::Depth(name) => {
+                        let target = find_target(&self.targets, main, name);
+                        let (width, height) = target.texture_size();
+                        (target.depth_texture(), width as u32, height as u32)
+                    }
+                    AuxSource::Texture {
+                        texture,
+                        width,
+                        height,
+                    } => (*texture, *width, *height),
+                };
+                shader.set_sampler(&aux.sampler, texture.0);
+                if let Some(uniform) = shader.uniform(&format!("AuxSize{}", index)) {
+                    uniform.set_vec2(Vec2::new(width as f32, height as f32));
+                }
+            }
+            if let Some(uniform) = shader.uniform("ProjMat") {
+                uniform.set_mat4(&orthographic_gl(
+                    0.0, out_width, 0.0, out_height, 0.1, 1000.0,
+                ));
+            }
+            if let Some(uniform) = shader.uniform("InSize") {
+                uniform.set_vec2(Vec2::new(in_width as f32, in_height as f32));
+            }
+            if let Some(uniform) = shader.uniform("OutSize") {
+                uniform.set_vec2(Vec2::new(out_width, out_height));
+            }
+            if let Some(uniform) = shader.uniform("Time") {
+                uniform.set_float(time);
+            }
+            if let Some(uniform) = shader.uniform("ScreenSize") {
+                uniform.set_vec2(Vec2::new(
+                    self.screen_width as f32,
+                    self.screen_height as f32,
+                ));
+            }
+            // Last so a pass can override any of the above
+            for uniform in &pass.uniforms {
+                if let Some(target) = shader.uniform(&uniform.name) {
+                    // Checked when the pass was added
+                    let _ = set_pass_uniform(target, &uniform.values);
+                }
+            }
+            // The quad always passes the depth test but still writes depth
+            let mut gl = gl.push_render_state(&RenderState {
+                blend: shader.blend.state(),
+                depth_test: Some(GL_ALWAYS),
+                cull: None,
+                ..RenderState::OPAQUE
+            });
+            shader.apply(&mut gl);
+
+            output.clear(&mut gl);
+            output.bind(&mut gl, false);
+            build_quad(&mut self.builder, out_width, out_height)
+                .expect("post quad matches POSITION");
+            if let Some((parameters, data)) = self.builder.pop_data() {
+                self.buffer.upload(parameters, data);
+            }
+            self.buffer.draw();
+            shader.unbind();
+            output.unbind(&mut gl);
+        }
+        if linear {
+            self.set_filters(gl, main, false);
+        }
+    }
+}
+
+fn build_quad(builder: &mut BufferBuilder, width: f32, height: f32) -> Result<(), VertexError> {
+    builder.begin(DrawMode::Quads, VertexFormat::POSITION);
+    builder.vertex(0.0, 0.0, 500.0)?.end_vertex()?;
+    builder.vertex(width, 0.0, 500.0)?.end_vertex()?;
+    builder.vertex(width, height, 500.0)?.end_vertex()?;
+    builder.vertex(0.0, height, 500.0)?.end_vertex()?;
+    builder.end()
+}
+
+impl ReloadListener for PostChain {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prepare(&self) -> PrepareTask {
+        let identifier = self.identifier;
+        Box::new(move |resources| {
+            Ok(Box::new(PostChainSource::load(resources, identifier)?) as Prepared)
+        })
+    }
+
+    fn apply(&mut self, gl: &mut GlStateManager, prepared: Prepared) {
+        let source = prepared.downcast::<PostChainSource>().unwrap();
+        match PostChain::build(gl, &source, self.screen_width, self.screen_height) {
+            Ok(chain) => *self = chain,
+            Err(err) => eprintln!("Keeping the old post chain {}: {}", self.name, err),
+        }
+    }
+}
+
+impl Drop for PostChain {
+    fn drop(&mut self) {
+        if !self.textures.is_empty() {
+            self.deleter.delete(&std::mem::take(&mut self.textures));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+
+    // Source of a chain from its JSON, every pass using a program from the
+    // embedded resources
+    fn source(json: &str) -> PostChainSource {
+        let resources = ResourceManager::new();
+        let definition: PostChainDefinition = serde_json::from_str(json).unwrap();
+        let programs = definition
+            .passes
+            .iter()
+            .map(|pass| {
+                ShaderSource::load_in(&resources, &pass.name, ShaderSource::PROGRAM_DIRECTORY)
+                    .unwrap()
+            })
+            .collect();
+        PostChainSource {
+            identifier: Identifier::minecraft("shaders/post/test.json").unwrap(),
+            definition,
+            programs,
+            textures: HashMap::new(),
+        }
+    }
+
+    fn build(json: &str) -> Result<PostChain, PostChainError> {
+        let mut gl = GlStateManager::new(Rc::new(RecordingGl::new()));
+        PostChain::build(&mut gl, &source(json), 320, 240)
+    }
+
+    #[test]
+    fn parses_targets_passes_and_inputs() {
+        let definition: PostChainDefinition = serde_json::from_str(
+            r#"{
+                "targets": ["swap", {"name": "small", "width": 64, "height": 32}],
+                "passes": [{
+                    "name": "blur",
+                    "intarget": "minecraft:main",
+                    "outtarget": "small",
+                    "auxtargets": [
+                        {"name": "DepthSampler", "id": "swap:depth"},
+                        {"name": "NoiseSampler", "id": "noise", "width": 8, "height": 8, "bilinear": true}
+                    ],
+                    "uniforms": [{"name": "Radius", "values": [2.0]}],
+                    "use_linear_filter": true
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert!(matches!(&definition.targets[0], TargetDefinition::Screen(name) if name == "swap"));
+        assert!(matches!(
+            &definition.targets[1],
+            TargetDefinition::Sized { name, width: 64, height: 32 } if name == "small"
+        ));
+        let pass = &definition.passes[0];
+        assert_eq!(
+            (pass.intarget.as_str(), pass.outtarget.as_str()),
+            ("minecraft:main", "small")
+        );
+        assert!(pass.use_linear_filter);
+        assert_eq!(pass.auxtargets[0].id, "swap:depth");
+        assert!(!pass.auxtargets[0].bilinear);
+        let noise = &pass.auxtargets[1];
+        assert_eq!(
+            (noise.width, noise.height, noise.bilinear),
+            (Some(8), Some(8), true)
+        );
+        assert_eq!(pass.uniforms[0].name, "Radius");
+        assert_eq!(pass.uniforms[0].values, [2.0]);
+
+        // The embedded blur chain builds with its two passes
+        let mut gl = GlStateManager::new(Rc::new(RecordingGl::new()));
+        let identifier = PostChain::identifier("blur").unwrap();
+        let chain = PostChain::load(&mut gl, &ResourceManager::new(), identifier, 320, 240);
+        let chain = chain.ok().unwrap();
+        assert_eq!(chain.passes.len(), 2);
+        assert!(chain.targets.contains_key("swap"));
+    }
+
+    #[test]
+    fn rejects_targets_defined_twice() {
+        for targets in [r#"["swap", "swap"]"#, r#"["minecraft:main"]"#] {
+            let chain = build(&format!(r#"{{"targets": {}}}"#, targets));
+            assert!(matches!(
+                chain.err(),
+                Some(PostChainError::DuplicateTarget { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_targets() {
+        let chain = build(
+            r#"{"passes": [{"name": "blur", "intarget": "swap", "outtarget": "minecraft:main"}]}"#,
+        );
+        assert!(matches!(
+            chain.err(),
+            Some(PostChainError::UnknownTarget { target, .. }) if target == "swap"
+        ));
+        let chain = build(
+            r#"{"passes": [{
+                "name": "blur",
+                "intarget": "minecraft:main",
+                "outtarget": "minecraft:main",
+                "auxtargets": [{"name": "DepthSampler", "id": "swap:depth"}]
+            }]}"#,
+        );
+        assert!(matches!(
+            chain.err(),
+            Some(PostChainError::UnknownTarget { target, .. }) if target == "swap"
+        ));
+    }
+
+    #[test]
+    fn rejects_uniforms_that_dont_fit() {
+        let chain = build(
+            r#"{"passes": [{
+                "name": "blur",
+                "intarget": "minecraft:main",
+                "outtarget": "minecraft:main",
+                "uniforms": [{"name": "Radius", "values": [1.0, 2.0]}]
+            }]}"#,
+        );
+        assert!(matches!(
+            chain.err(),
+            Some(PostChainError::InvalidUniform { uniform, kind: UniformKind::Float(1), count: 2, .. })
+                if uniform == "Radius"
+        ));
+        let chain = build(
+            r#"{"passes": [{
+                "name": "blur",
+                "intarget": "minecraft:main",
+                "outtarget": "minecraft:main",
+                "uniforms": [{"name": "Missing", "values": [1.0]}]
+            }]}"#,
+        );
+        assert!(matches!(
+            chain.err(),
+            Some(PostChainError::UnknownUniform { .. })
+        ));
+    }
+
+    #[test]
+    fn pass_uniforms_override_the_standard_ones() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let source = source(
+            r#"{
+                "targets": [{"name": "small", "width": 64, "height": 32}],
+                "passes": [{
+                    "name": "blur",
+                    "intarget": "minecraft:main",
+                    "outtarget": "small",
+                    "uniforms": [{"name": "OutSize", "values": [3.0, 4.0]}]
+                }]
+            }"#,
+        );
+        let mut chain = PostChain::build(&mut gl, &source, 320, 240).ok().unwrap();
+        let mut main = Framebuffer::new(&mut gl, 320, 240).unwrap();
+        backend.take_calls();
+        chain.process(&mut gl, &mut main, &StandardUniforms::new(), 0.0);
+        let uploads: Vec<Vec<i64>> = backend
+            .take_calls()
+            .into_iter()
+            .filter(|call| call.function == "glUniformfv")
+            .map(|call| call.args[1..].to_vec())
+            .collect();
+        let vec2 = |x: f32, y: f32| vec![x.to_bits() as i64, y.to_bits() as i64];
+        assert!(uploads.contains(&vec2(3.0, 4.0)));
+        assert!(!uploads.contains(&vec2(64.0, 32.0)));
+    }
+}
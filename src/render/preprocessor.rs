@@ -0,0 +1,411 @@
+use thiserror::Error;
+
+use crate::resources::{Identifier, IdentifierError, ResourceError, ResourceLocation};
+
+#[derive(Debug, Error)]
+pub enum PreprocessError {
+    #[error("malformed #moj_import in {file} on line {line}: {directive}")]
+    MalformedImport {
+        file: Identifier,
+        line: usize,
+        directive: String,
+    },
+    #[error("invalid #moj_import path in {file} on line {line}: {source}")]
+    InvalidPath {
+        file: Identifier,
+        line: usize,
+        source: IdentifierError,
+    },
+    #[error("#moj_import path {path} in {file} on line {line} leaves its directory")]
+    OutsideDirectory {
+        file: Identifier,
+        line: usize,
+        path: String,
+    },
+    #[error("#moj_import of {import} in {file} on line {line} is cyclic")]
+    Cycle {
+        file: Identifier,
+        line: usize,
+        import: Identifier,
+    },
+    #[error("unable to #moj_import {import} in {file} on line {line}: {source}")]
+    Resource {
+        file: Identifier,
+        line: usize,
+        import: Identifier,
+        source: ResourceError,
+    },
+}
+
+// GLSL source with every import inlined. Each inlined file is given its own
+// source string number in the #line directives, files[n] is the file used for
+// source string number n
+#[derive(Debug, Clone)]
+pub struct GlslSource {
+    pub code: String,
+    pub files: Vec<Identifier>,
+}
+
+impl GlslSource {
+    // Replaces the source string numbers at the start of driver log lines
+    // (e.g. "1:12(3): error" or "1(12) : error") with the file names
+    pub fn map_log(&self, log: &str) -> String {
+        log.lines()
+            .map(|line| {
+                let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+                let next = line[digits..].chars().next();
+                if digits == 0 || !matches!(next, Some(':') | Some('(')) {
+                    return line.to_string();
+                }
+                match line[..digits]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| self.files.get(index))
+                {
+                    Some(file) => format!("{}{}", file, &line[digits..]),
+                    None => line.to_string(),
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+enum Import<'a> {
+    // #moj_import <file.glsl>, resolved against shaders/include
+    Include(&'a str),
+    // #moj_import "file.glsl", resolved against the importing file
+    Relative(&'a str),
+}
+
+const IMPORT_DIRECTIVE: &str = "#moj_import";
+const INCLUDE_DIRECTORY: &str = "shaders/include";
+
+fn parse_import(line: &str) -> Option<Result<Import<'_>, ()>> {
+    let rest = line.trim_start().strip_prefix(IMPORT_DIRECTIVE)?;
+    let rest = rest.trim_start();
+    let close = match rest.chars().next() {
+        Some('<') => '>',
+        Some('"') => '"',
+        _ => return Some(Err(())),
+    };
+    let rest = &rest[1..];
+    let end = match rest.find(close) {
+        Some(value) => value,
+        None => return Some(Err(())),
+    };
+    let trailing = rest[end + 1..].trim();
+    if !trailing.is_empty() && !trailing.starts_with("//") {
+        return Some(Err(()));
+    }
+    let path = &rest[..end];
+    if close == '>' {
+        Some(Ok(Import::Include(path)))
+    } else {
+        Some(Ok(Import::Relative(path)))
+    }
+}
+
+// Joins a relative path onto a directory resolving "." and ".." segments.
+// None when ".." would leave the directory
+fn join_path(directory: &str, path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = directory.split('/').filter(|s| !s.is_empty()).collect();
+    let base = segments.len();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if segments.len() > base => {
+                segments.pop();
+            }
+            ".." => return None,
+            value => segments.push(value),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+// Include paths stay inside shaders/include and relative paths inside the
+// namespace of the importing file
+fn resolve_import(
+    file: Identifier,
+    line: usize,
+    import: Import,
+) -> Result<Identifier, PreprocessError> {
+    let invalid_path = |source| PreprocessError::InvalidPath { file, line, source };
+    let outside = |path: &str| PreprocessError::OutsideDirectory {
+        file,
+        line,
+        path: path.to_string(),
+    };
+    match import {
+        Import::Include(path) => {
+            // The location rejects ".." itself, report it like the relative form
+            let location = path.parse::<ResourceLocation>().map_err(|err| match err {
+                IdentifierError::RelativeSegment { .. } => outside(path),
+                err => invalid_path(err),
+            })?;
+            let joined =
+                join_path(INCLUDE_DIRECTORY, location.path()).ok_or_else(|| outside(path))?;
+            Identifier::new(location.namespace(), &joined).map_err(invalid_path)
+        }
+        Import::Relative(path) => {
+            let directory = match file.path().rfind('/') {
+                Some(index) => &file.path()[..index],
+                None => "",
+            };
+            let joined =
+                join_path("", &format!("{}/{}", directory, path)).ok_or_else(|| outside(path))?;
+            Identifier::new(file.namespace(), &joined).map_err(invalid_path)
+        }
+    }
+}
+
+// #version is only allowed once at the top, so it is commented out of
+// imported files like vanilla does
+fn is_version_directive(line: &str) -> bool {
+    line.trim_start()
+        .strip_prefix('#')
+        .is_some_and(|rest| rest.trim_start().starts_with("version"))
+}
+
+// Inlines #moj_import directives. Each file is only imported once, later
+// imports of the same file are dropped. Files are read through load so this
+// can run against in memory sources as well as a ResourceManager
+pub fn preprocess<F>(file: Identifier, source: &str, load: F) -> Result<GlslSource, PreprocessError>
+where
+    F: FnMut(Identifier) -> Result<String, ResourceError>,
+{
+    let mut preprocessor = Preprocessor {
+        load,
+        files: vec![file],
+        stack: vec![file],
+        code: String::with_capacity(source.len()),
+    };
+    preprocessor.process(file, 0, source)?;
+    Ok(GlslSource {
+        code: preprocessor.code,
+        files: preprocessor.files,
+    })
+}
+
+struct Preprocessor<F> {
+    load: F,
+    // Every file that has been imported in order of source string number
+    files: Vec<Identifier>,
+    // Files currently being processed, used to detect cycles
+    stack: Vec<Identifier>,
+    code: String,
+}
+
+impl<F> Preprocessor<F>
+where
+    F: FnMut(Identifier) -> Result<String, ResourceError>,
+{
+    fn process(
+        &mut self,
+        file: Identifier,
+        index: usize,
+        source: &str,
+    ) -> Result<(), PreprocessError> {
+        for (number, line) in source.lines().enumerate() {
+            let number = number + 1;
+            let import = match parse_import(line) {
+                None if index > 0 && is_version_directive(line) => {
+                    self.code.push_str("// ");
+                    self.code.push_str(line);
+                    self.code.push('\n');
+                    continue;
+                }
+                None => {
+                    self.code.push_str(line);
+                    self.code.push('\n');
+                    continue;
+                }
+                Some(Err(())) => {
+                    return Err(PreprocessError::MalformedImport {
+                        file,
+                        line: number,
+                        directive: line.trim().to_string(),
+                    })
+                }
+                Some(Ok(value)) => value,
+            };
+            let import = resolve_import(file, number, import)?;
+            if self.stack.contains(&import) {
+                return Err(PreprocessError::Cycle {
+                    file,
+                    line: number,
+                    import,
+                });
+            }
+            if self.files.contains(&import) {
+                // Keep the line count matching the original file
+                self.code.push('\n');
+                continue;
+            }
+            let imported = (self.load)(import).map_err(|source| PreprocessError::Resource {
+                file,
+                line: number,
+                import,
+                source,
+            })?;
+            let import_index = self.files.len();
+            self.files.push(import);
+            self.stack.push(import);
+            self.code.push_str(&format!("#line 0 {}\n", import_index));
+            self.process(import, import_index, &imported)?;
+            self.stack.pop();
+            self.code.push_str(&format!("#line {} {}\n", number, index));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn identifier(value: &str) -> Identifier {
+        value.parse().unwrap()
+    }
+
+    fn run(main: &str, files: &[(&str, &str)]) -> Result<GlslSource, PreprocessError> {
+        let files: HashMap<Identifier, String> = files
+            .iter()
+            .map(|(name, code)| (identifier(name), code.to_string()))
+            .collect();
+        preprocess(identifier("shaders/core/main.vsh"), main, |import| {
+            files
+                .get(&import)
+                .cloned()
+                .ok_or(ResourceError::NotFound(import))
+        })
+    }
+
+    #[test]
+    fn inlines_imports_with_line_directives() {
+        let source = run(
+            "#version 150\n#moj_import <fog.glsl>\nvoid main() {}\n",
+            &[("shaders/include/fog.glsl", "#version 150\nfloat fog;\n")],
+        )
+        .unwrap();
+        assert_eq!(
+            source.code,
+            "#version 150\n#line 0 1\n// #version 150\nfloat fog;\n#line 2 0\nvoid main() {}\n"
+        );
+        assert_eq!(
+            source.files,
+            [
+                identifier("shaders/core/main.vsh"),
+                identifier("shaders/include/fog.glsl")
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_relative_and_namespaced_imports() {
+        let source = run(
+            "#moj_import \"../include/a.glsl\"\n#moj_import <mod:b.glsl>\n",
+            &[
+                ("shaders/include/a.glsl", "float a;"),
+                ("mod:shaders/include/b.glsl", "float b;"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            source.files[1..],
+            [
+                identifier("shaders/include/a.glsl"),
+                identifier("mod:shaders/include/b.glsl")
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_each_file_once() {
+        let source = run(
+            "#moj_import <a.glsl>\n#moj_import <b.glsl>\n#moj_import <a.glsl>\nvoid main() {}\n",
+            &[
+                ("shaders/include/a.glsl", "float a;"),
+                ("shaders/include/b.glsl", "#moj_import <a.glsl>\nfloat b;"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(source.code.matches("float a;").count(), 1);
+        assert_eq!(source.files.len(), 3);
+        // The dropped import still takes up its line
+        assert!(source.code.ends_with("#line 2 0\n\nvoid main() {}\n"));
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let result = run(
+            "#moj_import <a.glsl>\n",
+            &[
+                ("shaders/include/a.glsl", "#moj_import <b.glsl>"),
+                ("shaders/include/b.glsl", "\n#moj_import <a.glsl>"),
+            ],
+        );
+        match result {
+            Err(PreprocessError::Cycle { file, line, import }) => {
+                assert_eq!(file, identifier("shaders/include/b.glsl"));
+                assert_eq!(line, 2);
+                assert_eq!(import, identifier("shaders/include/a.glsl"));
+            }
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_paths_leaving_their_directory() {
+        for main in [
+            "#moj_import <../x.glsl>",
+            "#moj_import <a/../../x.glsl>",
+            "#moj_import \"../../../x.glsl\"",
+        ] {
+            assert!(
+                matches!(
+                    run(main, &[]),
+                    Err(PreprocessError::OutsideDirectory { .. })
+                ),
+                "{} was accepted",
+                main
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_imports() {
+        for main in [
+            "#moj_import fog.glsl",
+            "#moj_import <fog.glsl",
+            "#moj_import <fog.glsl> x",
+        ] {
+            assert!(matches!(
+                run(main, &[]),
+                Err(PreprocessError::MalformedImport { line: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn maps_log_lines_to_files() {
+        let source = GlslSource {
+            code: String::new(),
+            files: vec![
+                identifier("shaders/core/main.vsh"),
+                identifier("shaders/include/fog.glsl"),
+            ],
+        };
+        let log = "0:3(1): error: a\n1(4) : error b\n2:1(1): error c\nnote";
+        assert_eq!(
+            source.map_log(log),
+            "minecraft:shaders/core/main.vsh:3(1): error: a\n\
+             minecraft:shaders/include/fog.glsl(4) : error b\n\
+             2:1(1): error c\n\
+             note"
+        );
+    }
+}
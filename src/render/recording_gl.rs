@@ -0,0 +1,619 @@
+use std::cell::RefCell;
+
+use gl33::*;
+
+use crate::render::backend::GlBackend;
+use crate::types::{GLboolean, GLfloat, GLint, GLsizei, GLuint};
+
+// A call made through RecordingGl. Enums, objects and sizes are logged as
+// integers, data and strings only by their length
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlCall {
+    pub function: &'static str,
+    pub args: Vec<i64>,
+}
+
+struct Recording {
+    calls: Vec<GlCall>,
+    // Shared by every kind of object, ids start at 1 like GL's
+    next_id: GLuint,
+    next_uniform_location: GLint,
+    // Like GL only the first error is kept until get_error
+    error: GLenum,
+    max_texture_size: GLint,
+    // Texture levels with more texels than this fail with GL_OUT_OF_MEMORY
+    texel_limit: Option<u64>,
+    // Width of the last GL_PROXY_TEXTURE_2D image, 0 if it was too large
+    proxy_width: GLint,
+    framebuffer_status: FramebufferStatus,
+    compile_status: bool,
+    link_status: bool,
+}
+
+// GlBackend that needs no context, for running render code headless. Every
+// call is logged and the few queries render code makes answer from settings,
+// so tests can check what was sent and simulate running out of memory,
+// incomplete framebuffers and shader errors
+pub struct RecordingGl {
+    recording: RefCell<Recording>,
+}
+
+impl RecordingGl {
+    pub fn new() -> RecordingGl {
+        RecordingGl {
+            recording: RefCell::new(Recording {
+                calls: Vec::new(),
+                next_id: 1,
+                next_uniform_location: 0,
+                error: GL_NO_ERROR,
+                max_texture_size: 16384,
+                texel_limit: None,
+                proxy_width: 0,
+                framebuffer_status: GL_FRAMEBUFFER_COMPLETE,
+                compile_status: true,
+                link_status: true,
+            }),
+        }
+    }
+
+    pub fn set_max_texture_size(&self, size: GLint) {
+        self.recording.borrow_mut().max_texture_size = size;
+    }
+
+    pub fn set_texel_limit(&self, limit: Option<u64>) {
+        self.recording.borrow_mut().texel_limit = limit;
+    }
+
+    pub fn set_framebuffer_status(&self, status: FramebufferStatus) {
+        self.recording.borrow_mut().framebuffer_status = status;
+    }
+
+    pub fn set_compile_status(&self, success: bool) {
+        self.recording.borrow_mut().compile_status = success;
+    }
+
+    pub fn set_link_status(&self, success: bool) {
+        self.recording.borrow_mut().link_status = success;
+    }
+
+    pub fn take_calls(&self) -> Vec<GlCall> {
+        std::mem::take(&mut self.recording.borrow_mut().calls)
+    }
+
+    pub fn count(&self, function: &str) -> usize {
+        self.recording
+            .borrow()
+            .calls
+            .iter()
+            .filter(|call| call.function == function)
+            .count()
+    }
+
+    fn record(&self, function: &'static str, args: &[i64]) {
+        self.recording.borrow_mut().calls.push(GlCall {
+            function,
+            args: args.to_vec(),
+        });
+    }
+
+    fn gen(&self, function: &'static str) -> GLuint {
+        let id = self.next_id();
+        self.record(function, &[id as i64]);
+        id
+    }
+
+    fn next_id(&self) -> GLuint {
+        let mut recording = self.recording.borrow_mut();
+        recording.next_id += 1;
+        recording.next_id - 1
+    }
+}
+
+impl Default for RecordingGl {
+    fn default() -> RecordingGl {
+        RecordingGl::new()
+    }
+}
+
+impl Recording {
+    fn raise(&mut self, error: GLenum) {
+        if self.error == GL_NO_ERROR {
+            self.error = error;
+        }
+    }
+}
+
+fn e(value: GLenum) -> i64 {
+    value.0 as i64
+}
+
+impl GlBackend for RecordingGl {
+    fn get_error(&self) -> GLenum {
+        self.record("glGetError", &[]);
+        std::mem::replace(&mut self.recording.borrow_mut().error, GL_NO_ERROR)
+    }
+
+    fn get_integer(&self, name: GLenum) -> GLint {
+        self.record("glGetIntegerv", &[e(name)]);
+        if name == GL_MAX_TEXTURE_SIZE {
+            self.recording.borrow().max_texture_size
+        } else {
+            0
+        }
+    }
+
+    fn enable(&self, cap: EnableCap) {
+        self.record("glEnable", &[e(cap)]);
+    }
+
+    fn disable(&self, cap: EnableCap) {
+        self.record("glDisable", &[e(cap)]);
+    }
+
+    fn depth_func(&self, func: DepthFunction) {
+        self.record("glDepthFunc", &[e(func)]);
+    }
+
+    fn depth_mask(&self, mask: bool) {
+        self.record("glDepthMask", &[mask as i64]);
+    }
+
+    fn blend_func(&self, src: BlendingFactor, dst: BlendingFactor) {
+        self.record("glBlendFunc", &[e(src), e(dst)]);
+    }
+
+    fn blend_func_separate(
+        &self,
+        src_rgb: BlendingFactor,
+        dst_rgb: BlendingFactor,
+        src_alpha: BlendingFactor,
+        dst_alpha: BlendingFactor,
+    ) {
+        self.record(
+            "glBlendFuncSeparate",
+            &[e(src_rgb), e(dst_rgb), e(src_alpha), e(dst_alpha)],
+        );
+    }
+
+    fn blend_equation(&self, equation: BlendEquationModeEXT) {
+        self.record("glBlendEquation", &[e(equation)]);
+    }
+
+    fn cull_face(&self, mode: CullFaceMode) {
+        self.record("glCullFace", &[e(mode)]);
+    }
+
+    fn polygon_offset(&self, factor: GLfloat, units: GLfloat) {
+        self.record(
+            "glPolygonOffset",
+            &[factor.to_bits() as i64, units.to_bits() as i64],
+        );
+    }
+
+    fn logic_op(&self, op: LogicOp) {
+        self.record("glLogicOp", &[e(op)]);
+    }
+
+    fn viewport(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        self.record(
+            "glViewport",
+            &[x as i64, y as i64, width as i64, height as i64],
+        );
+    }
+
+    fn color_mask(&self, red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) {
+        self.record(
+            "glColorMask",
+            &[red as i64, green as i64, blue as i64, alpha as i64],
+        );
+    }
+
+    fn stencil_func(&self, func: StencilFunction, ref_: GLint, mask: GLuint) {
+        self.record("glStencilFunc", &[e(func), ref_ as i64, mask as i64]);
+    }
+
+    fn stencil_mask(&self, mask: GLuint) {
+        self.record("glStencilMask", &[mask as i64]);
+    }
+
+    fn stencil_op(&self, sfail: StencilOp, dpfail: StencilOp, dppass: StencilOp) {
+        self.record("glStencilOp", &[e(sfail), e(dpfail), e(dppass)]);
+    }
+
+    fn clear_color(&self, red: GLfloat, green: GLfloat, blue: GLfloat, alpha: GLfloat) {
+        let args = [red, green, blue, alpha].map(|value| value.to_bits() as i64);
+        self.record("glClearColor", &args);
+    }
+
+    fn clear_depth(&self, depth: f64) {
+        self.record("glClearDepth", &[depth.to_bits() as i64]);
+    }
+
+    fn clear(&self, mask: GLbitfield) {
+        self.record("glClear", &[mask.0 as i64]);
+    }
+
+    fn gen_texture(&self) -> GLuint {
+        self.gen("glGenTextures")
+    }
+
+    fn delete_textures(&self, textures: &[GLuint]) {
+        let args: Vec<i64> = textures.iter().map(|texture| *texture as i64).collect();
+        self.record("glDeleteTextures", &args);
+    }
+
+    fn active_texture(&self, unit: TextureUnit) {
+        self.record("glActiveTexture", &[e(unit)]);
+    }
+
+    fn bind_texture(&self, target: TextureTarget, texture: GLuint) {
+        self.record("glBindTexture", &[e(target), texture as i64]);
+    }
+
+    fn tex_parameter_i(&self, target: TextureTarget, name: TextureParameterName, value: GLint) {
+        self.record("glTexParameteri", &[e(target), e(name), value as i64]);
+    }
+
+    fn tex_parameter_f(&self, target: TextureTarget, name: TextureParameterName, value: GLfloat) {
+        self.record(
+            "glTexParameterf",
+            &[e(target), e(name), value.to_bits() as i64],
+        );
+    }
+
+    fn tex_image_2d(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        internal_format: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: Option<&[u8]>,
+    ) {
+        self.record(
+            "glTexImage2D",
+            &[
+                e(target),
+                level as i64,
+                internal_format as i64,
+                width as i64,
+                height as i64,
+                e(format),
+                e(type_),
+                pixels.map_or(-1, |pixels| pixels.len() as i64),
+            ],
+        );
+        let mut recording = self.recording.borrow_mut();
+        let max_size = recording.max_texture_size;
+        let fits = width >= 0 && width <= max_size && height >= 0 && height <= max_size;
+        if target == GL_PROXY_TEXTURE_2D {
+            recording.proxy_width = if fits { width } else { 0 };
+        } else if !fits {
+            recording.raise(GL_INVALID_VALUE);
+        } else if let Some(limit) = recording.texel_limit {
+            if width as u64 * height as u64 > limit {
+                recording.raise(GL_OUT_OF_MEMORY);
+            }
+        }
+    }
+
+    fn tex_sub_image_2d(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        x: GLint,
+        y: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: &[u8],
+    ) {
+        self.record(
+            "glTexSubImage2D",
+            &[
+                e(target),
+                level as i64,
+                x as i64,
+                y as i64,
+                width as i64,
+                height as i64,
+                e(format),
+                e(type_),
+                pixels.len() as i64,
+            ],
+        );
+    }
+
+    fn pixel_store_i(&self, name: PixelStoreParameter, value: GLint) {
+        self.record("glPixelStorei", &[e(name), value as i64]);
+    }
+
+    // Textures have no contents, so pixels are left as they are
+    fn get_tex_image(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        format: PixelFormat,
+        type_: PixelType,
+        pixels: &mut [u8],
+    ) {
+        self.record(
+            "glGetTexImage",
+            &[
+                e(target),
+                level as i64,
+                e(format),
+                e(type_),
+                pixels.len() as i64,
+            ],
+        );
+    }
+
+    fn get_tex_level_parameter_i(
+        &self,
+        target: TextureTarget,
+        level: GLint,
+        name: GetTextureParameter,
+    ) -> GLint {
+        self.record(
+            "glGetTexLevelParameteriv",
+            &[e(target), level as i64, e(name)],
+        );
+        if target == GL_PROXY_TEXTURE_2D && level == 0 && name == GL_TEXTURE_WIDTH {
+            self.recording.borrow().proxy_width
+        } else {
+            0
+        }
+    }
+
+    fn gen_framebuffer(&self) -> GLuint {
+        self.gen("glGenFramebuffers")
+    }
+
+    fn delete_framebuffer(&self, framebuffer: GLuint) {
+        self.record("glDeleteFramebuffers", &[framebuffer as i64]);
+    }
+
+    fn bind_framebuffer(&self, target: FramebufferTarget, framebuffer: GLuint) {
+        self.record("glBindFramebuffer", &[e(target), framebuffer as i64]);
+    }
+
+    fn framebuffer_texture_2d(
+        &self,
+        target: FramebufferTarget,
+        attachment: FramebufferAttachment,
+        texture_target: TextureTarget,
+        texture: GLuint,
+        level: GLint,
+    ) {
+        self.record(
+            "glFramebufferTexture2D",
+            &[
+                e(target),
+                e(attachment),
+                e(texture_target),
+                texture as i64,
+                level as i64,
+            ],
+        );
+    }
+
+    fn check_framebuffer_status(&self, target: FramebufferTarget) -> FramebufferStatus {
+        self.record("glCheckFramebufferStatus", &[e(target)]);
+        self.recording.borrow().framebuffer_status
+    }
+
+    fn gen_buffer(&self) -> GLuint {
+        self.gen("glGenBuffers")
+    }
+
+    fn delete_buffer(&self, buffer: GLuint) {
+        self.record("glDeleteBuffers", &[buffer as i64]);
+    }
+
+    fn bind_buffer(&self, target: BufferTargetARB, buffer: GLuint) {
+        self.record("glBindBuffer", &[e(target), buffer as i64]);
+    }
+
+    fn buffer_data(&self, target: BufferTargetARB, data: &[u8], usage: BufferUsageARB) {
+        self.record("glBufferData", &[e(target), data.len() as i64, e(usage)]);
+    }
+
+    fn gen_vertex_array(&self) -> GLuint {
+        self.gen("glGenVertexArrays")
+    }
+
+    fn delete_vertex_array(&self, vertex_array: GLuint) {
+        self.record("glDeleteVertexArrays", &[vertex_array as i64]);
+    }
+
+    fn bind_vertex_array(&self, vertex_array: GLuint) {
+        self.record("glBindVertexArray", &[vertex_array as i64]);
+    }
+
+    fn enable_vertex_attrib_array(&self, index: GLuint) {
+        self.record("glEnableVertexAttribArray", &[index as i64]);
+    }
+
+    fn disable_vertex_attrib_array(&self, index: GLuint) {
+        self.record("glDisableVertexAttribArray", &[index as i64]);
+    }
+
+    fn vertex_attrib_pointer(
+        &self,
+        index: GLuint,
+        size: GLint,
+        type_: VertexAttribPointerType,
+        normalized: bool,
+        stride: GLsizei,
+        offset: usize,
+    ) {
+        self.record(
+            "glVertexAttribPointer",
+            &[
+                index as i64,
+                size as i64,
+                e(type_),
+                normalized as i64,
+                stride as i64,
+                offset as i64,
+            ],
+        );
+    }
+
+    fn vertex_attrib_i_pointer(
+        &self,
+        index: GLuint,
+        size: GLint,
+        type_: VertexAttribIType,
+        stride: GLsizei,
+        offset: usize,
+    ) {
+        self.record(
+            "glVertexAttribIPointer",
+            &[
+                index as i64,
+                size as i64,
+                e(type_),
+                stride as i64,
+                offset as i64,
+            ],
+        );
+    }
+
+    fn draw_elements(
+        &self,
+        mode: PrimitiveType,
+        count: GLsizei,
+        type_: DrawElementsType,
+        offset: usize,
+    ) {
+        self.record(
+            "glDrawElements",
+            &[e(mode), count as i64, e(type_), offset as i64],
+        );
+    }
+
+    fn create_shader(&self, kind: ShaderType) -> GLuint {
+        let id = self.next_id();
+        self.record("glCreateShader", &[e(kind), id as i64]);
+        id
+    }
+
+    fn delete_shader(&self, shader: GLuint) {
+        self.record("glDeleteShader", &[shader as i64]);
+    }
+
+    fn shader_source(&self, shader: GLuint, source: &str) {
+        self.record("glShaderSource", &[shader as i64, source.len() as i64]);
+    }
+
+    fn compile_shader(&self, shader: GLuint) {
+        self.record("glCompileShader", &[shader as i64]);
+    }
+
+    fn get_shader_i(&self, shader: GLuint, name: ShaderParameterName) -> GLint {
+        self.record("glGetShaderiv", &[shader as i64, e(name)]);
+        if name == GL_COMPILE_STATUS {
+            self.recording.borrow().compile_status as GLint
+        } else {
+            0
+        }
+    }
+
+    fn shader_info_log(&self, shader: GLuint) -> String {
+        self.record("glGetShaderInfoLog", &[shader as i64]);
+        if self.recording.borrow().compile_status {
+            String::new()
+        } else {
+            "0(1) : error C0000: simulated compile failure".to_string()
+        }
+    }
+
+    fn create_program(&self) -> GLuint {
+        self.gen("glCreateProgram")
+    }
+
+    fn delete_program(&self, program: GLuint) {
+        self.record("glDeleteProgram", &[program as i64]);
+    }
+
+    fn attach_shader(&self, program: GLuint, shader: GLuint) {
+        self.record("glAttachShader", &[program as i64, shader as i64]);
+    }
+
+    fn detach_shader(&self, program: GLuint, shader: GLuint) {
+        self.record("glDetachShader", &[program as i64, shader as i64]);
+    }
+
+    fn bind_attrib_location(&self, program: GLuint, index: GLuint, name: &str) {
+        self.record(
+            "glBindAttribLocation",
+            &[program as i64, index as i64, name.len() as i64],
+        );
+    }
+
+    fn link_program(&self, program: GLuint) {
+        self.record("glLinkProgram", &[program as i64]);
+    }
+
+    fn get_program_i(&self, program: GLuint, name: ProgramPropertyARB) -> GLint {
+        self.record("glGetProgramiv", &[program as i64, e(name)]);
+        if name == GL_LINK_STATUS {
+            self.recording.borrow().link_status as GLint
+        } else {
+            0
+        }
+    }
+
+    fn program_info_log(&self, program: GLuint) -> String {
+        self.record("glGetProgramInfoLog", &[program as i64]);
+        if self.recording.borrow().link_status {
+            String::new()
+        } else {
+            "simulated link failure".to_string()
+        }
+    }
+
+    fn use_program(&self, program: GLuint) {
+        self.record("glUseProgram", &[program as i64]);
+    }
+
+    // Every name is found, each lookup at a new location
+    fn get_uniform_location(&self, program: GLuint, name: &str) -> GLint {
+        let mut recording = self.recording.borrow_mut();
+        let location = recording.next_uniform_location;
+        recording.next_uniform_location += 1;
+        recording.calls.push(GlCall {
+            function: "glGetUniformLocation",
+            args: vec![program as i64, name.len() as i64, location as i64],
+        });
+        location
+    }
+
+    fn uniform_i(&self, location: GLint, size: usize, values: &[GLint]) {
+        let mut args = vec![location as i64];
+        args.extend(values.iter().take(size).map(|value| *value as i64));
+        self.record("glUniformiv", &args);
+    }
+
+    fn uniform_f(&self, location: GLint, size: usize, values: &[GLfloat]) {
+        let mut args = vec![location as i64];
+        args.extend(values.iter().take(size).map(|value| value.to_bits() as i64));
+        self.record("glUniformfv", &args);
+    }
+
+    fn uniform_matrix(&self, location: GLint, size: usize, values: &[GLfloat]) {
+        let mut args = vec![location as i64];
+        args.extend(
+            values
+                .iter()
+                .take(size * size)
+                .map(|value| value.to_bits() as i64),
+        );
+        self.record("glUniformMatrixfv", &args);
+    }
+}
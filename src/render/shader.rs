@@ -1,6 +1,765 @@
+use std::rc::Rc;
+
 use gl33::*;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::render::backend::GlBackend;
+use crate::render::preprocessor::{preprocess, GlslSource, PreprocessError};
+use crate::render::uniform::{StandardUniform, StandardUniforms, Uniform};
+use crate::render::util::{BlendState, GlStateManager};
+use crate::render::vertex_format::{VertexFormatBuilder, VertexFormatError};
+use crate::render::VertexFormat;
+use crate::resources::reload::{PrepareTask, Prepared, ReloadListener};
+use crate::resources::{
+    Identifier, IdentifierError, ResourceError, ResourceLocation, ResourceManager,
+};
+use crate::types::GLuint;
+
+#[derive(Debug, Error)]
+pub enum ShaderError {
+    #[error("shader {shader} has an invalid identifier: {source}")]
+    Identifier {
+        shader: String,
+        source: IdentifierError,
+    },
+    #[error("unable to load shader {shader}: {source}")]
+    Resource {
+        shader: String,
+        source: ResourceError,
+    },
+    #[error("unable to preprocess shader {shader}: {source}")]
+    Preprocess {
+        shader: String,
+        source: PreprocessError,
+    },
+    #[error("uniform {uniform} in shader {shader} has {actual} values but expected {expected}")]
+    InvalidUniform {
+        shader: String,
+        uniform: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("uniform {uniform} in shader {shader} has an unsupported count {count} for {type_:?}")]
+    UnsupportedUniform {
+        shader: String,
+        uniform: String,
+        type_: UniformType,
+        count: usize,
+    },
+    #[error("failed to compile {stage} program {program} for shader {shader}:\n{log}")]
+    Compile {
+        shader: String,
+        program: String,
+        stage: ShaderStage,
+        log: String,
+    },
+    #[error("shader {shader} has an invalid vertex format: {source}")]
+    VertexFormat {
+        shader: String,
+        source: VertexFormatError,
+    },
+    #[error("shader {shader} has an invalid blend {value}")]
+    InvalidBlend { shader: String, value: String },
+    #[error("failed to link shader {shader}:\n{log}")]
+    Link { shader: String, log: String },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+}
+
+impl ShaderStage {
+    fn extension(&self) -> &'static str {
+        match self {
+            ShaderStage::Vertex => "vsh",
+            ShaderStage::Fragment => "fsh",
+        }
+    }
+
+    fn gl(&self) -> ShaderType {
+        match self {
+            ShaderStage::Vertex => GL_VERTEX_SHADER,
+            ShaderStage::Fragment => GL_FRAGMENT_SHADER,
+        }
+    }
+}
+
+impl std::fmt::Display for ShaderStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShaderStage::Vertex => f.write_str("vertex"),
+            ShaderStage::Fragment => f.write_str("fragment"),
+        }
+    }
+}
+
+// assets/<ns>/shaders/core/<name>.json
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShaderDefinition {
+    pub vertex: String,
+    pub fragment: String,
+    #[serde(default)]
+    pub attributes: Vec<String>,
+    #[serde(default)]
+    pub samplers: Vec<SamplerDefinition>,
+    #[serde(default)]
+    pub uniforms: Vec<UniformDefinition>,
+    pub blend: Option<BlendDefinition>,
+}
+
+// Missing fields keep the opaque defaults, factors are names like
+// "srcalpha" or "1-srcalpha" in any case with optional underscores
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlendDefinition {
+    pub func: Option<String>,
+    pub srcrgb: Option<String>,
+    pub dstrgb: Option<String>,
+    pub srcalpha: Option<String>,
+    pub dstalpha: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SamplerDefinition {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UniformDefinition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: UniformType,
+    pub count: usize,
+    pub values: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum UniformType {
+    #[serde(rename = "int")]
+    Int,
+    #[serde(rename = "float")]
+    Float,
+    #[serde(rename = "matrix2x2")]
+    Matrix2x2,
+    #[serde(rename = "matrix3x3")]
+    Matrix3x3,
+    #[serde(rename = "matrix4x4")]
+    Matrix4x4,
+}
+
+impl UniformType {
+    fn supports_count(&self, count: usize) -> bool {
+        match self {
+            UniformType::Int | UniformType::Float => (1..=4).contains(&count),
+            UniformType::Matrix2x2 => count == 4,
+            UniformType::Matrix3x3 => count == 9,
+            UniformType::Matrix4x4 => count == 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlendMode {
+    equation: BlendEquationModeEXT,
+    src_rgb: BlendingFactor,
+    dst_rgb: BlendingFactor,
+    src_alpha: BlendingFactor,
+    dst_alpha: BlendingFactor,
+}
+
+impl BlendMode {
+    pub const OPAQUE: BlendMode = BlendMode {
+        equation: GL_FUNC_ADD,
+        src_rgb: GL_ONE,
+        dst_rgb: GL_ZERO,
+        src_alpha: GL_ONE,
+        dst_alpha: GL_ZERO,
+    };
+
+    // Returns the invalid value on failure
+    fn from_definition(definition: &BlendDefinition) -> Result<BlendMode, String> {
+        let factor = |name: &Option<String>, default| match name {
+            Some(name) => blend_factor(name).ok_or_else(|| name.clone()),
+            None => Ok(default),
+        };
+        let equation = match &definition.func {
+            Some(name) => blend_equation(name).ok_or_else(|| name.clone())?,
+            None => GL_FUNC_ADD,
+        };
+        let src_rgb = factor(&definition.srcrgb, GL_ONE)?;
+        let dst_rgb = factor(&definition.dstrgb, GL_ZERO)?;
+        Ok(BlendMode {
+            equation,
+            src_rgb,
+            dst_rgb,
+            src_alpha: factor(&definition.srcalpha, src_rgb)?,
+            dst_alpha: factor(&definition.dstalpha, dst_rgb)?,
+        })
+    }
+
+    // None when blending can be left disabled
+    pub fn state(&self) -> Option<BlendState> {
+        if *self == BlendMode::OPAQUE {
+            return None;
+        }
+        Some(BlendState {
+            equation: self.equation,
+            src_rgb: self.src_rgb,
+            dst_rgb: self.dst_rgb,
+            src_alpha: self.src_alpha,
+            dst_alpha: self.dst_alpha,
+        })
+    }
+}
+
+fn blend_equation(name: &str) -> Option<BlendEquationModeEXT> {
+    match name.trim().to_lowercase().replace('_', "").as_str() {
+        "add" => Some(GL_FUNC_ADD),
+        "subtract" => Some(GL_FUNC_SUBTRACT),
+        "reversesubtract" => Some(GL_FUNC_REVERSE_SUBTRACT),
+        "min" => Some(GL_MIN),
+        "max" => Some(GL_MAX),
+        _ => None,
+    }
+}
+
+fn blend_factor(name: &str) -> Option<BlendingFactor> {
+    let name = name
+        .trim()
+        .to_lowercase()
+        .replace('_', "")
+        .replace("one", "1")
+        .replace("zero", "0")
+        .replace("minus", "-");
+    match name.as_str() {
+        "0" => Some(GL_ZERO),
+        "1" => Some(GL_ONE),
+        "srccolor" => Some(GL_SRC_COLOR),
+        "1-srccolor" => Some(GL_ONE_MINUS_SRC_COLOR),
+        "dstcolor" => Some(GL_DST_COLOR),
+        "1-dstcolor" => Some(GL_ONE_MINUS_DST_COLOR),
+        "srcalpha" => Some(GL_SRC_ALPHA),
+        "1-srcalpha" => Some(GL_ONE_MINUS_SRC_ALPHA),
+        "dstalpha" => Some(GL_DST_ALPHA),
+        "1-dstalpha" => Some(GL_ONE_MINUS_DST_ALPHA),
+        _ => None,
+    }
+}
+
+fn shader_identifier(shader: &str, path: String) -> Result<Identifier, ShaderError> {
+    path.parse::<Identifier>()
+        .map_err(|source| ShaderError::Identifier {
+            shader: shader.to_string(),
+            source,
+        })
+}
+
+// Everything needed to build a shader, loaded without touching GL so it can
+// be prepared off the render thread
+#[derive(Debug, Clone)]
+pub struct ShaderSource {
+    pub name: String,
+    pub definition: ShaderDefinition,
+    pub blend: BlendMode,
+    pub vertex: GlslSource,
+    pub fragment: GlslSource,
+}
+
+impl ShaderSource {
+    const DIRECTORY: &'static str = "shaders/core";
+    // Programs used by post chain passes
+    pub const PROGRAM_DIRECTORY: &'static str = "shaders/program";
+
+    pub fn load(resources: &ResourceManager, name: &str) -> Result<ShaderSource, ShaderError> {
+        ShaderSource::load_in(resources, name, ShaderSource::DIRECTORY)
+    }
+
+    // Loads <directory>/<name>.json and the programs it names from the
+    // same directory
+    pub fn load_in(
+        resources: &ResourceManager,
+        name: &str,
+        directory: &str,
+    ) -> Result<ShaderSource, ShaderError> {
+        let location =
+            name.parse::<ResourceLocation>()
+                .map_err(|source| ShaderError::Identifier {
+                    shader: name.to_string(),
+                    source,
+                })?;
+        let path = format!(
+            "{}:{}/{}.json",
+            location.namespace(),
+            directory,
+            location.path()
+        );
+        let identifier = shader_identifier(name, path)?;
+        let resource_error = |source| ShaderError::Resource {
+            shader: name.to_string(),
+            source,
+        };
+        let definition: ShaderDefinition =
+            resources.get_json(identifier).map_err(resource_error)?;
+        for uniform in &definition.uniforms {
+            if !uniform.type_.supports_count(uniform.count) {
+                return Err(ShaderError::UnsupportedUniform {
+                    shader: name.to_string(),
+                    uniform: uniform.name.clone(),
+                    type_: uniform.type_,
+                    count: uniform.count,
+                });
+            }
+            if uniform.values.len() != uniform.count {
+                return Err(ShaderError::InvalidUniform {
+                    shader: name.to_string(),
+                    uniform: uniform.name.clone(),
+                    expected: uniform.count,
+                    actual: uniform.values.len(),
+                });
+            }
+        }
+        let blend = match &definition.blend {
+            Some(blend) => {
+                BlendMode::from_definition(blend).map_err(|value| ShaderError::InvalidBlend {
+                    shader: name.to_string(),
+                    value,
+                })?
+            }
+            None => BlendMode::OPAQUE,
+        };
+        let vertex = ShaderSource::load_program(
+            resources,
+            name,
+            directory,
+            &definition.vertex,
+            ShaderStage::Vertex,
+        )?;
+        let fragment = ShaderSource::load_program(
+            resources,
+            name,
+            directory,
+            &definition.fragment,
+            ShaderStage::Fragment,
+        )?;
+        Ok(ShaderSource {
+            name: name.to_string(),
+            definition,
+            blend,
+            vertex,
+            fragment,
+        })
+    }
+
+    // The format declared by the attributes of the definition
+    pub fn vertex_format(&self) -> Result<&'static VertexFormat, ShaderError> {
+        VertexFormatBuilder::from_attributes(&self.definition.attributes)
+            .and_then(VertexFormatBuilder::build)
+            .map_err(|source| ShaderError::VertexFormat {
+                shader: self.name.clone(),
+                source,
+            })
+    }
+
+    fn load_program(
+        resources: &ResourceManager,
+        shader: &str,
+        directory: &str,
+        program: &str,
+        stage: ShaderStage,
+    ) -> Result<GlslSource, ShaderError> {
+        let location =
+            program
+                .parse::<ResourceLocation>()
+                .map_err(|source| ShaderError::Identifier {
+                    shader: shader.to_string(),
+                    source,
+                })?;
+        let path = format!(
+            "{}:{}/{}.{}",
+            location.namespace(),
+            directory,
+            location.path(),
+            stage.extension()
+        );
+        let identifier = shader_identifier(shader, path)?;
+        let code = resources
+            .get_utf8(identifier)
+            .map_err(|source| ShaderError::Resource {
+                shader: shader.to_string(),
+                source,
+            })?;
+        preprocess(identifier, &code, |import| resources.get_utf8(import)).map_err(|source| {
+            ShaderError::Preprocess {
+                shader: shader.to_string(),
+                source,
+            }
+        })
+    }
+}
+
+struct Sampler {
+    uniform: Uniform,
+    texture: Option<GLuint>,
+}
+
+pub struct Shader {
+    pub name: String,
+    pub format: &'static VertexFormat,
+    pub blend: BlendMode,
+    backend: Rc<dyn GlBackend>,
+    program: GLuint,
+    samplers: Vec<Sampler>,
+    uniforms: Vec<Uniform>,
+    // Index into uniforms for each of StandardUniform::ALL the shader declares
+    standard: [Option<usize>; StandardUniform::ALL.len()],
+}
+
+impl Shader {
+    pub fn load(
+        gl: &GlStateManager,
+        resources: &ResourceManager,
+        name: &str,
+    ) -> Result<Shader, ShaderError> {
+        let source = ShaderSource::load(resources, name)?;
+        Shader::compile(gl, &source)
+    }
+
+    // The vertex format comes from the attributes of the definition, so
+    // shaders only declared in JSON get a format without any code
+    pub fn compile(gl: &GlStateManager, source: &ShaderSource) -> Result<Shader, ShaderError> {
+        let format = source.vertex_format()?;
+        let backend = gl.backend();
+        let definition = &source.definition;
+        let vertex = compile_stage(
+            &**backend,
+            source,
+            &definition.vertex,
+            ShaderStage::Vertex,
+            &source.vertex,
+        )?;
+        let fragment = match compile_stage(
+            &**backend,
+            source,
+            &definition.fragment,
+            ShaderStage::Fragment,
+            &source.fragment,
+        ) {
+            Ok(value) => value,
+            Err(err) => {
+                backend.delete_shader(vertex);
+                return Err(err);
+            }
+        };
+
+        let program = backend.create_program();
+        backend.attach_shader(program, vertex);
+        backend.attach_shader(program, fragment);
+        // Attribute locations match the element indices used by VertexFormatElement::start_drawing
+        for (index, name) in format.attr_names.iter().enumerate() {
+            backend.bind_attrib_location(program, index as GLuint, name);
+        }
+        backend.link_program(program);
+        backend.detach_shader(program, vertex);
+        backend.detach_shader(program, fragment);
+        backend.delete_shader(vertex);
+        backend.delete_shader(fragment);
+
+        if backend.get_program_i(program, GL_LINK_STATUS) == 0 {
+            let log = backend.program_info_log(program);
+            backend.delete_program(program);
+            return Err(ShaderError::Link {
+                shader: source.name.clone(),
+                log,
+            });
+        }
+
+        // Samplers read from the texture unit matching their declaration order
+        let samplers = definition
+            .samplers
+            .iter()
+            .enumerate()
+            .map(|(unit, sampler)| Sampler {
+                uniform: Uniform::sampler(
+                    &sampler.name,
+                    unit as i32,
+                    backend.get_uniform_location(program, &sampler.name),
+                ),
+                texture: None,
+            })
+            .collect();
+        let uniforms: Vec<Uniform> = definition
+            .uniforms
+            .iter()
+            .map(|uniform| {
+                Uniform::from_definition(
+                    uniform,
+                    backend.get_uniform_location(program, &uniform.name),
+                )
+            })
+            .collect();
+        let standard = StandardUniform::ALL.map(|standard| {
+            uniforms.iter().position(|uniform| {
+                uniform.name() == standard.name() && uniform.kind() == standard.kind()
+            })
+        });
+        Ok(Shader {
+            name: source.name.clone(),
+            format,
+            blend: source.blend,
+            backend: backend.clone(),
+            program,
+            samplers,
+            uniforms,
+            standard,
+        })
+    }
+
+    pub fn uniform(&mut self, name: &str) -> Option<&mut Uniform> {
+        self.uniforms
+            .iter_mut()
+            .find(|uniform| uniform.name() == name)
+    }
+
+    pub fn set_sampler(&mut self, name: &str, texture: GLuint) {
+        if let Some(sampler) = self
+            .samplers
+            .iter_mut()
+            .find(|sampler| sampler.uniform.name() == name)
+        {
+            sampler.texture = Some(texture);
+        }
+    }
+
+    pub fn set_standard_uniforms(&mut self, values: &StandardUniforms) {
+        for (standard, index) in StandardUniform::ALL.iter().zip(self.standard) {
+            if let Some(index) = index {
+                values.apply_to(*standard, &mut self.uniforms[index]);
+            }
+        }
+    }
+
+    // Uses the program, binds sampler textures and uploads any uniforms
+    // that changed since the last apply
+    pub fn apply(&mut self, gl: &mut GlStateManager) {
+        self.backend.use_program(self.program);
+        for (unit, sampler) in self.samplers.iter_mut().enumerate() {
+            if let Some(texture) = sampler.texture {
+                gl.set_active_texture(GLenum(GL_TEXTURE0.0 + unit as u32));
+                gl.bind_texture(GLenum(texture));
+            }
+            sampler.uniform.upload(&*self.backend)
+        }
+        gl.set_active_texture(GL_TEXTURE0);
+        for uniform in &mut self.uniforms {
+            uniform.upload(&*self.backend)
+        }
+    }
+
+    pub fn unbind(&self) {
+        self.backend.use_program(0);
+    }
+}
+
+// Core shaders reload on their own, post pass programs with their chain
+impl ReloadListener for Shader {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prepare(&self) -> PrepareTask {
+        let name = self.name.clone();
+        Box::new(move |resources| Ok(Box::new(ShaderSource::load(resources, &name)?) as Prepared))
+    }
+
+    fn apply(&mut self, gl: &mut GlStateManager, prepared: Prepared) {
+        let source = prepared.downcast::<ShaderSource>().unwrap();
+        match Shader::compile(gl, &source) {
+            Ok(shader) => *self = shader,
+            Err(err) => eprintln!("Keeping the old {} shader: {}", self.name, err),
+        }
+    }
+}
+
+impl Drop for Shader {
+    fn drop(&mut self) {
+        self.backend.delete_program(self.program)
+    }
+}
+
+fn compile_stage(
+    backend: &dyn GlBackend,
+    source: &ShaderSource,
+    program: &str,
+    stage: ShaderStage,
+    code: &GlslSource,
+) -> Result<GLuint, ShaderError> {
+    let shader = backend.create_shader(stage.gl());
+    backend.shader_source(shader, &code.code);
+    backend.compile_shader(shader);
+    if backend.get_shader_i(shader, GL_COMPILE_STATUS) == 0 {
+        let log = code.map_log(&backend.shader_info_log(shader));
+        backend.delete_shader(shader);
+        return Err(ShaderError::Compile {
+            shader: source.name.clone(),
+            program: program.to_string(),
+            stage,
+            log,
+        });
+    }
+    Ok(shader)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use ultraviolet::{IVec2, IVec3, IVec4, Mat3, Vec3, Vec4};
+
+    use super::*;
+    use crate::render::recording_gl::{GlCall, RecordingGl};
+
+    #[test]
+    fn derives_vertex_format_from_attributes() {
+        let resources = ResourceManager::new();
+        let source = ShaderSource::load(&resources, "blit_screen").unwrap();
+        assert_eq!(source.vertex_format().unwrap(), VertexFormat::BLIT_SCREEN);
+    }
+
+    #[test]
+    fn uploads_changed_standard_uniforms() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let resources = ResourceManager::new();
+        let mut shader = Shader::load(&gl, &resources, "blit_screen").unwrap();
+        let mut uniforms = StandardUniforms::new();
+        uniforms.color_modulator = Vec4::new(0.5, 0.25, 1.0, 1.0);
+        shader.set_standard_uniforms(&uniforms);
+        backend.take_calls();
+        shader.apply(&mut gl);
+        let modulator = [0.5f32, 0.25, 1.0, 1.0].map(|value| value.to_bits() as i64);
+        assert!(backend
+            .take_calls()
+            .iter()
+            .any(|call| call.function == "glUniformfv" && call.args[1..] == modulator));
+
+        // Nothing changed, so the next frame uploads nothing
+        shader.set_standard_uniforms(&uniforms);
+        shader.apply(&mut gl);
+        assert_eq!(backend.count("glUniformfv"), 0);
+        assert_eq!(backend.count("glUniformMatrixfv"), 0);
+    }
+
+    #[test]
+    fn uploads_only_uniforms_that_changed_since_the_last_apply() {
+        let backend = Rc::new(RecordingGl::new());
+        let mut gl = GlStateManager::new(backend.clone());
+        let definition = serde_json::from_str(
+            r#"{
+                "vertex": "test", "fragment": "test", "attributes": ["Position"],
+                "samplers": [{"name": "Sampler0"}],
+                "uniforms": [
+                    {"name": "Count", "type": "int", "count": 1, "values": [0]},
+                    {"name": "Offset", "type": "int", "count": 2, "values": [0, 0]},
+                    {"name": "Cell", "type": "int", "count": 3, "values": [0, 0, 0]},
+                    {"name": "Mask", "type": "int", "count": 4, "values": [0, 0, 0, 0]},
+                    {"name": "Light", "type": "float", "count": 3, "values": [0, 0, 0]},
+                    {"name": "Normal", "type": "matrix3x3", "count": 9,
+                        "values": [1, 0, 0, 0, 1, 0, 0, 0, 1]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let empty = || GlslSource {
+            code: String::new(),
+            files: Vec::new(),
+        };
+        let source = ShaderSource {
+            name: "test".to_string(),
+            definition,
+            blend: BlendMode::OPAQUE,
+            vertex: empty(),
+            fragment: empty(),
+        };
+        let mut shader = Shader::compile(&gl, &source).unwrap();
+        // Everything starts dirty so the first apply uploads the defaults
+        backend.take_calls();
+        shader.apply(&mut gl);
+        assert_eq!(backend.count("glUniformiv"), 5);
+        assert_eq!(backend.count("glUniformfv"), 1);
+        assert_eq!(backend.count("glUniformMatrixfv"), 1);
+        backend.take_calls();
+
+        // Setting the values they already have uploads nothing
+        shader.uniform("Count").unwrap().set_int(0);
+        shader.uniform("Offset").unwrap().set_ivec2(IVec2::zero());
+        shader
+            .uniform("Normal")
+            .unwrap()
+            .set_mat3(&Mat3::identity());
+        shader.apply(&mut gl);
+        let uploads = |calls: Vec<GlCall>| -> Vec<(&str, usize)> {
+            calls
+                .into_iter()
+                .filter(|call| call.function.starts_with("glUniform"))
+                .map(|call| (call.function, call.args.len() - 1))
+                .collect()
+        };
+        assert_eq!(uploads(backend.take_calls()), []);
+
+        shader
+            .uniform("Cell")
+            .unwrap()
+            .set_ivec3(IVec3::new(1, 2, 3));
+        shader
+            .uniform("Mask")
+            .unwrap()
+            .set_ivec4(IVec4::new(0, 0, 0, 0));
+        shader.uniform("Light").unwrap().set_vec3(Vec3::unit_y());
+        shader
+            .uniform("Normal")
+            .unwrap()
+            .set_mat3(&(Mat3::identity() * 2.0));
+        shader.apply(&mut gl);
+        assert_eq!(
+            uploads(backend.take_calls()),
+            [
+                ("glUniformiv", 3),
+                ("glUniformfv", 3),
+                ("glUniformMatrixfv", 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_compile_and_link_errors() {
+        let backend = Rc::new(RecordingGl::new());
+        let gl = GlStateManager::new(backend.clone());
+        let resources = ResourceManager::new();
+        backend.set_compile_status(false);
+        let err = Shader::load(&gl, &resources, "blit_screen").err().unwrap();
+        assert!(matches!(
+            err,
+            ShaderError::Compile {
+                stage: ShaderStage::Vertex,
+                ..
+            }
+        ));
+        // Nothing is left behind when compiling fails
+        assert_eq!(
+            backend.count("glCreateShader"),
+            backend.count("glDeleteShader")
+        );
 
-struct Shader {
-    name: String,
-    format: String,
-}
\ No newline at end of file
+        backend.set_compile_status(true);
+        backend.set_link_status(false);
+        let err = Shader::load(&gl, &resources, "blit_screen").err().unwrap();
+        assert!(matches!(err, ShaderError::Link { .. }));
+        assert_eq!(
+            backend.count("glCreateShader"),
+            backend.count("glDeleteShader")
+        );
+        assert_eq!(backend.count("glDeleteProgram"), 1);
+    }
+}
@@ -0,0 +1,194 @@
+use gl33::*;
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+use thiserror::Error;
+
+use crate::render::util::GlStateManager;
+use crate::resources::missing_texture;
+use crate::types::{GLint, GLsizei};
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("invalid png: {0}")]
+    Decode(#[from] png::DecodingError),
+    #[error("unsupported png color type {0:?}")]
+    UnsupportedColor(ColorType),
+    #[error("unable to encode png: {0}")]
+    Encode(#[from] png::EncodingError),
+}
+
+// RGBA pixels with 8 bits per channel, rows stored top to bottom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl NativeImage {
+    pub fn new(width: u32, height: u32) -> NativeImage {
+        NativeImage {
+            width,
+            height,
+            pixels: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn missing(width: u32, height: u32) -> NativeImage {
+        NativeImage {
+            width,
+            height,
+            pixels: missing_texture(width, height),
+        }
+    }
+
+    pub fn from_pixels(width: u32, height: u32, pixels: Vec<u8>) -> NativeImage {
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        NativeImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    // Decodes any png, palette, grayscale and 16 bit images are converted to RGBA
+    pub fn decode(data: &[u8]) -> Result<NativeImage, ImageError> {
+        let mut decoder = Decoder::new(data);
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+        buffer.truncate(info.line_size * info.height as usize);
+        let pixels = match info.color_type {
+            ColorType::Rgba => buffer,
+            ColorType::Rgb => buffer
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+                .collect(),
+            ColorType::GrayscaleAlpha => buffer
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            ColorType::Grayscale => buffer.iter().flat_map(|p| [*p, *p, *p, 0xFF]).collect(),
+            value => return Err(ImageError::UnsupportedColor(value)),
+        };
+        Ok(NativeImage {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        ((y * self.width + x) * 4) as usize
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let index = self.index(x, y);
+        self.pixels[index..index + 4].try_into().unwrap()
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: [u8; 4]) {
+        let index = self.index(x, y);
+        self.pixels[index..index + 4].copy_from_slice(&value);
+    }
+
+    // Copies all of source into this image with its top left corner at x, y
+    pub fn copy_from(&mut self, source: &NativeImage, x: u32, y: u32) {
+        let row_length = (source.width * 4) as usize;
+        for row in 0..source.height {
+            let from = source.index(0, row);
+            let to = self.index(x, y + row);
+            self.pixels[to..to + row_length]
+                .copy_from_slice(&source.pixels[from..from + row_length]);
+        }
+    }
+
+    // Swaps rows top to bottom, GL reads images bottom row first
+    pub fn flip_vertical(&mut self) {
+        let row_length = (self.width * 4) as usize;
+        let (mut top, mut bottom) = (0, self.height as usize);
+        while top + 1 < bottom {
+            bottom -= 1;
+            let (upper, lower) = self.pixels.split_at_mut(bottom * row_length);
+            upper[top * row_length..(top + 1) * row_length]
+                .swap_with_slice(&mut lower[..row_length]);
+            top += 1;
+        }
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, ImageError> {
+        let mut data = Vec::new();
+        let mut encoder = Encoder::new(&mut data, self.width, self.height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(data)
+    }
+
+    pub fn sub_image(&self, x: u32, y: u32, width: u32, height: u32) -> NativeImage {
+        let mut image = NativeImage::new(width, height);
+        let row_length = (width * 4) as usize;
+        for row in 0..height {
+            let from = self.index(x, y + row);
+            let to = image.index(0, row);
+            image.pixels[to..to + row_length]
+                .copy_from_slice(&self.pixels[from..from + row_length]);
+        }
+        image
+    }
+
+    // Allocates and fills a mip level of the bound texture
+    pub fn upload(&self, gl: &mut GlStateManager, level: GLint) {
+        gl.backend().tex_image_2d(
+            GL_TEXTURE_2D,
+            level,
+            GL_RGBA8.0 as GLint,
+            self.width as GLsizei,
+            self.height as GLsizei,
+            GL_RGBA,
+            GL_UNSIGNED_BYTE,
+            Some(&self.pixels),
+        );
+    }
+
+    // Replaces part of a mip level of the bound texture
+    pub fn upload_sub(&self, gl: &mut GlStateManager, level: GLint, x: GLint, y: GLint) {
+        gl.backend().tex_sub_image_2d(
+            GL_TEXTURE_2D,
+            level,
+            x,
+            y,
+            self.width as GLsizei,
+            self.height as GLsizei,
+            GL_RGBA,
+            GL_UNSIGNED_BYTE,
+            &self.pixels,
+        );
+    }
+}
+
+// Sets the filtering of the bound texture. Mipmapped textures stay sharp up
+// close and blend between the two nearest levels further away
+pub fn set_mipmap_filter(gl: &mut GlStateManager, levels: u32) {
+    let min_filter = if levels > 0 {
+        GL_NEAREST_MIPMAP_LINEAR
+    } else {
+        GL_NEAREST
+    };
+    let backend = gl.backend();
+    backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, min_filter.0 as GLint);
+    backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_NEAREST.0 as GLint);
+    backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAX_LEVEL, levels as GLint);
+    backend.tex_parameter_f(GL_TEXTURE_2D, GL_TEXTURE_MIN_LOD, 0.0);
+    backend.tex_parameter_f(GL_TEXTURE_2D, GL_TEXTURE_MAX_LOD, levels as f32);
+}
@@ -0,0 +1,306 @@
+use ultraviolet::{IVec2, IVec3, IVec4, Mat3, Mat4, Vec2, Vec3, Vec4};
+
+use crate::render::backend::GlBackend;
+use crate::render::shader::{UniformDefinition, UniformType};
+use crate::types::GLint;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UniformKind {
+    // Number of components, 1 to 4
+    Int(usize),
+    Float(usize),
+    Mat2,
+    Mat3,
+    Mat4,
+    // Texture unit the sampler reads from
+    Sampler,
+}
+
+impl UniformKind {
+    fn from_definition(type_: UniformType, count: usize) -> UniformKind {
+        match type_ {
+            UniformType::Int => UniformKind::Int(count),
+            UniformType::Float => UniformKind::Float(count),
+            UniformType::Matrix2x2 => UniformKind::Mat2,
+            UniformType::Matrix3x3 => UniformKind::Mat3,
+            UniformType::Matrix4x4 => UniformKind::Mat4,
+        }
+    }
+
+    fn is_int(&self) -> bool {
+        matches!(self, UniformKind::Int(_) | UniformKind::Sampler)
+    }
+}
+
+// A shader uniform that remembers its last value and only uploads
+// when that value changes
+#[derive(Debug)]
+pub struct Uniform {
+    name: String,
+    kind: UniformKind,
+    location: GLint,
+    ints: Vec<i32>,
+    floats: Vec<f32>,
+    dirty: bool,
+}
+
+impl Uniform {
+    pub fn from_definition(definition: &UniformDefinition, location: GLint) -> Uniform {
+        let kind = UniformKind::from_definition(definition.type_, definition.count);
+        let (ints, floats) = if kind.is_int() {
+            (
+                definition.values.iter().map(|v| *v as i32).collect(),
+                Vec::new(),
+            )
+        } else {
+            (Vec::new(), definition.values.clone())
+        };
+        Uniform {
+            name: definition.name.clone(),
+            kind,
+            location,
+            ints,
+            floats,
+            dirty: true,
+        }
+    }
+
+    pub fn sampler(name: &str, unit: i32, location: GLint) -> Uniform {
+        Uniform {
+            name: name.to_string(),
+            kind: UniformKind::Sampler,
+            location,
+            ints: vec![unit],
+            floats: Vec::new(),
+            dirty: true,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> UniformKind {
+        self.kind
+    }
+
+    pub fn set_ints(&mut self, values: &[i32]) {
+        let valid = self.kind.is_int() && self.ints.len() == values.len();
+        debug_assert!(
+            valid,
+            "uniform {} is {:?} but was given {} ints",
+            self.name,
+            self.kind,
+            values.len()
+        );
+        if !valid {
+            return;
+        }
+        if self.ints != values {
+            self.ints.clear();
+            self.ints.extend_from_slice(values);
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_floats(&mut self, values: &[f32]) {
+        let valid = !self.kind.is_int() && self.floats.len() == values.len();
+        debug_assert!(
+            valid,
+            "uniform {} is {:?} but was given {} floats",
+            self.name,
+            self.kind,
+            values.len()
+        );
+        if !valid {
+            return;
+        }
+        if self.floats != values {
+            self.floats.clear();
+            self.floats.extend_from_slice(values);
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_float(&mut self, value: f32) {
+        self.set_floats(&[value]);
+    }
+
+    pub fn set_vec2(&mut self, value: Vec2) {
+        self.set_floats(&[value.x, value.y]);
+    }
+
+    pub fn set_vec4(&mut self, value: Vec4) {
+        self.set_floats(&[value.x, value.y, value.z, value.w]);
+    }
+
+    pub fn set_mat4(&mut self, value: &Mat4) {
+        self.set_floats(value.as_slice());
+    }
+
+    // Uploads the value if it changed since the last upload, the
+    // owning program must be in use
+    pub fn upload(&mut self, backend: &dyn GlBackend) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if self.location == -1 {
+            return;
+        }
+        let location = self.location;
+        match self.kind {
+            UniformKind::Int(size) => backend.uniform_i(location, size, &self.ints),
+            UniformKind::Sampler => backend.uniform_i(location, 1, &self.ints),
+            UniformKind::Float(size) => backend.uniform_f(location, size, &self.floats),
+            UniformKind::Mat2 => backend.uniform_matrix(location, 2, &self.floats),
+            UniformKind::Mat3 => backend.uniform_matrix(location, 3, &self.floats),
+            UniformKind::Mat4 => backend.uniform_matrix(location, 4, &self.floats),
+        }
+    }
+}
+
+// Typed setters for the kinds shaders may declare that none of the
+// renderer's own uniforms use yet
+#[allow(dead_code)]
+impl Uniform {
+    pub fn set_int(&mut self, value: i32) {
+        self.set_ints(&[value]);
+    }
+
+    pub fn set_ivec2(&mut self, value: IVec2) {
+        self.set_ints(&[value.x, value.y]);
+    }
+
+    pub fn set_ivec3(&mut self, value: IVec3) {
+        self.set_ints(&[value.x, value.y, value.z]);
+    }
+
+    pub fn set_ivec4(&mut self, value: IVec4) {
+        self.set_ints(&[value.x, value.y, value.z, value.w]);
+    }
+
+    pub fn set_vec3(&mut self, value: Vec3) {
+        self.set_floats(&[value.x, value.y, value.z]);
+    }
+
+    pub fn set_mat3(&mut self, value: &Mat3) {
+        self.set_floats(value.as_slice());
+    }
+
+    pub fn set_sampler(&mut self, unit: i32) {
+        self.set_ints(&[unit]);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StandardUniform {
+    ModelViewMat,
+    ProjMat,
+    ColorModulator,
+    FogStart,
+    FogEnd,
+    FogColor,
+    GameTime,
+    ScreenSize,
+}
+
+impl StandardUniform {
+    pub const ALL: [StandardUniform; 8] = [
+        StandardUniform::ModelViewMat,
+        StandardUniform::ProjMat,
+        StandardUniform::ColorModulator,
+        StandardUniform::FogStart,
+        StandardUniform::FogEnd,
+        StandardUniform::FogColor,
+        StandardUniform::GameTime,
+        StandardUniform::ScreenSize,
+    ];
+
+    // Shaders declaring a standard uniform with a different kind don't receive it
+    pub fn kind(&self) -> UniformKind {
+        match self {
+            StandardUniform::ModelViewMat | StandardUniform::ProjMat => UniformKind::Mat4,
+            StandardUniform::ColorModulator | StandardUniform::FogColor => UniformKind::Float(4),
+            StandardUniform::FogStart | StandardUniform::FogEnd | StandardUniform::GameTime => {
+                UniformKind::Float(1)
+            }
+            StandardUniform::ScreenSize => UniformKind::Float(2),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StandardUniform::ModelViewMat => "ModelViewMat",
+            StandardUniform::ProjMat => "ProjMat",
+            StandardUniform::ColorModulator => "ColorModulator",
+            StandardUniform::FogStart => "FogStart",
+            StandardUniform::FogEnd => "FogEnd",
+            StandardUniform::FogColor => "FogColor",
+            StandardUniform::GameTime => "GameTime",
+            StandardUniform::ScreenSize => "ScreenSize",
+        }
+    }
+}
+
+// Values of the uniforms shared by every shader, updated by the renderer
+// each frame and copied into shaders before they are applied
+#[derive(Debug, Clone)]
+pub struct StandardUniforms {
+    pub model_view_mat: Mat4,
+    pub proj_mat: Mat4,
+    pub color_modulator: Vec4,
+    pub fog_start: f32,
+    pub fog_end: f32,
+    pub fog_color: Vec4,
+    pub game_time: f32,
+    pub screen_size: Vec2,
+}
+
+impl StandardUniforms {
+    pub fn new() -> StandardUniforms {
+        StandardUniforms {
+            model_view_mat: Mat4::identity(),
+            proj_mat: Mat4::identity(),
+            color_modulator: Vec4::one(),
+            fog_start: 0.0,
+            fog_end: 1.0,
+            fog_color: Vec4::zero(),
+            game_time: 0.0,
+            screen_size: Vec2::zero(),
+        }
+    }
+
+    pub fn apply_to(&self, standard: StandardUniform, uniform: &mut Uniform) {
+        match standard {
+            StandardUniform::ModelViewMat => uniform.set_mat4(&self.model_view_mat),
+            StandardUniform::ProjMat => uniform.set_mat4(&self.proj_mat),
+            StandardUniform::ColorModulator => uniform.set_vec4(self.color_modulator),
+            StandardUniform::FogStart => uniform.set_float(self.fog_start),
+            StandardUniform::FogEnd => uniform.set_float(self.fog_end),
+            StandardUniform::FogColor => uniform.set_vec4(self.fog_color),
+            StandardUniform::GameTime => uniform.set_float(self.game_time),
+            StandardUniform::ScreenSize => uniform.set_vec2(self.screen_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+
+    #[test]
+    fn uploads_samplers_only_when_their_unit_changes() {
+        let backend = RecordingGl::new();
+        let mut sampler = Uniform::sampler("Sampler0", 0, 3);
+        sampler.upload(&backend);
+        sampler.set_sampler(0);
+        sampler.upload(&backend);
+        sampler.set_sampler(2);
+        sampler.upload(&backend);
+        let calls = backend.take_calls();
+        let uploads: Vec<_> = calls.iter().map(|call| call.args.clone()).collect();
+        assert_eq!(uploads, [vec![3, 0], vec![3, 2]]);
+    }
+}
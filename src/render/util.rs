@@ -1,49 +1,80 @@
+use std::cell::RefCell;
 use std::cmp::max;
-use std::os::raw::c_uint;
-use std::ptr::null;
+use std::ffi::{c_void, CStr};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::rc::{Rc, Weak};
 
+use gl33::global_loader::load_global_gl;
 use gl33::*;
-use gl33::global_loader::*;
-use glutin::dpi::Pixel;
 
-use crate::types::{GLboolean, GLfloat, GLint, GLsizei, GLuint, GLvoid};
+use crate::render::backend::{GlBackend, NativeGl};
+use crate::render::index_buffer::SharedIndexBuffers;
+use crate::types::{GLboolean, GLfloat, GLint, GLsizei, GLuint};
 
-pub struct CapTracker(EnableCap, bool);
+// Proof that a GL context is current on this thread with its functions
+// loaded. Neither Send nor Sync, so anything holding one stays on the GL thread
+#[derive(Debug, Clone, Copy)]
+pub struct GlThread(PhantomData<*const ()>);
+
+impl GlThread {
+    // Safety: a GL context must be current on the calling thread and the
+    // global loader must have been loaded from it
+    pub unsafe fn assume_current() -> GlThread {
+        GlThread(PhantomData)
+    }
+}
+
+// Loads the GL functions of the current context and starts tracking its state.
+// Safety: as for GlThread::assume_current, with get_proc_address from that context
+pub unsafe fn load_gl(get_proc_address: impl Fn(&str) -> *const c_void) -> GlStateManager {
+    load_global_gl(&|ptr| {
+        let name = CStr::from_ptr(ptr as *const i8);
+        get_proc_address(name.to_str().unwrap()) as _
+    });
+    GlStateManager::new(Rc::new(NativeGl::new(GlThread::assume_current())))
+}
+
+#[derive(Clone)]
+struct CapTracker(EnableCap, bool);
 
 impl CapTracker {
-    pub fn set_state(&mut self, state: bool) {
+    fn set_state(&mut self, backend: &dyn GlBackend, state: bool) {
         if state != self.1 {
             self.1 = state;
-            unsafe {
-                if state {
-                    glEnable(self.0)
-                } else {
-                    glDisable(self.0)
-                }
+            if state {
+                backend.enable(self.0)
+            } else {
+                backend.disable(self.0)
             }
         }
     }
 }
 
+#[derive(Clone)]
 struct DepthTestState {
     cap: CapTracker,
     mask: bool,
     func: DepthFunction,
 }
 
+#[derive(Clone)]
 struct BlendFuncState {
     cap: CapTracker,
+    equation: BlendEquationModeEXT,
     src_factor_rgb: BlendingFactor,
     dst_factor_rgb: BlendingFactor,
     src_factor_alpha: BlendingFactor,
     dst_factor_alpha: BlendingFactor,
 }
 
+#[derive(Clone)]
 struct CullFaceState {
     cap: CapTracker,
     mode: CullFaceMode,
 }
 
+#[derive(Clone)]
 struct PolygonOffsetState {
     cap_fill: CapTracker,
     cap_line: CapTracker,
@@ -51,6 +82,7 @@ struct PolygonOffsetState {
     units: GLfloat,
 }
 
+#[derive(Clone)]
 struct LogicOpState {
     cap: CapTracker,
     op: LogicOp,
@@ -58,17 +90,19 @@ struct LogicOpState {
 
 #[derive(Debug, Clone, Copy)]
 struct Texture2DState {
-    cap_state: bool,
     bound: Option<TextureUnit>,
 }
 
+#[derive(Clone)]
 struct StencilSubState {
     func: StencilFunction,
     ref_: GLint,
     mask: GLuint,
 }
 
+#[derive(Clone)]
 struct StencilState {
+    cap: CapTracker,
     sub_state: StencilSubState,
     mask: GLuint,
     sfail: StencilOp,
@@ -76,6 +110,7 @@ struct StencilState {
     dppass: StencilOp,
 }
 
+#[derive(PartialEq, Eq)]
 struct Viewport {
     x: GLint,
     y: GLint,
@@ -86,280 +121,693 @@ struct Viewport {
 // red, green, blue, alpha
 pub struct ColorMask(GLboolean, GLboolean, GLboolean, GLboolean);
 
-static mut ACTIVE_TEXTURE: usize = 0;
-static mut TEXTURES: [Texture2DState; 12] = [Texture2DState { cap_state: false, bound: Some(GL_ZERO) }; 12];
-static mut MAX_SUPPORTED_TEXTURE_SIZE: Option<GLsizei> = None;
-
-static mut SCISSOR_TEST_STATE: CapTracker = CapTracker(GL_SCISSOR_TEST, false);
-static mut DEPTH_TEST_STATE: DepthTestState = DepthTestState {
-    cap: CapTracker(GL_DEPTH_TEST, false),
-    mask: true,
-    func: GL_LESS,
-};
-static mut BLEND_FUNC_STATE: BlendFuncState = BlendFuncState {
-    cap: CapTracker(GL_BLEND, false),
-    src_factor_rgb: GL_ONE,
-    dst_factor_rgb: GL_ZERO,
-    src_factor_alpha: GL_ONE,
-    dst_factor_alpha: GL_ZERO,
-};
-static mut CULL_FACE_STATE: CullFaceState = CullFaceState {
-    cap: CapTracker(GL_CULL_FACE, false),
-    mode: GL_BACK,
-};
-static mut POLYGON_OFFSET_STATE: PolygonOffsetState = PolygonOffsetState {
-    cap_fill: CapTracker(GL_POLYGON_OFFSET_FILL, false),
-    cap_line: CapTracker(GL_POLYGON_OFFSET_LINE, false),
-    factor: 0.0,
-    units: 0.0,
-};
-static mut LOGIC_OP_STATE: LogicOpState = LogicOpState {
-    cap: CapTracker(GL_LOGIC_OP_MODE, false),
-    op: GL_COPY,
-};
-
-static mut STENCIL_STATE: StencilState = StencilState {
-    sub_state: StencilSubState {
-        func: GL_ALWAYS,
-        ref_: 0,
-        mask: 0,
-    },
-    mask: 0,
-    sfail: GL_KEEP,
-    dpfail: GL_KEEP,
-    dppass: GL_KEEP,
-};
-static mut VIEWPORT: Viewport = Viewport {
-    x: 0,
-    y: 0,
-    width: 0,
-    height: 0,
-};
-static mut COLOR_MASK: ColorMask = ColorMask(1, 1, 1, 1);
-
-pub fn gen_texture_id() -> TextureUnit {
-    let mut value = 0;
-    unsafe { glGenTextures(1, &mut value); }
-    GLenum(value)
+// Mirror of the GL state of one context. Calls that would not change the
+// state are skipped, so everything that changes tracked state has to go
+// through here
+pub struct GlStateManager {
+    backend: Rc<dyn GlBackend>,
+    active_texture: usize,
+    textures: [Texture2DState; 12],
+    max_supported_texture_size: Option<GLsizei>,
+    scissor_test: CapTracker,
+    depth_test: DepthTestState,
+    blend_func: BlendFuncState,
+    cull_face: CullFaceState,
+    polygon_offset: PolygonOffsetState,
+    logic_op: LogicOpState,
+    stencil: StencilState,
+    viewport: Viewport,
+    color_mask: ColorMask,
+    // Pushed by push_render_state, popped when its guard drops
+    saved_states: Vec<SavedState>,
+    // Queued by TextureDeleters, deleted before the next texture is made or bound
+    deleted_textures: Rc<RefCell<Vec<TextureUnit>>>,
+    // Shared with every VertexBuffer of this context
+    shared_indices: Rc<RefCell<SharedIndexBuffers>>,
 }
 
-pub fn bind_texture(texture: TextureUnit) {
-    let at = unsafe { &mut TEXTURES[ACTIVE_TEXTURE] };
-    if let Some(value) = at.bound {
-        if texture != value {
+impl GlStateManager {
+    // Starts from the defaults of a freshly created context
+    pub fn new(backend: Rc<dyn GlBackend>) -> GlStateManager {
+        GlStateManager {
+            backend,
+            active_texture: 0,
+            textures: [Texture2DState { bound: Some(GL_ZERO) }; 12],
+            max_supported_texture_size: None,
+            scissor_test: CapTracker(GL_SCISSOR_TEST, false),
+            depth_test: DepthTestState {
+                cap: CapTracker(GL_DEPTH_TEST, false),
+                mask: true,
+                func: GL_LESS,
+            },
+            blend_func: BlendFuncState {
+                cap: CapTracker(GL_BLEND, false),
+                equation: GL_FUNC_ADD,
+                src_factor_rgb: GL_ONE,
+                dst_factor_rgb: GL_ZERO,
+                src_factor_alpha: GL_ONE,
+                dst_factor_alpha: GL_ZERO,
+            },
+            cull_face: CullFaceState {
+                cap: CapTracker(GL_CULL_FACE, false),
+                mode: GL_BACK,
+            },
+            polygon_offset: PolygonOffsetState {
+                cap_fill: CapTracker(GL_POLYGON_OFFSET_FILL, false),
+                cap_line: CapTracker(GL_POLYGON_OFFSET_LINE, false),
+                factor: 0.0,
+                units: 0.0,
+            },
+            logic_op: LogicOpState {
+                cap: CapTracker(GL_COLOR_LOGIC_OP, false),
+                op: GL_COPY,
+            },
+            stencil: StencilState {
+                cap: CapTracker(GL_STENCIL_TEST, false),
+                sub_state: StencilSubState {
+                    func: GL_ALWAYS,
+                    ref_: 0,
+                    mask: !0,
+                },
+                mask: !0,
+                sfail: GL_KEEP,
+                dpfail: GL_KEEP,
+                dppass: GL_KEEP,
+            },
+            viewport: Viewport {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+            },
+            color_mask: ColorMask(1, 1, 1, 1),
+            saved_states: Vec::new(),
+            deleted_textures: Rc::new(RefCell::new(Vec::new())),
+            shared_indices: Rc::new(RefCell::new(SharedIndexBuffers::new())),
+        }
+    }
+
+    // For objects that make their own calls and have to delete themselves
+    // when dropped
+    pub fn backend(&self) -> &Rc<dyn GlBackend> {
+        &self.backend
+    }
+
+    // For objects owning textures that they delete when dropped
+    pub fn texture_deleter(&self) -> TextureDeleter {
+        TextureDeleter {
+            backend: self.backend.clone(),
+            queue: Rc::downgrade(&self.deleted_textures),
+        }
+    }
+
+    // For vertex buffers drawing with sequential indices
+    pub fn shared_indices(&self) -> Rc<RefCell<SharedIndexBuffers>> {
+        self.shared_indices.clone()
+    }
+
+    // Has to be called before the context goes away, the buffers are
+    // created again if anything draws afterwards
+    pub fn delete_shared_indices(&mut self) {
+        self.shared_indices.borrow_mut().delete(&*self.backend);
+    }
+
+    fn delete_queued_textures(&mut self) {
+        let textures = std::mem::take(&mut *self.deleted_textures.borrow_mut());
+        if !textures.is_empty() {
+            self.delete_textures(&textures);
+        }
+    }
+
+    pub fn gen_texture(&mut self) -> TextureUnit {
+        // Queued ids are deleted first so one handed out again isn't still
+        // recorded as bound
+        self.delete_queued_textures();
+        GLenum(self.backend.gen_texture())
+    }
+
+    pub fn bind_texture(&mut self, texture: TextureUnit) {
+        self.delete_queued_textures();
+        let at = &mut self.textures[self.active_texture];
+        if at.bound != Some(texture) {
             at.bound = Some(texture);
-            unsafe { glBindTexture(GL_TEXTURE_2D, texture.0) }
+            self.backend.bind_texture(GL_TEXTURE_2D, texture.0)
         }
-    } else {
-        at.bound = Some(texture);
-        unsafe { glBindTexture(GL_TEXTURE_2D, texture.0) }
     }
-}
 
-pub unsafe fn delete_texture(texture: TextureUnit) {
-    glDeleteTextures(1, &texture.0);
-    TEXTURES.iter_mut().for_each(|v| {
-        if let Some(value) = v.bound {
-            if value == texture {
-                v.bound = None
+    fn forget_textures(&mut self, textures: &[TextureUnit]) {
+        for state in &mut self.textures {
+            if let Some(value) = state.bound {
+                if textures.contains(&value) {
+                    state.bound = None
+                }
             }
         }
-    });
-}
+    }
 
-pub unsafe fn delete_textures(textures: Vec<TextureUnit>) {
-    TEXTURES.iter_mut().for_each(|v| {
-        if let Some(value) = v.bound {
-            textures.iter().for_each(|t| {
-                if value.0 == t.0 {
-                    v.bound = None
-                }
-            });
+    pub fn delete_textures(&mut self, textures: &[TextureUnit]) {
+        self.forget_textures(textures);
+        let va: Vec<GLuint> = textures.iter().map(|v| v.0).collect();
+        self.backend.delete_textures(&va);
+    }
+
+    pub fn set_active_texture(&mut self, texture: TextureUnit) {
+        let ts = (texture.0 - GL_TEXTURE0.0) as usize;
+        if self.active_texture != ts {
+            self.active_texture = ts;
+            self.backend.active_texture(texture)
         }
-    });
-    let va: Vec<GLuint> = textures.iter().map(|v| v.0).collect();
-    glDeleteTextures(va.len() as GLsizei, va.as_ptr());
-}
+    }
 
-pub unsafe fn set_active_texture(texture: TextureUnit) {
-    let ts = (texture.0 - 0x84C0 /* GL_TEXTURE0 */) as usize;
-    if ACTIVE_TEXTURE != ts {
-        ACTIVE_TEXTURE = ts;
-        glActiveTexture(texture);
+    pub fn max_supported_texture_size(&mut self) -> GLsizei {
+        if let Some(value) = self.max_supported_texture_size {
+            return value;
+        }
+        let max_size = self.backend.get_integer(GL_MAX_TEXTURE_SIZE);
+        let mut i = max(32768, max_size);
+        while i >= 1024 {
+            self.backend.tex_image_2d(GL_PROXY_TEXTURE_2D, 0, 0x1908, i, i, GL_RGBA, GL_UNSIGNED_BYTE, None);
+            let width = self.backend.get_tex_level_parameter_i(GL_PROXY_TEXTURE_2D, 0, GL_TEXTURE_WIDTH);
+            if width != 0 {
+                i = width;
+                break;
+            }
+            i >>= 1;
+        }
+
+        i = max(i, 1024);
+        self.max_supported_texture_size = Some(i);
+        i
     }
-}
 
-pub unsafe fn enable_texture() {
-    TEXTURES[ACTIVE_TEXTURE].cap_state = true;
-}
+    #[inline]
+    pub fn disable_scissor_test(&mut self) { self.scissor_test.set_state(&*self.backend, false) }
 
-pub unsafe fn disable_texture() {
-    TEXTURES[ACTIVE_TEXTURE].cap_state = false;
-}
+    #[inline]
+    pub fn enable_scissor_test(&mut self) { self.scissor_test.set_state(&*self.backend, true) }
 
-pub unsafe fn max_supported_texture_size() -> GLsizei {
-    match MAX_SUPPORTED_TEXTURE_SIZE {
-        None => {
-            let mut max_size = 0;
-            unsafe { glGetIntegerv(GL_MAX_TEXTURE_SIZE, &mut max_size); }
-            let mut i = max(32768, max_size);
-            while i >= 1024 {
-                unsafe { glTexImage2D(GL_PROXY_TEXTURE_2D, 0, 0x1908, i, i, 0, GL_RGBA, GL_UNSIGNED_BYTE, null()); }
-                let mut width = 0;
-                unsafe { glGetTexLevelParameteriv(GL_PROXY_TEXTURE_2D, 0, GL_TEXTURE_WIDTH, &mut width); }
-                if width != 0 {
-                    i = width;
-                    break;
-                }
-                i >>= 1;
-            }
+    #[inline]
+    pub fn disable_depth_test(&mut self) { self.depth_test.cap.set_state(&*self.backend, false) }
 
-            i = max(i, 1024);
-            MAX_SUPPORTED_TEXTURE_SIZE = Some(i);
-            i
+    #[inline]
+    pub fn enable_depth_test(&mut self) { self.depth_test.cap.set_state(&*self.backend, true) }
+
+    pub fn depth_func(&mut self, func: DepthFunction) {
+        if func != self.depth_test.func {
+            self.depth_test.func = func;
+            self.backend.depth_func(func)
         }
-        Some(value) => value
     }
-}
 
-#[inline]
-pub unsafe fn disable_scissor_test() { SCISSOR_TEST_STATE.set_state(false) }
+    pub fn depth_mask(&mut self, mask: bool) {
+        if mask != self.depth_test.mask {
+            self.depth_test.mask = mask;
+            self.backend.depth_mask(mask)
+        }
+    }
 
-#[inline]
-pub unsafe fn enable_scissor_test() { SCISSOR_TEST_STATE.set_state(false) }
+    #[inline]
+    pub fn disable_blend(&mut self) { self.blend_func.cap.set_state(&*self.backend, false) }
+
+    #[inline]
+    pub fn enable_blend(&mut self) { self.blend_func.cap.set_state(&*self.backend, true) }
+
+    // Like glBlendFunc this sets the alpha factors too
+    pub fn blend_func(&mut self, src_factor: BlendingFactor, dst_factor: BlendingFactor) {
+        let state = &mut self.blend_func;
+        if src_factor != state.src_factor_rgb
+            || dst_factor != state.dst_factor_rgb
+            || src_factor != state.src_factor_alpha
+            || dst_factor != state.dst_factor_alpha {
+            state.src_factor_rgb = src_factor;
+            state.dst_factor_rgb = dst_factor;
+            state.src_factor_alpha = src_factor;
+            state.dst_factor_alpha = dst_factor;
+            self.backend.blend_func(src_factor, dst_factor)
+        }
+    }
 
-#[inline]
-pub unsafe fn disable_depth_test() { DEPTH_TEST_STATE.cap.set_state(false) }
+    // Falls back to glBlendFunc when alpha is blended like the colour
+
+    pub fn blend_func_separate(
+        &mut self,
+        src_factor_rgb: BlendingFactor,
+        dst_factor_rgb: BlendingFactor,
+        src_factor_alpha: BlendingFactor,
+        dst_factor_alpha: BlendingFactor,
+    ) {
+        if src_factor_rgb == src_factor_alpha && dst_factor_rgb == dst_factor_alpha {
+            return self.blend_func(src_factor_rgb, dst_factor_rgb);
+        }
+        let state = &mut self.blend_func;
+        if src_factor_rgb != state.src_factor_rgb
+            || dst_factor_rgb != state.dst_factor_rgb
+            || src_factor_alpha != state.src_factor_alpha
+            || dst_factor_alpha != state.dst_factor_alpha {
+            state.src_factor_rgb = src_factor_rgb;
+            state.dst_factor_rgb = dst_factor_rgb;
+            state.src_factor_alpha = src_factor_alpha;
+            state.dst_factor_alpha = dst_factor_alpha;
+            self.backend.blend_func_separate(src_factor_rgb, dst_factor_rgb, src_factor_alpha, dst_factor_alpha)
+        }
+    }
 
-#[inline]
-pub unsafe fn enable_depth_test() { DEPTH_TEST_STATE.cap.set_state(false) }
+    pub fn blend_equation(&mut self, equation: BlendEquationModeEXT) {
+        if equation != self.blend_func.equation {
+            self.blend_func.equation = equation;
+            self.backend.blend_equation(equation)
+        }
+    }
 
-pub unsafe fn depth_func(func: DepthFunction) {
-    if func != DEPTH_TEST_STATE.func {
-        DEPTH_TEST_STATE.func = func;
-        glDepthFunc(func);
+    #[inline]
+    pub fn enable_cull(&mut self) { self.cull_face.cap.set_state(&*self.backend, true); }
+
+    #[inline]
+    pub fn disable_cull(&mut self) { self.cull_face.cap.set_state(&*self.backend, false); }
+
+    pub fn cull_face(&mut self, mode: CullFaceMode) {
+        if mode != self.cull_face.mode {
+            self.cull_face.mode = mode;
+            self.backend.cull_face(mode)
+        }
     }
-}
 
-pub unsafe fn depth_mask(mask: bool) {
-    if mask != DEPTH_TEST_STATE.mask {
-        DEPTH_TEST_STATE.mask = mask;
-        glDepthMask(mask as GLboolean)
+    #[inline]
+    pub fn enable_polygon_offset(&mut self) { self.polygon_offset.cap_fill.set_state(&*self.backend, true) }
+
+    #[inline]
+    pub fn disable_polygon_offset(&mut self) { self.polygon_offset.cap_fill.set_state(&*self.backend, false) }
+
+    pub fn polygon_offset(&mut self, factor: GLfloat, units: GLfloat) {
+        let state = &mut self.polygon_offset;
+        if factor != state.factor || units != state.units {
+            state.factor = factor;
+            state.units = units;
+            self.backend.polygon_offset(factor, units)
+        }
     }
-}
 
-#[inline]
-pub unsafe fn disable_blend() { BLEND_FUNC_STATE.cap.set_state(false) }
+    #[inline]
+    pub fn enable_color_logic_op(&mut self) { self.logic_op.cap.set_state(&*self.backend, true) }
 
-#[inline]
-pub unsafe fn enable_blend() { BLEND_FUNC_STATE.cap.set_state(true) }
+    #[inline]
+    pub fn disable_color_logic_op(&mut self) { self.logic_op.cap.set_state(&*self.backend, false) }
 
-pub unsafe fn blend_func(src_factor: BlendingFactor, dst_factor: BlendingFactor) {
-    if src_factor != BLEND_FUNC_STATE.src_factor_rgb || dst_factor != BLEND_FUNC_STATE.dst_factor_rgb {
-        BLEND_FUNC_STATE.src_factor_rgb = src_factor;
-        BLEND_FUNC_STATE.dst_factor_rgb = dst_factor;
-        glBlendFunc(src_factor, dst_factor)
+    pub fn logic_op(&mut self, op: LogicOp) {
+        if op != self.logic_op.op {
+            self.logic_op.op = op;
+            self.backend.logic_op(op)
+        }
     }
-}
 
-pub unsafe fn blend_func_separate(
-    src_factor_rgb: BlendingFactor,
-    dst_factor_rgb: BlendingFactor,
-    src_factor_alpha: BlendingFactor,
-    dst_factor_alpha: BlendingFactor,
-) {
-    if src_factor_rgb != BLEND_FUNC_STATE.src_factor_rgb
-        || dst_factor_rgb != BLEND_FUNC_STATE.dst_factor_rgb
-        || src_factor_alpha != BLEND_FUNC_STATE.src_factor_alpha
-        || dst_factor_alpha != BLEND_FUNC_STATE.dst_factor_alpha {
-        BLEND_FUNC_STATE.src_factor_rgb = src_factor_rgb;
-        BLEND_FUNC_STATE.dst_factor_rgb = dst_factor_rgb;
-        BLEND_FUNC_STATE.src_factor_alpha = src_factor_alpha;
-        BLEND_FUNC_STATE.dst_factor_alpha = dst_factor_alpha;
-        glBlendFuncSeparate(src_factor_rgb, dst_factor_rgb, src_factor_alpha, dst_factor_alpha);
+    pub fn viewport(&mut self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        let viewport = Viewport { x, y, width, height };
+        if viewport != self.viewport {
+            self.viewport = viewport;
+            self.backend.viewport(x, y, width, height)
+        }
     }
-}
 
-pub unsafe fn delete_buffers(buffer: GLuint) {
-    if cfg!(target_os = "linux") {
-        glBindBuffer(GL_ARRAY_BUFFER, buffer);
-        let v: [u8; 0] = [];
-        glBufferData(GL_ARRAY_BUFFER, 0, v.as_ptr().cast(), GL_DYNAMIC_DRAW);
-        glBindBuffer(GL_ARRAY_BUFFER, 0);
+    pub fn color_mask(&mut self, red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) {
+        let mask = &mut self.color_mask;
+        if red != mask.0 || green != mask.1 || blue != mask.2 || alpha != mask.3 {
+            *mask = ColorMask(red, green, blue, alpha);
+            self.backend.color_mask(red, green, blue, alpha)
+        }
     }
-    glDeleteBuffers(1, &buffer);
-}
 
-#[inline]
-pub unsafe fn enable_cull() { CULL_FACE_STATE.cap.set_state(true); }
+    #[inline]
+    pub fn enable_stencil_test(&mut self) { self.stencil.cap.set_state(&*self.backend, true) }
+
+    #[inline]
+    pub fn disable_stencil_test(&mut self) { self.stencil.cap.set_state(&*self.backend, false) }
+
+    pub fn stencil_func(&mut self, func: StencilFunction, ref_: GLint, mask: GLuint) {
+        let s = &mut self.stencil.sub_state;
+        if s.func != func || s.ref_ != ref_ || s.mask != mask {
+            s.func = func;
+            s.ref_ = ref_;
+            s.mask = mask;
+            self.backend.stencil_func(func, ref_, mask)
+        }
+    }
 
-#[inline]
-pub unsafe fn disable_cull() { CULL_FACE_STATE.cap.set_state(false); }
+    pub fn stencil_mask(&mut self, mask: GLuint) {
+        if mask != self.stencil.mask {
+            self.stencil.mask = mask;
+            self.backend.stencil_mask(mask)
+        }
+    }
 
-pub unsafe fn polygon_offset(factor: GLfloat, units: GLfloat) {
-    if factor != POLYGON_OFFSET_STATE.factor || units != POLYGON_OFFSET_STATE.units {
-        POLYGON_OFFSET_STATE.factor = factor;
-        POLYGON_OFFSET_STATE.units = units;
-        glPolygonOffset(factor, units)
+    pub fn stencil_op(&mut self, sfail: StencilOp, dpfail: StencilOp, dppass: StencilOp) {
+        let state = &mut self.stencil;
+        if sfail != state.sfail || dpfail != state.dpfail || dppass != state.dppass {
+            state.sfail = sfail;
+            state.dpfail = dpfail;
+            state.dppass = dppass;
+            self.backend.stencil_op(sfail, dpfail, dppass)
+        }
+    }
+
+    // Switches to state until the returned guard is dropped, which puts
+    // back everything RenderState covers. Guards nest through the deref
+    pub fn push_render_state(&mut self, state: &RenderState) -> RenderStateGuard<'_> {
+        self.saved_states.push(self.save_state());
+        self.apply_render_state(state);
+        RenderStateGuard { gl: self }
+    }
+
+    fn apply_render_state(&mut self, state: &RenderState) {
+        match state.blend {
+            Some(blend) => {
+                self.enable_blend();
+                self.blend_func_separate(blend.src_rgb, blend.dst_rgb, blend.src_alpha, blend.dst_alpha);
+                self.blend_equation(blend.equation);
+            }
+            None => self.disable_blend(),
+        }
+        match state.depth_test {
+            Some(func) => {
+                self.enable_depth_test();
+                self.depth_func(func);
+            }
+            None => self.disable_depth_test(),
+        }
+        self.depth_mask(state.depth_write);
+        match state.cull {
+            Some(mode) => {
+                self.enable_cull();
+                self.cull_face(mode);
+            }
+            None => self.disable_cull(),
+        }
+        if state.scissor_test {
+            self.enable_scissor_test();
+        } else {
+            self.disable_scissor_test();
+        }
+        match state.stencil {
+            Some(stencil) => {
+                self.enable_stencil_test();
+                self.stencil_func(stencil.func, stencil.ref_, stencil.mask);
+                self.stencil_mask(stencil.write_mask);
+                self.stencil_op(stencil.sfail, stencil.dpfail, stencil.dppass);
+            }
+            None => self.disable_stencil_test(),
+        }
+        match state.polygon_offset {
+            Some((factor, units)) => {
+                self.enable_polygon_offset();
+                self.polygon_offset(factor, units);
+            }
+            None => self.disable_polygon_offset(),
+        }
+        match state.logic_op {
+            Some(op) => {
+                self.enable_color_logic_op();
+                self.logic_op(op);
+            }
+            None => self.disable_color_logic_op(),
+        }
+    }
+
+    // Parameters are saved along with the caps, a state that leaves something
+    // disabled must not lose the values set before it
+    fn save_state(&self) -> SavedState {
+        SavedState {
+            scissor_test: self.scissor_test.1,
+            depth_test: self.depth_test.clone(),
+            blend_func: self.blend_func.clone(),
+            cull_face: self.cull_face.clone(),
+            polygon_offset: self.polygon_offset.clone(),
+            logic_op: self.logic_op.clone(),
+            stencil: self.stencil.clone(),
+        }
+    }
+
+    fn pop_render_state(&mut self) {
+        let saved = match self.saved_states.pop() {
+            Some(value) => value,
+            None => return,
+        };
+        self.scissor_test.set_state(&*self.backend, saved.scissor_test);
+
+        let depth = saved.depth_test;
+        self.depth_test.cap.set_state(&*self.backend, depth.cap.1);
+        self.depth_func(depth.func);
+        self.depth_mask(depth.mask);
+
+        let blend = saved.blend_func;
+        self.blend_func.cap.set_state(&*self.backend, blend.cap.1);
+        self.blend_func_separate(blend.src_factor_rgb, blend.dst_factor_rgb, blend.src_factor_alpha, blend.dst_factor_alpha);
+        self.blend_equation(blend.equation);
+
+        self.cull_face.cap.set_state(&*self.backend, saved.cull_face.cap.1);
+        self.cull_face(saved.cull_face.mode);
+
+        let offset = saved.polygon_offset;
+        self.polygon_offset.cap_fill.set_state(&*self.backend, offset.cap_fill.1);
+        self.polygon_offset.cap_line.set_state(&*self.backend, offset.cap_line.1);
+        self.polygon_offset(offset.factor, offset.units);
+
+        self.logic_op.cap.set_state(&*self.backend, saved.logic_op.cap.1);
+        self.logic_op(saved.logic_op.op);
+
+        let stencil = saved.stencil;
+        self.stencil.cap.set_state(&*self.backend, stencil.cap.1);
+        self.stencil_func(stencil.sub_state.func, stencil.sub_state.ref_, stencil.sub_state.mask);
+        self.stencil_mask(stencil.mask);
+        self.stencil_op(stencil.sfail, stencil.dpfail, stencil.dppass);
     }
 }
 
-#[inline]
-pub unsafe fn enable_color_logic_op() { LOGIC_OP_STATE.cap.set_state(true) }
+struct SavedState {
+    scissor_test: bool,
+    depth_test: DepthTestState,
+    blend_func: BlendFuncState,
+    cull_face: CullFaceState,
+    polygon_offset: PolygonOffsetState,
+    logic_op: LogicOpState,
+    stencil: StencilState,
+}
 
-#[inline]
-pub unsafe fn disable_color_logic_op() { LOGIC_OP_STATE.cap.set_state(false) }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlendState {
+    pub equation: BlendEquationModeEXT,
+    pub src_rgb: BlendingFactor,
+    pub dst_rgb: BlendingFactor,
+    pub src_alpha: BlendingFactor,
+    pub dst_alpha: BlendingFactor,
+}
+
+impl BlendState {
+    // Colour over what is behind it, alpha accumulates towards opaque
+    pub const TRANSLUCENT: BlendState = BlendState {
+        equation: GL_FUNC_ADD,
+        src_rgb: GL_SRC_ALPHA,
+        dst_rgb: GL_ONE_MINUS_SRC_ALPHA,
+        src_alpha: GL_ONE,
+        dst_alpha: GL_ONE_MINUS_SRC_ALPHA,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StencilTest {
+    pub func: StencilFunction,
+    pub ref_: GLint,
+    pub mask: GLuint,
+    pub write_mask: GLuint,
+    pub sfail: StencilOp,
+    pub dpfail: StencilOp,
+    pub dppass: StencilOp,
+}
+
+// Fixed function state a pass draws with, e.g. translucent geometry is
+// RenderState::TRANSLUCENT. None disables the test or feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderState {
+    pub blend: Option<BlendState>,
+    pub depth_test: Option<DepthFunction>,
+    pub depth_write: bool,
+    pub cull: Option<CullFaceMode>,
+    pub scissor_test: bool,
+    pub stencil: Option<StencilTest>,
+    // factor, units for filled polygons
+    pub polygon_offset: Option<(GLfloat, GLfloat)>,
+    pub logic_op: Option<LogicOp>,
+}
+
+impl RenderState {
+    pub const OPAQUE: RenderState = RenderState {
+        blend: None,
+        depth_test: Some(GL_LEQUAL),
+        depth_write: true,
+        cull: Some(GL_BACK),
+        scissor_test: false,
+        stencil: None,
+        polygon_offset: None,
+        logic_op: None,
+    };
+
+    // Depth tested against the opaque geometry without hiding what is
+    // drawn after it
+    pub const TRANSLUCENT: RenderState = RenderState {
+        blend: Some(BlendState::TRANSLUCENT),
+        depth_write: false,
+        ..RenderState::OPAQUE
+    };
+
+    // Full screen passes such as blits and post effects
+    pub const OVERLAY: RenderState = RenderState {
+        depth_test: None,
+        depth_write: false,
+        cull: None,
+        ..RenderState::OPAQUE
+    };
+}
+
+// Pops the pushed RenderState when dropped
+pub struct RenderStateGuard<'a> {
+    gl: &'a mut GlStateManager,
+}
+
+impl Deref for RenderStateGuard<'_> {
+    type Target = GlStateManager;
+
+    fn deref(&self) -> &GlStateManager {
+        self.gl
+    }
+}
 
-pub unsafe fn logic_op(op: LogicOp) {
-    if op != LOGIC_OP_STATE.op {
-        LOGIC_OP_STATE.op = op;
-        glLogicOp(op);
+impl DerefMut for RenderStateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut GlStateManager {
+        self.gl
     }
 }
 
-pub unsafe fn viewport(x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
-    VIEWPORT.x = x;
-    VIEWPORT.y = y;
-    VIEWPORT.width = width;
-    VIEWPORT.height = height;
-    glViewport(x, y, width, height)
+impl Drop for RenderStateGuard<'_> {
+    fn drop(&mut self) {
+        self.gl.pop_render_state();
+    }
 }
 
-pub unsafe fn color_mask(red: GLboolean, green: GLboolean, blue: GLboolean, alpha: GLboolean) {
-    if red != COLOR_MASK.0 ||
-        green != COLOR_MASK.1 ||
-        blue != COLOR_MASK.2 ||
-        alpha != COLOR_MASK.3 {
-        COLOR_MASK.0 = red;
-        COLOR_MASK.1 = green;
-        COLOR_MASK.2 = blue;
-        COLOR_MASK.3 = alpha;
-        glColorMask(red, green, blue, alpha)
+impl Drop for GlStateManager {
+    fn drop(&mut self) {
+        self.delete_queued_textures();
     }
 }
 
-pub unsafe fn stencil_func(func: StencilFunction, ref_: GLint, mask: GLuint) {
-    let s = &mut STENCIL_STATE.sub_state;
-    if s.func != func || s.ref_ != ref_ || s.mask != mask {
-        s.func = func;
-        s.ref_ = ref_;
-        s.mask = mask;
-        glStencilFunc(func, ref_, mask)
+// Lets Drop impls, which can't reach the state manager, delete their textures
+// through GlStateManager::delete_textures
+#[derive(Clone)]
+pub struct TextureDeleter {
+    backend: Rc<dyn GlBackend>,
+    queue: Weak<RefCell<Vec<TextureUnit>>>,
+}
+
+impl TextureDeleter {
+    pub fn delete(&self, textures: &[TextureUnit]) {
+        match self.queue.upgrade() {
+            Some(queue) => queue.borrow_mut().extend_from_slice(textures),
+            // Nothing tracks their bindings once the manager is gone
+            None => {
+                let va: Vec<GLuint> = textures.iter().map(|v| v.0).collect();
+                self.backend.delete_textures(&va);
+            }
+        }
     }
 }
 
-pub unsafe fn stencil_mask(mask: GLuint) {
-    if mask != STENCIL_STATE.mask {
-        STENCIL_STATE.mask = mask;
-        glStencilMask(mask);
+pub fn delete_buffer(backend: &dyn GlBackend, buffer: GLuint) {
+    if cfg!(target_os = "linux") {
+        backend.bind_buffer(GL_ARRAY_BUFFER, buffer);
+        backend.buffer_data(GL_ARRAY_BUFFER, &[], GL_DYNAMIC_DRAW);
+        backend.bind_buffer(GL_ARRAY_BUFFER, 0);
     }
+    backend.delete_buffer(buffer);
 }
 
-pub unsafe fn stencil_op(sfail: StencilOp, dpfail: StencilOp, dppass: StencilOp) {
-    if sfail != STENCIL_STATE.sfail || dpfail != STENCIL_STATE.dpfail || dppass != STENCIL_STATE.dppass {
-        STENCIL_STATE.sfail = sfail;
-        STENCIL_STATE.dpfail = dpfail;
-        STENCIL_STATE.dppass = dppass;
-        glStencilOp(sfail, dpfail, dppass);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+
+    fn state_manager() -> (Rc<RecordingGl>, GlStateManager) {
+        let backend = Rc::new(RecordingGl::new());
+        let gl = GlStateManager::new(backend.clone());
+        (backend, gl)
+    }
+
+    #[test]
+    fn elides_redundant_state_changes() {
+        let (backend, mut gl) = state_manager();
+        gl.enable_blend();
+        gl.enable_blend();
+        gl.disable_depth_test();
+        gl.blend_func(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+        gl.blend_func(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+        let texture = gl.gen_texture();
+        gl.bind_texture(texture);
+        gl.bind_texture(texture);
+        gl.viewport(0, 0, 320, 240);
+        gl.viewport(0, 0, 320, 240);
+        let calls: Vec<_> = backend.take_calls().into_iter().map(|call| call.function).collect();
+        assert_eq!(
+            calls,
+            ["glEnable", "glBlendFunc", "glGenTextures", "glBindTexture", "glViewport"]
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tracks_the_alpha_factors_of_blend_func() {
+        let (backend, mut gl) = state_manager();
+        gl.blend_func_separate(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA, GL_ONE, GL_ZERO);
+        // Same colour factors but the alpha ones change as well
+        gl.blend_func(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+        let (src, dst) = (GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+        gl.blend_func_separate(src, dst, src, dst);
+        gl.blend_func_separate(GL_ONE, GL_ONE, GL_ONE, GL_ONE);
+        let calls: Vec<_> = backend.take_calls().into_iter().map(|call| call.function).collect();
+        assert_eq!(calls, ["glBlendFuncSeparate", "glBlendFunc", "glBlendFunc"]);
+    }
+
+    #[test]
+    fn restores_pushed_render_states() {
+        let (backend, mut gl) = state_manager();
+        gl.enable_depth_test();
+        backend.take_calls();
+        {
+            let mut overlay = gl.push_render_state(&RenderState::OVERLAY);
+            overlay.push_render_state(&RenderState::OVERLAY);
+        }
+        let calls = backend.take_calls();
+        let disables = calls.iter().filter(|call| call.function == "glDisable").count();
+        let enables = calls.iter().filter(|call| call.function == "glEnable").count();
+        // Only the depth test differs, and only the outer push and pop touch it
+        assert_eq!((disables, enables), (1, 1));
+
+        // Back where it started, so applying the same again is free
+        gl.enable_depth_test();
+        gl.disable_cull();
+        assert_eq!(backend.take_calls(), []);
+    }
+
+    #[test]
+    fn forgets_bindings_of_queued_deletions() {
+        let (backend, mut gl) = state_manager();
+        let texture = gl.gen_texture();
+        gl.bind_texture(texture);
+        gl.texture_deleter().delete(&[texture]);
+        backend.take_calls();
+        gl.bind_texture(texture);
+        let calls: Vec<_> = backend.take_calls().into_iter().map(|call| call.function).collect();
+        assert_eq!(calls, ["glDeleteTextures", "glBindTexture"]);
+
+        // Deleted straight away once the manager is gone
+        let deleter = gl.texture_deleter();
+        drop(gl);
+        backend.take_calls();
+        deleter.delete(&[texture]);
+        assert_eq!(backend.count("glDeleteTextures"), 1);
+    }
+}
@@ -0,0 +1,149 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use gl33::*;
+
+use crate::render::backend::GlBackend;
+
+use crate::render::index_buffer::SharedIndexBuffers;
+use crate::render::shader::Shader;
+use crate::render::uniform::StandardUniforms;
+use crate::render::util::{delete_buffer, GlStateManager};
+use crate::render::{DrawArrayParameters, DrawMode, IntType, VertexFormat};
+use crate::types::{GLsizei, GLuint};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferUsage {
+    // Uploaded once and drawn many times, e.g. chunk meshes
+    Static,
+    // Uploaded again every frame, e.g. immediate mode GUI
+    Dynamic,
+}
+
+impl BufferUsage {
+    fn gl(&self) -> BufferUsageARB {
+        match self {
+            BufferUsage::Static => GL_STATIC_DRAW,
+            BufferUsage::Dynamic => GL_DYNAMIC_DRAW,
+        }
+    }
+}
+
+// Vertex and index data uploaded from a BufferBuilder, owns the vertex
+// array and both buffers
+pub struct VertexBuffer {
+    backend: Rc<dyn GlBackend>,
+    shared_indices: Rc<RefCell<SharedIndexBuffers>>,
+    vertex_array: GLuint,
+    vertex_buffer: GLuint,
+    index_buffer: GLuint,
+    usage: BufferUsage,
+    format: Option<&'static VertexFormat>,
+    draw_mode: DrawMode,
+    index_type: IntType,
+    index_count: usize,
+    // Drawn with the shared index buffer for draw_mode
+    sequential: bool,
+}
+
+impl VertexBuffer {
+    pub fn new(gl: &GlStateManager, usage: BufferUsage) -> VertexBuffer {
+        let backend = gl.backend().clone();
+        VertexBuffer {
+            vertex_array: backend.gen_vertex_array(),
+            vertex_buffer: backend.gen_buffer(),
+            index_buffer: backend.gen_buffer(),
+            backend,
+            shared_indices: gl.shared_indices(),
+            usage,
+            format: None,
+            draw_mode: DrawMode::Triangles,
+            index_type: IntType::Int,
+            index_count: 0,
+            sequential: false,
+        }
+    }
+
+    // Uploads data as returned by BufferBuilder::pop_data, the vertices
+    // followed by the indices unless they are sequential
+    pub fn upload(&mut self, parameters: DrawArrayParameters, data: &[u8]) {
+        let backend = &*self.backend;
+        backend.bind_vertex_array(self.vertex_array);
+        // index_only marks a resort of the indices with unchanged vertices
+        if !parameters.index_only {
+            backend.bind_buffer(GL_ARRAY_BUFFER, self.vertex_buffer);
+            backend.buffer_data(
+                GL_ARRAY_BUFFER,
+                &data[..parameters.get_limit()],
+                self.usage.gl(),
+            );
+            let format = parameters.vertex_format;
+            if self.format != Some(format) {
+                if let Some(current) = self.format {
+                    current.clear_state(backend);
+                }
+                format.setup_state(backend);
+                self.format = Some(format);
+            }
+        }
+        if parameters.sequential {
+            self.index_type = self.shared_indices.borrow_mut().bind(
+                backend,
+                parameters.draw_mode,
+                parameters.vertex_count,
+            );
+        } else {
+            backend.bind_buffer(GL_ELEMENT_ARRAY_BUFFER, self.index_buffer);
+            let start = parameters.get_limit();
+            backend.buffer_data(
+                GL_ELEMENT_ARRAY_BUFFER,
+                &data[start..start + parameters.get_draw_length()],
+                self.usage.gl(),
+            );
+            self.index_type = parameters.element_format;
+        }
+        self.sequential = parameters.sequential;
+        self.draw_mode = parameters.draw_mode;
+        self.index_count = parameters.vertex_count;
+        backend.bind_vertex_array(0);
+    }
+
+    pub fn draw(&self) {
+        if self.index_count == 0 {
+            return;
+        }
+        let index_type = if self.sequential {
+            self.shared_indices.borrow_mut().index_type(self.draw_mode)
+        } else {
+            self.index_type
+        };
+        self.backend.bind_vertex_array(self.vertex_array);
+        self.backend.draw_elements(
+            self.draw_mode.into(),
+            self.index_count as GLsizei,
+            index_type.into(),
+            0,
+        );
+        self.backend.bind_vertex_array(0);
+    }
+
+    pub fn draw_with_shader(
+        &self,
+        gl: &mut GlStateManager,
+        shader: &mut Shader,
+        uniforms: &StandardUniforms,
+    ) {
+        shader.set_standard_uniforms(uniforms);
+        shader.apply(gl);
+        self.draw();
+        shader.unbind();
+    }
+}
+
+impl Drop for VertexBuffer {
+    fn drop(&mut self) {
+        self.backend.delete_vertex_array(self.vertex_array);
+        delete_buffer(&*self.backend, self.vertex_buffer);
+        delete_buffer(&*self.backend, self.index_buffer);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+
+use thiserror::Error;
+
+use crate::render::{VertexFormat, VertexFormatElement};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum VertexFormatError {
+    #[error("unknown vertex attribute {0}")]
+    UnknownAttribute(String),
+    #[error("vertex attribute {0} is declared more than once")]
+    DuplicateAttribute(String),
+    #[error("vertex format has no attributes")]
+    Empty,
+}
+
+// Attribute names paired with their elements, the registry key
+type Layout = Vec<(String, VertexFormatElement)>;
+
+fn registry() -> &'static Mutex<HashMap<Layout, &'static VertexFormat>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<Layout, &'static VertexFormat>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let formats = VertexFormat::BUILTIN
+            .iter()
+            .map(|format| (layout(format), *format))
+            .collect();
+        Mutex::new(formats)
+    })
+}
+
+fn layout(format: &VertexFormat) -> Layout {
+    format
+        .attr_names
+        .iter()
+        .zip(format.elements)
+        .map(|(name, element)| (name.to_string(), **element))
+        .collect()
+}
+
+// Builds a VertexFormat at runtime, e.g. from the attributes of a shader
+// definition. Identical layouts are deduplicated so every build of the same
+// layout returns the same format
+#[derive(Debug, Clone, Default)]
+pub struct VertexFormatBuilder {
+    layout: Layout,
+}
+
+impl VertexFormatBuilder {
+    pub fn new() -> VertexFormatBuilder {
+        VertexFormatBuilder { layout: Vec::new() }
+    }
+
+    // Maps shader attribute names to their standard elements. Normals are
+    // followed by padding to keep vertices 4 byte aligned
+    pub fn from_attributes<S: AsRef<str>>(
+        attributes: &[S],
+    ) -> Result<VertexFormatBuilder, VertexFormatError> {
+        let mut builder = VertexFormatBuilder::new();
+        for attribute in attributes {
+            let name = attribute.as_ref();
+            let element = match name {
+                "Position" => VertexFormatElement::POSITION,
+                "Color" => VertexFormatElement::COLOR,
+                "UV" | "UV0" => VertexFormatElement::TEXTURE,
+                "UV1" => VertexFormatElement::OVERLAY,
+                "UV2" => VertexFormatElement::LIGHT,
+                "Normal" => VertexFormatElement::NORMAL,
+                _ => return Err(VertexFormatError::UnknownAttribute(name.to_string())),
+            };
+            builder = builder.element(name, element);
+            if element == VertexFormatElement::NORMAL {
+                builder = builder.element("Padding", VertexFormatElement::PADDING);
+            }
+        }
+        Ok(builder)
+    }
+
+    pub fn element(mut self, name: &str, element: &VertexFormatElement) -> VertexFormatBuilder {
+        self.layout.push((name.to_string(), *element));
+        self
+    }
+
+    pub fn build(self) -> Result<&'static VertexFormat, VertexFormatError> {
+        if self.layout.is_empty() {
+            return Err(VertexFormatError::Empty);
+        }
+        let mut names = HashSet::new();
+        if let Some((name, _)) = self.layout.iter().find(|(name, _)| !names.insert(name)) {
+            return Err(VertexFormatError::DuplicateAttribute(name.clone()));
+        }
+        let mut registry = registry().lock().unwrap();
+        if let Some(format) = registry.get(&self.layout) {
+            return Ok(format);
+        }
+        // Registered formats live for the rest of the program
+        let attr_names: Vec<&'static str> = self
+            .layout
+            .iter()
+            .map(|(name, _)| &*Box::leak(name.clone().into_boxed_str()))
+            .collect();
+        let elements: Vec<&'static VertexFormatElement> = self
+            .layout
+            .iter()
+            .map(|(_, element)| &*Box::leak(Box::new(*element)))
+            .collect();
+        let format: &'static VertexFormat = Box::leak(Box::new(VertexFormat::new(
+            Box::leak(attr_names.into_boxed_slice()),
+            Box::leak(elements.into_boxed_slice()),
+        )));
+        registry.insert(self.layout, format);
+        Ok(format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn reuses_builtin_formats() {
+        let format = VertexFormatBuilder::from_attributes(&["Position", "UV", "Color"])
+            .and_then(VertexFormatBuilder::build)
+            .unwrap();
+        assert!(ptr::eq(format, VertexFormat::BLIT_SCREEN));
+    }
+
+    #[test]
+    fn registers_runtime_formats_once() {
+        let build = || {
+            VertexFormatBuilder::from_attributes(&["Color", "Normal", "Position"])
+                .and_then(VertexFormatBuilder::build)
+                .unwrap()
+        };
+        let format = build();
+        assert!(ptr::eq(format, build()));
+        assert_eq!(format.attr_names, ["Color", "Normal", "Padding", "Position"]);
+        assert_eq!(format.size, 4 + 3 + 1 + 12);
+    }
+
+    #[test]
+    fn rejects_invalid_attributes() {
+        assert_eq!(
+            VertexFormatBuilder::from_attributes(&["Position", "Tangent"]).unwrap_err(),
+            VertexFormatError::UnknownAttribute("Tangent".to_string())
+        );
+        assert_eq!(
+            VertexFormatBuilder::from_attributes(&["UV0", "UV0"])
+                .and_then(VertexFormatBuilder::build)
+                .unwrap_err(),
+            VertexFormatError::DuplicateAttribute("UV0".to_string())
+        );
+        assert_eq!(
+            VertexFormatBuilder::new().build().unwrap_err(),
+            VertexFormatError::Empty
+        );
+    }
+}
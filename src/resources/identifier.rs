@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum IdentifierError {
+    #[error("invalid character {character:?} in namespace of identifier {value}")]
+    InvalidNamespace { value: String, character: char },
+    #[error("invalid character {character:?} in path of identifier {value}")]
+    InvalidPath { value: String, character: char },
+    #[error("identifier {0} has an empty path")]
+    EmptyPath(String),
+    // Packs join identifiers onto their root, so these would escape it
+    #[error("identifier {value} has a {segment:?} segment")]
+    RelativeSegment { value: String, segment: String },
+}
+
+// Owned namespace and path pair, validated on construction
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ResourceLocation {
+    namespace: String,
+    path: String,
+}
+
+fn is_valid_namespace_char(c: char) -> bool {
+    matches!(c, 'a'..='z' | '0'..='9' | '_' | '.' | '-')
+}
+
+fn is_valid_path_char(c: char) -> bool {
+    is_valid_namespace_char(c) || c == '/'
+}
+
+fn is_relative(segment: &str) -> bool {
+    segment == "." || segment == ".."
+}
+
+impl ResourceLocation {
+    pub const DEFAULT_NAMESPACE: &'static str = "minecraft";
+
+    pub fn new(namespace: &str, path: &str) -> Result<ResourceLocation, IdentifierError> {
+        let value = || format!("{}:{}", namespace, path);
+        if let Some(character) = namespace.chars().find(|c| !is_valid_namespace_char(*c)) {
+            return Err(IdentifierError::InvalidNamespace { value: value(), character });
+        }
+        if let Some(character) = path.chars().find(|c| !is_valid_path_char(*c)) {
+            return Err(IdentifierError::InvalidPath { value: value(), character });
+        }
+        if path.is_empty() {
+            return Err(IdentifierError::EmptyPath(value()));
+        }
+        let relative = path.split('/').chain([namespace]).find(|segment| is_relative(segment));
+        if let Some(segment) = relative {
+            let segment = segment.to_string();
+            return Err(IdentifierError::RelativeSegment { value: value(), segment });
+        }
+        let namespace = if namespace.is_empty() {
+            ResourceLocation::DEFAULT_NAMESPACE
+        } else {
+            namespace
+        };
+        Ok(ResourceLocation {
+            namespace: namespace.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    pub fn minecraft(path: &str) -> Result<ResourceLocation, IdentifierError> {
+        ResourceLocation::new(ResourceLocation::DEFAULT_NAMESPACE, path)
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn asset_path(&self) -> String {
+        format!("assets/{}/{}", self.namespace, self.path)
+    }
+
+    pub fn intern(&self) -> Identifier {
+        Identifier::intern(self)
+    }
+}
+
+impl FromStr for ResourceLocation {
+    type Err = IdentifierError;
+
+    // Values without a namespace (e.g. "stone" or ":stone") use the minecraft namespace
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some((namespace, path)) => ResourceLocation::new(namespace, path),
+            None => ResourceLocation::minecraft(value),
+        }
+    }
+}
+
+impl Display for ResourceLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.path)
+    }
+}
+
+// Interned handle to a ResourceLocation, cheap to copy, compare and hash
+#[derive(Clone, Copy)]
+pub struct Identifier(&'static ResourceLocation);
+
+fn interned() -> &'static Mutex<HashSet<&'static ResourceLocation>> {
+    static INTERNED: OnceLock<Mutex<HashSet<&'static ResourceLocation>>> = OnceLock::new();
+    INTERNED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+impl Identifier {
+    pub fn new(namespace: &str, path: &str) -> Result<Identifier, IdentifierError> {
+        ResourceLocation::new(namespace, path).map(|location| location.intern())
+    }
+
+    pub fn minecraft(path: &str) -> Result<Identifier, IdentifierError> {
+        Identifier::new(ResourceLocation::DEFAULT_NAMESPACE, path)
+    }
+
+    fn intern(location: &ResourceLocation) -> Identifier {
+        let mut interned = interned().lock().unwrap();
+        if let Some(value) = interned.get(location) {
+            return Identifier(value);
+        }
+        // Interned locations live for the rest of the program
+        let value: &'static ResourceLocation = Box::leak(Box::new(location.clone()));
+        interned.insert(value);
+        Identifier(value)
+    }
+}
+
+impl Deref for Identifier {
+    type Target = ResourceLocation;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl From<&ResourceLocation> for Identifier {
+    fn from(value: &ResourceLocation) -> Self {
+        value.intern()
+    }
+}
+
+impl FromStr for Identifier {
+    type Err = IdentifierError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        ResourceLocation::from_str(value).map(|location| location.intern())
+    }
+}
+
+// Interning guarantees equal locations share an address
+impl PartialEq for Identifier {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Identifier {}
+
+impl Hash for Identifier {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ptr::hash(self.0, state)
+    }
+}
+
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Ordered by value rather than address so iteration order is stable
+impl Ord for Identifier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(other.0)
+    }
+}
+
+impl Display for Identifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(self.0, f)
+    }
+}
+
+impl Debug for Identifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Identifier({})", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_namespace_and_path() {
+        let location: ResourceLocation = "mod:textures/block/stone.png".parse().unwrap();
+        assert_eq!(location.namespace(), "mod");
+        assert_eq!(location.path(), "textures/block/stone.png");
+        assert_eq!(location.asset_path(), "assets/mod/textures/block/stone.png");
+        assert_eq!(location.to_string(), "mod:textures/block/stone.png");
+    }
+
+    #[test]
+    fn defaults_to_minecraft_namespace() {
+        for value in ["stone", ":stone"] {
+            let location: ResourceLocation = value.parse().unwrap();
+            assert_eq!(location.namespace(), ResourceLocation::DEFAULT_NAMESPACE);
+            assert_eq!(location.path(), "stone");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_characters() {
+        assert_eq!(
+            "Mod:stone".parse::<ResourceLocation>(),
+            Err(IdentifierError::InvalidNamespace {
+                value: "Mod:stone".to_string(),
+                character: 'M'
+            })
+        );
+        assert_eq!(
+            "mod:stone block".parse::<ResourceLocation>(),
+            Err(IdentifierError::InvalidPath {
+                value: "mod:stone block".to_string(),
+                character: ' '
+            })
+        );
+        assert_eq!(
+            "mod:".parse::<ResourceLocation>(),
+            Err(IdentifierError::EmptyPath("mod:".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_relative_segments() {
+        for value in [
+            "../secret",
+            "textures/../../secret",
+            "textures/./stone.png",
+            "textures/..",
+            "..:textures/stone.png",
+        ] {
+            assert!(
+                matches!(
+                    value.parse::<ResourceLocation>(),
+                    Err(IdentifierError::RelativeSegment { .. })
+                ),
+                "{} was accepted",
+                value
+            );
+        }
+        // Dots are fine inside a segment
+        assert!("textures/stone..png".parse::<ResourceLocation>().is_ok());
+        assert!("mod.name:.hidden".parse::<ResourceLocation>().is_ok());
+    }
+
+    #[test]
+    fn interns_equal_locations() {
+        let a: Identifier = "mod:stone".parse().unwrap();
+        let b = Identifier::new("mod", "stone").unwrap();
+        assert_eq!(a, b);
+        assert!(ptr::eq(&*a, &*b));
+        assert_ne!(a, Identifier::minecraft("stone").unwrap());
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+
+use rust_embed::RustEmbed;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+pub use identifier::{Identifier, IdentifierError, ResourceLocation};
+use pack::{open_pack, EmbeddedPack, Pack, PackMetadata};
+
+mod identifier;
+pub mod pack;
+pub mod reload;
+
+#[derive(RustEmbed)]
+#[folder = "resources"]
+pub struct Resources;
+
+#[derive(Debug, Error)]
+pub enum ResourceError {
+    #[error("resource {0} was not found in any pack")]
+    NotFound(Identifier),
+    #[error("resource {identifier} in pack {pack} is not valid utf-8: {source}")]
+    InvalidUtf8 {
+        identifier: Identifier,
+        pack: String,
+        source: FromUtf8Error,
+    },
+    #[error("resource {identifier} in pack {pack} is malformed json: {source}")]
+    MalformedJson {
+        identifier: Identifier,
+        pack: String,
+        source: serde_json::Error,
+    },
+    #[error("unable to read resource {identifier} from pack {pack}: {source}")]
+    Io {
+        identifier: Identifier,
+        pack: String,
+        source: io::Error,
+    },
+}
+
+// The raw contents of a resource along with the pack that provided it
+pub struct Resource {
+    pub identifier: Identifier,
+    pub pack: String,
+    pub data: Vec<u8>,
+}
+
+impl Resource {
+    pub fn into_utf8(self) -> Result<String, ResourceError> {
+        let Resource { identifier, pack, data } = self;
+        String::from_utf8(data).map_err(|source| ResourceError::InvalidUtf8 {
+            identifier,
+            pack,
+            source,
+        })
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, ResourceError> {
+        serde_json::from_slice(&self.data).map_err(|source| ResourceError::MalformedJson {
+            identifier: self.identifier,
+            pack: self.pack.clone(),
+            source,
+        })
+    }
+}
+
+// Stack of packs where later packs take priority over earlier ones,
+// the embedded defaults are always at the bottom
+pub struct ResourceManager {
+    packs: Vec<Box<dyn Pack>>,
+    packs_directory: Option<PathBuf>,
+}
+
+impl ResourceManager {
+    pub fn new() -> ResourceManager {
+        ResourceManager {
+            packs: vec![Box::new(EmbeddedPack)],
+            packs_directory: None,
+        }
+    }
+
+    // Creates a new manager with the embedded defaults and, if given, every
+    // pack in directory. Used to reopen packs_directory when reloading, packs
+    // added directly through add_pack are not carried over
+    pub fn scan(directory: Option<&Path>) -> ResourceManager {
+        let mut resources = ResourceManager::new();
+        if let Some(directory) = directory {
+            resources.load_packs(directory);
+        }
+        resources
+    }
+
+    pub fn packs_directory(&self) -> Option<&Path> {
+        self.packs_directory.as_deref()
+    }
+
+    pub fn add_pack(&mut self, pack: Box<dyn Pack>) {
+        self.packs.push(pack);
+    }
+
+    // Adds every directory and .zip pack in the provided directory ordered by
+    // file name, packs that fail to open are skipped with a warning
+    pub fn load_packs(&mut self, directory: &Path) {
+        self.packs_directory = Some(directory.to_path_buf());
+        let entries = match fs::read_dir(directory) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            match open_pack(&path) {
+                Ok(pack) => {
+                    let description = pack.metadata().map(PackMetadata::description_text);
+                    match description.filter(|description| !description.is_empty()) {
+                        Some(description) => {
+                            println!("Loaded resource pack {}: {}", pack.name(), description)
+                        }
+                        None => println!("Loaded resource pack {}", pack.name()),
+                    }
+                    self.add_pack(pack)
+                }
+                Err(err) => eprintln!("Skipping resource pack: {}", err),
+            }
+        }
+    }
+
+    pub fn get(&self, identifier: Identifier) -> Result<Resource, ResourceError> {
+        let path = identifier.asset_path();
+        for pack in self.packs.iter().rev() {
+            match pack.get(&path) {
+                Ok(Some(data)) => {
+                    return Ok(Resource {
+                        identifier,
+                        pack: pack.name().to_string(),
+                        data,
+                    })
+                }
+                Ok(None) => {}
+                Err(source) => {
+                    return Err(ResourceError::Io {
+                        identifier,
+                        pack: pack.name().to_string(),
+                        source,
+                    })
+                }
+            }
+        }
+        Err(ResourceError::NotFound(identifier))
+    }
+
+    pub fn get_utf8(&self, identifier: Identifier) -> Result<String, ResourceError> {
+        self.get(identifier)?.into_utf8()
+    }
+
+    pub fn get_json<T: DeserializeOwned>(&self, identifier: Identifier) -> Result<T, ResourceError> {
+        self.get(identifier)?.json()
+    }
+
+    // Every resource below directory ending with extension in any namespace
+    // of any pack (e.g. list("textures/block", ".png")), sorted and without
+    // duplicates. Packs that fail to list are skipped with a warning
+    pub fn list(&self, directory: &str, extension: &str) -> Vec<Identifier> {
+        let mut found = BTreeSet::new();
+        for pack in &self.packs {
+            let namespaces = match pack.namespaces() {
+                Ok(value) => value,
+                Err(err) => {
+                    eprintln!("Unable to list resource pack {}: {}", pack.name(), err);
+                    continue;
+                }
+            };
+            for namespace in namespaces {
+                let root = format!("assets/{}/", namespace);
+                let paths = match pack.list(&format!("{}{}", root, directory)) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        eprintln!("Unable to list resource pack {}: {}", pack.name(), err);
+                        continue;
+                    }
+                };
+                for path in paths.iter().filter(|path| path.ends_with(extension)) {
+                    match Identifier::new(&namespace, &path[root.len()..]) {
+                        Ok(identifier) => {
+                            found.insert(identifier);
+                        }
+                        Err(err) => eprintln!("Skipping resource in pack {}: {}", pack.name(), err),
+                    }
+                }
+            }
+        }
+        found.into_iter().collect()
+    }
+}
+
+// Magenta and black checkerboard RGBA pixels used in place of textures that fail to load
+pub fn missing_texture(width: u32, height: u32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let magenta = (x < width / 2) == (y < height / 2);
+            if magenta {
+                pixels.extend_from_slice(&[0xF8, 0x00, 0xF8, 0xFF]);
+            } else {
+                pixels.extend_from_slice(&[0x00, 0x00, 0x00, 0xFF]);
+            }
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use super::*;
+
+    const PACK_META: &str = r#"{"pack": {"pack_format": 8, "description": "Test"}}"#;
+
+    // A directory of packs under the system temp directory, removed on drop
+    struct PacksDirectory(PathBuf);
+
+    impl PacksDirectory {
+        fn new(name: &str) -> PacksDirectory {
+            let path = std::env::temp_dir().join(format!("rmc-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            PacksDirectory(path)
+        }
+
+        fn add_directory(&self, name: &str, files: &[(&str, &str)]) {
+            let root = self.0.join(name);
+            for (path, contents) in [("pack.mcmeta", PACK_META)].iter().chain(files) {
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+        }
+
+        fn add_zip(&self, name: &str, files: &[(&str, &str)]) {
+            let mut zip = ZipWriter::new(File::create(self.0.join(name)).unwrap());
+            for (path, contents) in [("pack.mcmeta", PACK_META)].iter().chain(files) {
+                zip.start_file(*path, FileOptions::default()).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+    }
+
+    impl Drop for PacksDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read(resources: &ResourceManager, identifier: &str) -> (String, String) {
+        let resource = resources.get(identifier.parse().unwrap()).unwrap();
+        let pack = resource.pack.clone();
+        (pack, resource.into_utf8().unwrap())
+    }
+
+    #[test]
+    fn later_packs_override_earlier_ones() {
+        let packs = PacksDirectory::new("priority");
+        packs.add_directory(
+            "a",
+            &[
+                ("assets/minecraft/texts/shared.txt", "from a"),
+                ("assets/minecraft/texts/only_a.txt", "only in a"),
+            ],
+        );
+        packs.add_zip("b.zip", &[("assets/minecraft/texts/shared.txt", "from b")]);
+        let resources = ResourceManager::scan(Some(&packs.0));
+
+        assert_eq!(read(&resources, "texts/shared.txt"), ("b.zip".into(), "from b".into()));
+        assert_eq!(read(&resources, "texts/only_a.txt"), ("a".into(), "only in a".into()));
+        assert!(matches!(
+            resources.get("texts/missing.txt".parse().unwrap()),
+            Err(ResourceError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn lists_and_reads_directory_and_zip_packs() {
+        let packs = PacksDirectory::new("listing");
+        packs.add_directory(
+            "directory",
+            &[
+                ("assets/minecraft/textures/block/granite.png", "granite"),
+                ("assets/mod/textures/block/ore.png", "ore"),
+                ("assets/mod/textures/block/ore.png.mcmeta", "{}"),
+            ],
+        );
+        packs.add_zip(
+            "zipped.zip",
+            &[
+                ("assets/minecraft/textures/block/granite.png", "zipped granite"),
+                ("assets/minecraft/textures/block/deep/andesite.png", "andesite"),
+                ("assets/minecraft/textures/item/stick.png", "stick"),
+            ],
+        );
+        // Not a pack, skipped with a warning
+        fs::write(packs.0.join("notes.txt"), "").unwrap();
+        let resources = ResourceManager::scan(Some(&packs.0));
+
+        // The embedded pack adds its own block textures, only the ones
+        // written here are checked
+        let listed: Vec<String> = resources
+            .list("textures/block", ".png")
+            .iter()
+            .map(|identifier| identifier.to_string())
+            .filter(|identifier| {
+                ["granite", "andesite", "ore"].iter().any(|name| identifier.contains(name))
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                "minecraft:textures/block/deep/andesite.png",
+                "minecraft:textures/block/granite.png",
+                "mod:textures/block/ore.png",
+            ]
+        );
+
+        assert_eq!(
+            read(&resources, "minecraft:textures/block/deep/andesite.png"),
+            ("zipped.zip".into(), "andesite".into())
+        );
+        assert_eq!(
+            read(&resources, "mod:textures/block/ore.png"),
+            ("directory".into(), "ore".into())
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use thiserror::Error;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::resources::Resources;
+
+// The resource pack format version for the assets this client understands
+pub const PACK_FORMAT: u32 = 8;
+
+const PACK_META: &str = "pack.mcmeta";
+
+#[derive(Debug, Error)]
+pub enum PackError {
+    #[error("pack {0} is missing pack.mcmeta")]
+    MissingMetadata(String),
+    #[error("pack {0} has an invalid pack.mcmeta: {1}")]
+    InvalidMetadata(String, serde_json::Error),
+    #[error("unable to open pack {0}: {1}")]
+    Io(String, io::Error),
+    #[error("unable to read zip pack {0}: {1}")]
+    Zip(String, ZipError),
+    #[error("{0} is not a directory or zip pack")]
+    Unsupported(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PackMetadata {
+    pub pack_format: u32,
+    // Descriptions may be plain strings or text components
+    #[serde(default)]
+    pub description: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct PackMetadataFile {
+    pack: PackMetadata,
+}
+
+impl PackMetadata {
+    fn parse(name: &str, data: Option<Vec<u8>>) -> Result<PackMetadata, PackError> {
+        let data = data.ok_or_else(|| PackError::MissingMetadata(name.to_string()))?;
+        let file: PackMetadataFile = serde_json::from_slice(&data)
+            .map_err(|err| PackError::InvalidMetadata(name.to_string(), err))?;
+        let metadata = file.pack;
+        // Like vanilla, packs for other versions still load since most of
+        // their assets usually work
+        if metadata.pack_format != PACK_FORMAT {
+            eprintln!(
+                "Pack {} has pack_format {} but {} is expected, it may not work correctly",
+                name, metadata.pack_format, PACK_FORMAT
+            );
+        }
+        Ok(metadata)
+    }
+
+    // The description with formatting dropped, text components are
+    // flattened in order
+    pub fn description_text(&self) -> String {
+        let mut text = String::new();
+        flatten_text(&self.description, &mut text);
+        text
+    }
+}
+
+fn flatten_text(component: &serde_json::Value, text: &mut String) {
+    match component {
+        serde_json::Value::String(value) => text.push_str(value),
+        serde_json::Value::Array(components) => {
+            for component in components {
+                flatten_text(component, text);
+            }
+        }
+        serde_json::Value::Object(fields) => {
+            if let Some(value) = fields.get("text") {
+                flatten_text(value, text);
+            }
+            if let Some(extra) = fields.get("extra") {
+                flatten_text(extra, text);
+            }
+        }
+        _ => {}
+    }
+}
+
+// A source of resources, paths are relative to the pack root (e.g. assets/minecraft/texts/end.txt)
+pub trait Pack: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Ok(None) when the pack doesn't contain the path
+    fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>>;
+
+    // The embedded default pack has no metadata
+    fn metadata(&self) -> Option<&PackMetadata>;
+
+    // Namespaces with a directory under assets/
+    fn namespaces(&self) -> io::Result<Vec<String>>;
+
+    // Paths of every file below directory (e.g. assets/minecraft/textures/block)
+    fn list(&self, directory: &str) -> io::Result<Vec<String>>;
+}
+
+// Opens a directory or .zip pack at the provided path
+pub fn open_pack(path: &Path) -> Result<Box<dyn Pack>, PackError> {
+    if path.is_dir() {
+        Ok(Box::new(DirectoryPack::open(path)?))
+    } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip")) {
+        Ok(Box::new(ZipPack::open(path)?))
+    } else {
+        Err(PackError::Unsupported(path.display().to_string()))
+    }
+}
+
+const ASSETS: &str = "assets";
+
+// Collects the namespaces from a flat list of file paths
+fn namespaces_in<'a>(paths: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut namespaces: Vec<String> = paths
+        .filter_map(|path| path.strip_prefix(ASSETS)?.strip_prefix('/')?.split_once('/'))
+        .map(|(namespace, _)| namespace.to_string())
+        .collect();
+    namespaces.sort();
+    namespaces.dedup();
+    namespaces
+}
+
+fn list_in<'a>(paths: impl Iterator<Item = &'a str>, directory: &str) -> Vec<String> {
+    let prefix = format!("{}/", directory.trim_end_matches('/'));
+    paths
+        .filter(|path| path.starts_with(&prefix) && !path.ends_with('/'))
+        .map(|path| path.to_string())
+        .collect()
+}
+
+fn pack_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+pub struct EmbeddedPack;
+
+impl Pack for EmbeddedPack {
+    fn name(&self) -> &str {
+        "default"
+    }
+
+    fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(Resources::get(path).map(|file| file.data.into_owned()))
+    }
+
+    fn metadata(&self) -> Option<&PackMetadata> {
+        None
+    }
+
+    fn namespaces(&self) -> io::Result<Vec<String>> {
+        let paths: Vec<_> = Resources::iter().collect();
+        Ok(namespaces_in(paths.iter().map(|path| path.as_ref())))
+    }
+
+    fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+        let paths: Vec<_> = Resources::iter().collect();
+        Ok(list_in(paths.iter().map(|path| path.as_ref()), directory))
+    }
+}
+
+pub struct DirectoryPack {
+    name: String,
+    root: PathBuf,
+    metadata: PackMetadata,
+}
+
+impl DirectoryPack {
+    pub fn open(root: &Path) -> Result<DirectoryPack, PackError> {
+        let name = pack_name(root);
+        let metadata = match fs::read(root.join(PACK_META)) {
+            Ok(data) => Some(data),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(PackError::Io(name, err)),
+        };
+        let metadata = PackMetadata::parse(&name, metadata)?;
+        Ok(DirectoryPack {
+            name,
+            root: root.to_path_buf(),
+            metadata,
+        })
+    }
+}
+
+impl Pack for DirectoryPack {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.root.join(path)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn metadata(&self) -> Option<&PackMetadata> {
+        Some(&self.metadata)
+    }
+
+    fn namespaces(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.root.join(ASSETS)) {
+            Ok(value) => value,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut namespaces = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                namespaces.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        namespaces.sort();
+        Ok(namespaces)
+    }
+
+    fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut pending = vec![directory.trim_end_matches('/').to_string()];
+        while let Some(directory) = pending.pop() {
+            let entries = match fs::read_dir(self.root.join(&directory)) {
+                Ok(value) => value,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for entry in entries {
+                let entry = entry?;
+                // Pack paths always use / regardless of platform
+                let path = format!("{}/{}", directory, entry.file_name().to_string_lossy());
+                if entry.file_type()?.is_dir() {
+                    pending.push(path);
+                } else {
+                    paths.push(path);
+                }
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+pub struct ZipPack {
+    name: String,
+    // Reading zip entries needs mutable access to the underlying file
+    archive: Mutex<ZipArchive<File>>,
+    metadata: PackMetadata,
+}
+
+impl ZipPack {
+    pub fn open(path: &Path) -> Result<ZipPack, PackError> {
+        let name = pack_name(path);
+        let file = File::open(path).map_err(|err| PackError::Io(name.clone(), err))?;
+        let mut archive = ZipArchive::new(file).map_err(|err| PackError::Zip(name.clone(), err))?;
+        let metadata =
+            read_entry(&mut archive, PACK_META).map_err(|err| PackError::Io(name.clone(), err))?;
+        let metadata = PackMetadata::parse(&name, metadata)?;
+        Ok(ZipPack {
+            name,
+            archive: Mutex::new(archive),
+            metadata,
+        })
+    }
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, path: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut entry = match archive.by_name(path) {
+        Ok(value) => value,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(ZipError::Io(err)) => return Err(err),
+        Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+    };
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut data)?;
+    Ok(Some(data))
+}
+
+impl Pack for ZipPack {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut archive = self.archive.lock().unwrap();
+        read_entry(&mut archive, path)
+    }
+
+    fn metadata(&self) -> Option<&PackMetadata> {
+        Some(&self.metadata)
+    }
+
+    fn namespaces(&self) -> io::Result<Vec<String>> {
+        let archive = self.archive.lock().unwrap();
+        Ok(namespaces_in(archive.file_names()))
+    }
+
+    fn list(&self, directory: &str) -> io::Result<Vec<String>> {
+        let archive = self.archive.lock().unwrap();
+        Ok(list_in(archive.file_names(), directory))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<PackMetadata, PackError> {
+        PackMetadata::parse("test", Some(json.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let metadata = parse(r#"{"pack": {"pack_format": 8, "description": "Test"}}"#).unwrap();
+        assert_eq!(metadata.pack_format, PACK_FORMAT);
+        assert_eq!(metadata.description, "Test");
+    }
+
+    #[test]
+    fn flattens_text_component_descriptions() {
+        let metadata = parse(
+            r#"{"pack": {"pack_format": 8, "description": [
+                "Faithful ",
+                {"text": "x32", "color": "gold", "extra": [{"text": " edit"}]},
+                {"translate": "pack.source"}
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.description_text(), "Faithful x32 edit");
+        assert_eq!(parse(r#"{"pack": {"pack_format": 8}}"#).unwrap().description_text(), "");
+    }
+
+    #[test]
+    fn loads_other_pack_formats() {
+        let metadata = parse(r#"{"pack": {"pack_format": 6}}"#).unwrap();
+        assert_eq!(metadata.pack_format, 6);
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_metadata() {
+        assert!(matches!(
+            PackMetadata::parse("test", None),
+            Err(PackError::MissingMetadata(_))
+        ));
+        assert!(matches!(
+            parse(r#"{"pack": {}}"#),
+            Err(PackError::InvalidMetadata(..))
+        ));
+    }
+
+    #[test]
+    fn reports_unreadable_metadata_as_io_errors() {
+        // A directory where pack.mcmeta should be fails to read with
+        // something other than NotFound
+        let root = std::env::temp_dir().join(format!("rmc-pack-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join(PACK_META)).unwrap();
+        let result = DirectoryPack::open(&root);
+        fs::remove_dir_all(&root).unwrap();
+        assert!(matches!(result, Err(PackError::Io(..))));
+    }
+}
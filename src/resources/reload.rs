@@ -0,0 +1,263 @@
+use std::any::Any;
+use std::error::Error;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::render::util::GlStateManager;
+use crate::resources::ResourceManager;
+
+pub type Prepared = Box<dyn Any + Send>;
+
+// Listeners fail with their own error types, shaders and post chains with
+// more than a missing resource
+pub type PrepareError = Box<dyn Error + Send + Sync>;
+
+// Work run on the reload thread against the freshly scanned packs
+pub type PrepareTask = Box<dyn FnOnce(&ResourceManager) -> Result<Prepared, PrepareError> + Send>;
+
+// Anything holding state built from resources (textures, shaders, models,
+// fonts, lang tables) that must be rebuilt when the packs change
+pub trait ReloadListener {
+    fn name(&self) -> &str;
+
+    // Called on the render thread, the returned task loads and decodes
+    // everything needed without touching GL
+    fn prepare(&self) -> PrepareTask;
+
+    // Called on the render thread with the result of the prepare task once
+    // every listener has prepared successfully
+    fn apply(&mut self, gl: &mut GlStateManager, prepared: Prepared);
+}
+
+// Name of the listener that failed and why
+type ReloadError = (String, PrepareError);
+
+struct Reloaded {
+    resources: ResourceManager,
+    // Results by listener name
+    prepared: Vec<(String, Prepared)>,
+}
+
+pub struct ReloadManager {
+    pending: Option<Receiver<Result<Reloaded, ReloadError>>>,
+    // Set when a reload is requested while another is still in progress
+    queued: bool,
+    watcher: Option<(RecommendedWatcher, Receiver<DebouncedEvent>)>,
+}
+
+impl ReloadManager {
+    const WATCH_DELAY: Duration = Duration::from_millis(500);
+
+    pub fn new() -> ReloadManager {
+        ReloadManager {
+            pending: None,
+            queued: false,
+            watcher: None,
+        }
+    }
+
+    // Watches the directory packs are loaded from and starts a reload
+    // whenever something inside of it changes
+    pub fn watch(&mut self, directory: &Path) {
+        let (tx, rx) = channel();
+        let mut value = match watcher(tx, ReloadManager::WATCH_DELAY) {
+            Ok(value) => value,
+            Err(err) => {
+                eprintln!("Unable to watch resource packs: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = value.watch(directory, RecursiveMode::Recursive) {
+            eprintln!("Unable to watch resource packs in {}: {}", directory.display(), err);
+            return;
+        }
+        self.watcher = Some((value, rx));
+    }
+
+    pub fn is_reloading(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Re-scans the packs and prepares every listener on a background thread,
+    // if a reload is already in progress another is started once it finishes
+    pub fn begin(&mut self, resources: &ResourceManager, listeners: &[&mut dyn ReloadListener]) {
+        if self.is_reloading() {
+            self.queued = true;
+            return;
+        }
+        println!("Reloading resources");
+        let tasks: Vec<(String, PrepareTask)> = listeners
+            .iter()
+            .map(|listener| (listener.name().to_string(), listener.prepare()))
+            .collect();
+        let directory = resources.packs_directory().map(Path::to_path_buf);
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let resources = ResourceManager::scan(directory.as_deref());
+            let result = tasks
+                .into_iter()
+                .map(|(name, task)| match task(&resources) {
+                    Ok(prepared) => Ok((name, prepared)),
+                    Err(err) => Err((name, err)),
+                })
+                .collect::<Result<Vec<_>, ReloadError>>()
+                .map(|prepared| Reloaded { resources, prepared });
+            let _ = tx.send(result);
+        });
+        self.pending = Some(rx);
+    }
+
+    // Called every frame on the render thread. Starts a reload if the watcher
+    // saw changes and applies a finished reload, returning the new resources.
+    // Results go to the listener with the same name, listeners that weren't
+    // passed to begin keep what they had
+    pub fn poll(
+        &mut self,
+        gl: &mut GlStateManager,
+        resources: &ResourceManager,
+        listeners: &mut [&mut dyn ReloadListener],
+    ) -> Option<Arc<ResourceManager>> {
+        if let Some((_, events)) = &self.watcher {
+            // Counted rather than found so every pending event is drained
+            let changed = events
+                .try_iter()
+                .filter(|event| {
+                    !matches!(
+                        event,
+                        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_)
+                    )
+                })
+                .count();
+            if changed > 0 {
+                self.begin(resources, listeners);
+            }
+        }
+
+        let result = match self.pending.as_ref()?.try_recv() {
+            Ok(value) => value,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => {
+                self.pending = None;
+                eprintln!("Resource reload thread stopped unexpectedly, keeping old resources");
+                return None;
+            }
+        };
+        self.pending = None;
+        let reloaded = match result {
+            Ok(reloaded) => {
+                for (name, prepared) in reloaded.prepared {
+                    if let Some(listener) = listeners.iter_mut().find(|listener| listener.name() == name) {
+                        listener.apply(gl, prepared);
+                    }
+                }
+                println!("Reloaded resources");
+                Some(Arc::new(reloaded.resources))
+            }
+            Err((name, err)) => {
+                eprintln!("Failed to reload {}, keeping old resources: {}", name, err);
+                None
+            }
+        };
+        if self.queued {
+            self.queued = false;
+            self.begin(reloaded.as_deref().unwrap_or(resources), listeners);
+        }
+        reloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+    use crate::resources::Identifier;
+
+    struct EndText {
+        name: &'static str,
+        text: Option<String>,
+    }
+
+    impl ReloadListener for EndText {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn prepare(&self) -> PrepareTask {
+            Box::new(|resources| {
+                let identifier = Identifier::minecraft("texts/end.txt").unwrap();
+                Ok(Box::new(resources.get_utf8(identifier)?) as Prepared)
+            })
+        }
+
+        fn apply(&mut self, _gl: &mut GlStateManager, prepared: Prepared) {
+            self.text = Some(*prepared.downcast::<String>().unwrap());
+        }
+    }
+
+    fn wait(
+        reloader: &mut ReloadManager,
+        gl: &mut GlStateManager,
+        listeners: &mut [&mut dyn ReloadListener],
+    ) -> Option<Arc<ResourceManager>> {
+        let resources = ResourceManager::new();
+        while reloader.is_reloading() {
+            if let Some(resources) = reloader.poll(gl, &resources, listeners) {
+                return Some(resources);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn applies_results_by_listener_name() {
+        let mut gl = GlStateManager::new(Rc::new(RecordingGl::new()));
+        let mut first = EndText { name: "first", text: None };
+        let mut second = EndText { name: "second", text: None };
+        let mut reloader = ReloadManager::new();
+        reloader.begin(&ResourceManager::new(), &[&mut first]);
+
+        // second wasn't prepared and first moved, neither matters
+        let resources = wait(&mut reloader, &mut gl, &mut [&mut second, &mut first]);
+        assert!(resources.is_some());
+        assert_eq!(first.text.as_deref(), Some("Thanks for playing.\n"));
+        assert_eq!(second.text, None);
+    }
+
+    struct Missing;
+
+    impl ReloadListener for Missing {
+        fn name(&self) -> &str {
+            "missing"
+        }
+
+        fn prepare(&self) -> PrepareTask {
+            Box::new(|resources| {
+                let identifier = Identifier::minecraft("texts/missing.txt").unwrap();
+                Ok(Box::new(resources.get_utf8(identifier)?) as Prepared)
+            })
+        }
+
+        fn apply(&mut self, _gl: &mut GlStateManager, _prepared: Prepared) {
+            panic!("applied a failed reload")
+        }
+    }
+
+    #[test]
+    fn keeps_everything_when_a_listener_fails() {
+        let mut gl = GlStateManager::new(Rc::new(RecordingGl::new()));
+        let mut text = EndText { name: "text", text: None };
+        let mut missing = Missing;
+        let mut reloader = ReloadManager::new();
+        reloader.begin(&ResourceManager::new(), &[&mut text, &mut missing]);
+        assert!(wait(&mut reloader, &mut gl, &mut [&mut text, &mut missing]).is_none());
+        assert_eq!(text.text, None);
+    }
+}
@@ -0,0 +1,92 @@
+use ultraviolet::Vec3;
+
+use crate::render::chunk::{Block, Section};
+
+// What the game draws, picked with --scene for headless runs and by each
+// golden scene. The window shows the chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneKind {
+    // Only the clear colour
+    Empty,
+    // One stone block
+    Block,
+    // A stone floor and walls behind plain and stained glass
+    Chunk,
+    // Lines of text in the GUI font and no world
+    GuiText,
+}
+
+impl SceneKind {
+    pub const ALL: [SceneKind; 4] = [
+        SceneKind::Empty,
+        SceneKind::Block,
+        SceneKind::Chunk,
+        SceneKind::GuiText,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SceneKind::Empty => "empty",
+            SceneKind::Block => "block",
+            SceneKind::Chunk => "chunk",
+            SceneKind::GuiText => "gui_text",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SceneKind> {
+        SceneKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    // Blocks drawn with their section at the origin, None without a world
+    pub fn section(self) -> Option<Section> {
+        let mut section = Section::new();
+        match self {
+            SceneKind::Empty | SceneKind::GuiText => return None,
+            SceneKind::Block => section.set(0, 0, 0, Block::Stone),
+            SceneKind::Chunk => {
+                section.fill((0, 0, 0), (7, 0, 7), Block::Stone);
+                section.fill((0, 1, 0), (7, 3, 0), Block::Stone);
+                section.fill((0, 1, 1), (0, 2, 7), Block::Stone);
+                // Panes of glass in front of each other and the walls so
+                // both blend over the stone and over one another
+                section.fill((2, 1, 3), (5, 3, 3), Block::Glass);
+                section.fill((3, 1, 5), (6, 2, 5), Block::RedStainedGlass);
+                section.set(6, 1, 2, Block::RedStainedGlass);
+            }
+        }
+        Some(section)
+    }
+
+    // Centre of the blocks and how far and high above it the camera orbits
+    fn orbit(self) -> (Vec3, f32, f32) {
+        match self {
+            SceneKind::Block => (Vec3::new(0.5, 0.5, 0.5), 2.5, 1.5),
+            _ => (Vec3::new(4.0, 1.0, 4.0), 7.5, 5.0),
+        }
+    }
+
+    // Camera position and the point it looks at after ticks game ticks, one
+    // turn around the blocks takes a minute
+    pub fn camera(self, ticks: f32) -> (Vec3, Vec3) {
+        const TICKS_PER_TURN: f32 = 1200.0;
+        let (centre, distance, height) = self.orbit();
+        // Starts looking in from the south east
+        let angle = std::f32::consts::FRAC_PI_4 + ticks / TICKS_PER_TURN * std::f32::consts::TAU;
+        let eye = centre + Vec3::new(angle.sin() * distance, height, angle.cos() * distance);
+        (eye, centre)
+    }
+
+    // Lines drawn at the top left of the GUI
+    pub fn text(self) -> &'static [(&'static str, [u8; 4])] {
+        match self {
+            SceneKind::GuiText => &[
+                ("Rust MC", [0x20, 0x20, 0x20, 0xFF]),
+                ("The quick brown fox", [0xAA, 0x00, 0x00, 0xFF]),
+                ("jumps over the lazy dog.", [0xAA, 0x00, 0x00, 0xFF]),
+                ("0123456789 !\"#$%&'()*+,-./", [0x00, 0x00, 0xAA, 0xFF]),
+                (":;<=>?@[\\]^_`{|}~", [0x00, 0x80, 0x00, 0xFF]),
+            ],
+            _ => &[],
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use gl33::*;
+use thiserror::Error;
+use ultraviolet::{Mat4, Vec3};
+
+use crate::render::texture::{ImageError, NativeImage};
+use crate::render::util::GlStateManager;
+use crate::window::Framebuffer;
+
+pub const DIRECTORY: &str = "screenshots";
+
+#[derive(Debug, Error)]
+pub enum ScreenshotError {
+    #[error("unable to encode screenshot: {0}")]
+    Encode(#[from] ImageError),
+    #[error("unable to save screenshot to {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+}
+
+// Reads back the area of the framebuffer that was rendered to, top row
+// first and fully opaque
+pub fn read_pixels(gl: &mut GlStateManager, framebuffer: &Framebuffer) -> NativeImage {
+    let (texture_width, texture_height) = framebuffer.texture_size();
+    let (width, height) = framebuffer.viewport_size();
+    let mut pixels = vec![0; (texture_width * texture_height * 4) as usize];
+    let backend = gl.backend().clone();
+    backend.pixel_store_i(GL_PACK_ALIGNMENT, 4);
+    framebuffer.begin_read(gl);
+    backend.get_tex_image(GL_TEXTURE_2D, 0, GL_RGBA, GL_UNSIGNED_BYTE, &mut pixels);
+    framebuffer.end_read(gl);
+    // The world is drawn without writing alpha
+    pixels
+        .iter_mut()
+        .skip(3)
+        .step_by(4)
+        .for_each(|alpha| *alpha = 0xFF);
+    let image = NativeImage::from_pixels(texture_width as u32, texture_height as u32, pixels);
+    // The viewport is the bottom left of the texture, which is read first
+    let mut image = if (width, height) == (texture_width, texture_height) {
+        image
+    } else {
+        image.sub_image(0, 0, width as u32, height as u32)
+    };
+    image.flip_vertical();
+    image
+}
+
+// Saves the image as <date>_<time>.png in directory, adding _1, _2 and so on
+// if a screenshot was already taken that second. Returns the path written
+pub fn save(image: &NativeImage, directory: &Path) -> Result<PathBuf, ScreenshotError> {
+    fs::create_dir_all(directory).map_err(|source| ScreenshotError::Io {
+        path: directory.to_path_buf(),
+        source,
+    })?;
+    let path = unique_path(directory);
+    write(image, &path)?;
+    Ok(path)
+}
+
+// Encodes the image as a PNG at path, replacing any file already there
+pub fn write(image: &NativeImage, path: &Path) -> Result<(), ScreenshotError> {
+    let data = image.encode_png()?;
+    fs::write(path, data).map_err(|source| ScreenshotError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn unique_path(directory: &Path) -> PathBuf {
+    let timestamp = Local::now().format("%Y-%m-%d_%H.%M.%S").to_string();
+    let mut path = directory.join(format!("{}.png", timestamp));
+    let mut suffix = 1;
+    while path.exists() {
+        path = directory.join(format!("{}_{}.png", timestamp, suffix));
+        suffix += 1;
+    }
+    path
+}
+
+// A screenshot larger than the framebuffer, rendered one framebuffer sized
+// tile at a time with the projection zoomed in on each tile
+pub struct HugeScreenshot {
+    image: NativeImage,
+    tile_width: u32,
+    tile_height: u32,
+    columns: u32,
+    rows: u32,
+    // Index of the tile rendered next, row by row from the top left
+    next: u32,
+}
+
+impl HugeScreenshot {
+    pub fn new(width: u32, height: u32, tile_width: u32, tile_height: u32) -> HugeScreenshot {
+        HugeScreenshot {
+            image: NativeImage::new(width, height),
+            tile_width,
+            tile_height,
+            columns: width.div_ceil(tile_width),
+            rows: height.div_ceil(tile_height),
+            next: 0,
+        }
+    }
+
+    pub fn tile_count(&self) -> u32 {
+        self.columns * self.rows
+    }
+
+    // Matrix applied after the projection so the next tile fills the
+    // framebuffer, None once every tile is captured
+    pub fn tile_projection(&self) -> Option<Mat4> {
+        if self.next >= self.tile_count() {
+            return None;
+        }
+        let width = self.image.width() as f32;
+        let height = self.image.height() as f32;
+        let tile_width = self.tile_width as f32;
+        let tile_height = self.tile_height as f32;
+        let x = (self.next % self.columns * self.tile_width) as f32;
+        // Tiles go down from the top but GL y goes up from the bottom
+        let y = height - (self.next / self.columns * self.tile_height) as f32 - tile_height;
+        let scale = Vec3::new(width / tile_width, height / tile_height, 1.0);
+        let offset = Vec3::new(
+            scale.x - 1.0 - 2.0 * x / tile_width,
+            scale.y - 1.0 - 2.0 * y / tile_height,
+            0.0,
+        );
+        Some(Mat4::from_translation(offset) * Mat4::from_nonuniform_scale(scale))
+    }
+
+    // Copies the rendered tile into the image and moves on to the next
+    pub fn capture(&mut self, gl: &mut GlStateManager, framebuffer: &Framebuffer) {
+        let tile = read_pixels(gl, framebuffer);
+        let x = self.next % self.columns * self.tile_width;
+        let y = self.next / self.columns * self.tile_height;
+        // Tiles on the right and bottom edges hang over the image
+        let width = tile.width().min(self.image.width() - x);
+        let height = tile.height().min(self.image.height() - y);
+        let tile = if (width, height) == (tile.width(), tile.height()) {
+            tile
+        } else {
+            tile.sub_image(0, 0, width, height)
+        };
+        self.image.copy_from(&tile, x, y);
+        self.next += 1;
+    }
+
+    pub fn finish(self) -> NativeImage {
+        self.image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ultraviolet::Vec4;
+
+    use super::*;
+    use crate::headless::HeadlessContext;
+    use crate::render::util;
+
+    #[test]
+    fn reads_back_the_top_row_first() {
+        // Fails rather than passing without checking anything when neither
+        // Mesa nor a display is available
+        let context = HeadlessContext::new(16, 16)
+            .unwrap_or_else(|err| panic!("Reading back needs a GL context: {}", err));
+        let mut gl = unsafe { util::load_gl(|name| context.get_proc_address(name)) };
+        let mut framebuffer = Framebuffer::new(&mut gl, 4, 3).unwrap();
+        framebuffer.set_clear_color(1.0, 0.0, 0.0, 0.5);
+        framebuffer.clear(&mut gl);
+        // GL's first row is the bottom one
+        let mut row = NativeImage::new(4, 1);
+        for x in 0..4 {
+            row.set_pixel(x, 0, [0, 0, 0xFF, 0xFF]);
+        }
+        gl.bind_texture(framebuffer.color_texture());
+        row.upload_sub(&mut gl, 0, 0, 0);
+        gl.bind_texture(GL_ZERO);
+
+        let image = read_pixels(&mut gl, &framebuffer);
+        assert_eq!((image.width(), image.height()), (4, 3));
+        for x in 0..4 {
+            // Alpha is forced opaque
+            assert_eq!(image.pixel(x, 0), [0xFF, 0, 0, 0xFF]);
+            assert_eq!(image.pixel(x, 1), [0xFF, 0, 0, 0xFF]);
+            assert_eq!(image.pixel(x, 2), [0, 0, 0xFF, 0xFF]);
+        }
+
+        let decoded = NativeImage::decode(&image.encode_png().unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 3));
+        assert_eq!(decoded, image);
+    }
+
+    #[test]
+    fn zooms_in_on_each_tile_in_turn() {
+        let mut huge = HugeScreenshot::new(100, 60, 40, 40);
+        assert_eq!(huge.tile_count(), 6);
+        let mut tiles = Vec::new();
+        while let Some(projection) = huge.tile_projection() {
+            // Image pixels, from the top left, to normalized device coordinates
+            let ndc = |x: f32, y: f32| {
+                let point =
+                    projection * Vec4::new(2.0 * x / 100.0 - 1.0, 1.0 - 2.0 * y / 60.0, 0.0, 1.0);
+                (point.x, point.y)
+            };
+            let (x, y) = ((huge.next % 3 * 40) as f32, (huge.next / 3 * 40) as f32);
+            // The tile's corners land on the corners of the framebuffer,
+            // tiles on the edges hang over the image
+            for ((actual_x, actual_y), (expected_x, expected_y)) in [
+                (ndc(x, y), (-1.0, 1.0)),
+                (ndc(x + 40.0, y + 40.0), (1.0, -1.0)),
+            ] {
+                assert!(
+                    (actual_x - expected_x).abs() < 1e-5 && (actual_y - expected_y).abs() < 1e-5
+                );
+            }
+            tiles.push((x, y));
+            huge.next += 1;
+        }
+        assert_eq!(
+            tiles,
+            [
+                (0.0, 0.0),
+                (40.0, 0.0),
+                (80.0, 0.0),
+                (0.0, 40.0),
+                (40.0, 40.0),
+                (80.0, 40.0)
+            ]
+        );
+    }
+}
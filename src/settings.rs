@@ -0,0 +1,57 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// User options stored one key:value pair per line
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Mip levels generated for texture atlases, 0 disables mipmapping
+    pub mipmap_levels: u32,
+}
+
+impl Settings {
+    pub const MAX_MIPMAP_LEVELS: u32 = 4;
+
+    pub fn new() -> Settings {
+        Settings {
+            mipmap_levels: Settings::MAX_MIPMAP_LEVELS,
+        }
+    }
+
+    // Missing files give the defaults, unknown keys and invalid values are
+    // skipped with a warning
+    pub fn load(path: &Path) -> Settings {
+        let mut settings = Settings::new();
+        let contents = match fs::read_to_string(path) {
+            Ok(value) => value,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return settings,
+            Err(err) => {
+                eprintln!("Unable to read settings from {}: {}", path.display(), err);
+                return settings;
+            }
+        };
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = match line.split_once(':') {
+                Some(value) => value,
+                None => {
+                    eprintln!("Skipping malformed setting: {}", line);
+                    continue;
+                }
+            };
+            match key {
+                "mipmapLevels" => match value.parse::<u32>() {
+                    Ok(levels) if levels <= Settings::MAX_MIPMAP_LEVELS => {
+                        settings.mipmap_levels = levels
+                    }
+                    _ => eprintln!("Skipping invalid mipmapLevels: {}", value),
+                },
+                _ => eprintln!("Skipping unknown setting: {}", key),
+            }
+        }
+        settings
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, format!("mipmapLevels:{}\n", self.mipmap_levels))
+    }
+}
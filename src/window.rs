@@ -1,17 +1,35 @@
-use std::cmp::max;
-use std::ptr::null;
+use std::rc::Rc;
 
 use gl33::*;
-use gl33::global_loader::*;
+use thiserror::Error;
+use ultraviolet::projection::rh_yup::orthographic_gl;
+use ultraviolet::{Mat4, Vec3, Vec4};
 
-use crate::render::util::{bind_texture, color_mask, depth_mask, disable_blend, disable_depth_test, gen_texture_id, max_supported_texture_size, viewport};
+use crate::render::backend::GlBackend;
+use crate::render::shader::Shader;
+use crate::render::uniform::StandardUniforms;
+use crate::render::util::{GlStateManager, RenderState, TextureDeleter};
+use crate::render::vertex_buffer::{BufferUsage, VertexBuffer};
+use crate::render::{BufferBuilder, DrawMode, VertexError, VertexFormat};
 use crate::types::{GLint, GLsizei, GLuint};
 
+#[derive(Debug, Error)]
+pub enum FramebufferError {
+    #[error("not even the default framebuffer size {0}x{1} fits in memory")]
+    NoCompatibleSize(GLsizei, GLsizei),
+    #[error("framebuffer is incomplete: {0}")]
+    Incomplete(&'static str),
+    #[error("framebuffer is incomplete with status {0:#x}")]
+    UnknownStatus(u32),
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Size(GLsizei, GLsizei);
 
 pub struct Framebuffer {
-    size: Size,
+    backend: Rc<dyn GlBackend>,
+    deleter: TextureDeleter,
+    // Size of the attachments, may be larger than the area rendered to
     texture_width: GLsizei,
     texture_height: GLsizei,
     viewport_width: GLsizei,
@@ -20,6 +38,11 @@ pub struct Framebuffer {
     depth_attachment: Option<TextureUnit>,
     fbo: Option<GLuint>,
     clear_color: [f32; 4],
+    // GL_NEAREST or GL_LINEAR for the color attachment
+    filter: GLint,
+    // Fullscreen quad drawn by draw
+    blit_builder: BufferBuilder,
+    blit_buffer: VertexBuffer,
 }
 
 impl Framebuffer {
@@ -27,9 +50,10 @@ impl Framebuffer {
     const DEFAULT_HEIGHT: GLsizei = 480;
     const DEFAULT_SIZE: Size = Size(Framebuffer::DEFAULT_WIDTH, Framebuffer::DEFAULT_HEIGHT);
 
-    pub fn new(width: GLsizei, height: GLsizei) -> Framebuffer {
+    pub fn new(gl: &mut GlStateManager, width: GLsizei, height: GLsizei) -> Result<Framebuffer, FramebufferError> {
         let mut framebuffer = Framebuffer {
-            size: Framebuffer::DEFAULT_SIZE,
+            backend: gl.backend().clone(),
+            deleter: gl.texture_deleter(),
             texture_width: 0,
             texture_height: 0,
             viewport_width: 0,
@@ -38,134 +62,392 @@ impl Framebuffer {
             depth_attachment: None,
             fbo: None,
             clear_color: [1.0, 1.0, 1.0, 1.0],
+            filter: GL_NEAREST.0 as GLint,
+            blit_builder: BufferBuilder::new(4),
+            blit_buffer: VertexBuffer::new(gl, BufferUsage::Dynamic),
         };
-        unsafe {
-            framebuffer.set_suitable_size(width, height);
-            let mut fbo = 0;
-            glGenFramebuffers(1, &mut fbo);
-            framebuffer.fbo = Some(fbo);
-            glBindFramebuffer(GL_FRAMEBUFFER, fbo);
-            bind_texture(framebuffer.color_attachment.unwrap());
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, 0x2600);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, 0x2600);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, 0x812f);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, 0x812f);
-            glFramebufferTexture2D(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_TEXTURE_2D, framebuffer.color_attachment.unwrap().0, 0);
-            bind_texture(framebuffer.depth_attachment.unwrap());
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_COMPARE_MODE, 0);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, 0x2600);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, 0x2600);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, 0x812f);
-            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, 0x812f);
-            glFramebufferTexture2D(GL_FRAMEBUFFER, GL_DEPTH_ATTACHMENT, GL_TEXTURE_2D, framebuffer.depth_attachment.unwrap().0, 0);
-            bind_texture(GL_ZERO);
-            framebuffer.viewport_width = framebuffer.size.0;
-            framebuffer.viewport_width = framebuffer.size.1;
-            framebuffer.texture_width = framebuffer.size.1;
-            framebuffer.texture_height = framebuffer.size.1;
-            framebuffer.check_status();
-            glBindFramebuffer(GL_FRAMEBUFFER, 0)
+        framebuffer.fbo = Some(framebuffer.backend.gen_framebuffer());
+        framebuffer.allocate(gl, width, height)?;
+        Ok(framebuffer)
+    }
+
+    pub fn texture_size(&self) -> (GLsizei, GLsizei) {
+        (self.texture_width, self.texture_height)
+    }
+
+    pub fn viewport_size(&self) -> (GLsizei, GLsizei) {
+        (self.viewport_width, self.viewport_height)
+    }
+
+    // Reallocates the attachments for a new window size, the framebuffer
+    // object itself is kept. The old attachments are only deleted once the
+    // new ones are complete, on error the framebuffer is left as it was
+    pub fn resize(&mut self, gl: &mut GlStateManager, width: GLsizei, height: GLsizei) -> Result<(), FramebufferError> {
+        if width == self.viewport_width && height == self.viewport_height {
+            return Ok(());
         }
-        framebuffer
-    }
-
-    unsafe fn check_status(&self) {
-        let status = glCheckFramebufferStatus(GL_FRAMEBUFFER);
-        if status != GL_FRAMEBUFFER_COMPLETE {
-            if status == GL_FRAMEBUFFER_INCOMPLETE_ATTACHMENT {
-                panic!("Failed to create framebuffer: GL_FRAMEBUFFER_INCOMPLETE_ATTACHMENT")
-            } else if status == GL_FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT {
-                panic!("Failed to create framebuffer: GL_FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT")
-            } else if status == GL_FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER {
-                panic!("Failed to create framebuffer: GL_FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER")
-            } else if status == GL_FRAMEBUFFER_INCOMPLETE_READ_BUFFER {
-                panic!("Failed to create framebuffer: GL_FRAMEBUFFER_INCOMPLETE_READ_BUFFER")
-            } else if status == GL_FRAMEBUFFER_UNSUPPORTED {
-                panic!("Failed to create framebuffer: GL_FRAMEBUFFER_UNSUPPORTED")
-            } else if status == GL_OUT_OF_MEMORY {
-                panic!("Failed to create framebuffer: GL_OUT_OF_MEMORY")
-            } else {
-                panic!("Failed to create framebuffer: {:?}", status);
+        let old_attachments = (self.color_attachment.take(), self.depth_attachment.take());
+        let old_sizes = (self.texture_width, self.texture_height, self.viewport_width, self.viewport_height);
+        match self.allocate(gl, width, height) {
+            Ok(()) => {
+                let (color, depth) = old_attachments;
+                gl.delete_textures(&color.into_iter().chain(depth).collect::<Vec<_>>());
+                Ok(())
+            }
+            Err(err) => {
+                gl.delete_textures(&self.take_attachments());
+                (self.color_attachment, self.depth_attachment) = old_attachments;
+                (self.texture_width, self.texture_height, self.viewport_width, self.viewport_height) = old_sizes;
+                self.attach();
+                self.backend.bind_framebuffer(GL_FRAMEBUFFER, 0);
+                Err(err)
             }
         }
     }
 
-    unsafe fn is_compatible(&self, size: Size) -> bool {
-        self.supports_color(&size) && self.supports_depth(&size)
+    fn take_attachments(&mut self) -> Vec<TextureUnit> {
+        self.color_attachment.take().into_iter()
+            .chain(self.depth_attachment.take())
+            .collect()
+    }
+
+    fn allocate(&mut self, gl: &mut GlStateManager, width: GLsizei, height: GLsizei) -> Result<(), FramebufferError> {
+        self.color_attachment = Some(gl.gen_texture());
+        self.depth_attachment = Some(gl.gen_texture());
+        let size = self.set_suitable_size(gl, width, height)?;
+        let backend = self.backend.clone();
+        gl.bind_texture(self.color_attachment.unwrap());
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, self.filter);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, self.filter);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, 0x812f);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, 0x812f);
+        gl.bind_texture(self.depth_attachment.unwrap());
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_COMPARE_MODE, 0);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, 0x2600);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, 0x2600);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, 0x812f);
+        backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, 0x812f);
+        gl.bind_texture(GL_ZERO);
+        self.attach();
+        self.texture_width = size.0;
+        self.texture_height = size.1;
+        // Only the requested area is drawn to when falling back to the default size
+        self.viewport_width = width.clamp(1, size.0);
+        self.viewport_height = height.clamp(1, size.1);
+        let status = self.check_status();
+        backend.bind_framebuffer(GL_FRAMEBUFFER, 0);
+        status
     }
 
-    unsafe fn set_suitable_size(&mut self, width: GLsizei, height: GLsizei) -> Size {
-        let mut size = self.size.clone();
-        self.color_attachment = Some(gen_texture_id());
-        self.depth_attachment = Some(gen_texture_id());
-        let max_size = max_supported_texture_size();
+    // Leaves the framebuffer object bound
+    fn attach(&self) {
+        self.backend.bind_framebuffer(GL_FRAMEBUFFER, self.fbo.unwrap());
+        self.backend.framebuffer_texture_2d(GL_FRAMEBUFFER, GL_COLOR_ATTACHMENT0, GL_TEXTURE_2D, self.color_attachment.unwrap().0, 0);
+        self.backend.framebuffer_texture_2d(GL_FRAMEBUFFER, GL_DEPTH_ATTACHMENT, GL_TEXTURE_2D, self.depth_attachment.unwrap().0, 0);
+    }
+
+    fn check_status(&self) -> Result<(), FramebufferError> {
+        let status = self.backend.check_framebuffer_status(GL_FRAMEBUFFER);
+        let name = if status == GL_FRAMEBUFFER_COMPLETE {
+            return Ok(());
+        } else if status == GL_FRAMEBUFFER_INCOMPLETE_ATTACHMENT {
+            "GL_FRAMEBUFFER_INCOMPLETE_ATTACHMENT"
+        } else if status == GL_FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT {
+            "GL_FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT"
+        } else if status == GL_FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER {
+            "GL_FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER"
+        } else if status == GL_FRAMEBUFFER_INCOMPLETE_READ_BUFFER {
+            "GL_FRAMEBUFFER_INCOMPLETE_READ_BUFFER"
+        } else if status == GL_FRAMEBUFFER_UNSUPPORTED {
+            "GL_FRAMEBUFFER_UNSUPPORTED"
+        } else if status == GL_OUT_OF_MEMORY {
+            "GL_OUT_OF_MEMORY"
+        } else {
+            return Err(FramebufferError::UnknownStatus(status.0));
+        };
+        Err(FramebufferError::Incomplete(name))
+    }
+
+    fn is_compatible(&self, gl: &mut GlStateManager, size: Size) -> bool {
+        self.supports_color(gl, &size) && self.supports_depth(gl, &size)
+    }
+
+    // Allocates the attachments at the requested size, or the default size
+    // if that is too large
+    fn set_suitable_size(&self, gl: &mut GlStateManager, width: GLsizei, height: GLsizei) -> Result<Size, FramebufferError> {
+        let max_size = gl.max_supported_texture_size();
         if width > 0 && width <= max_size && height > 0 && height <= max_size {
             let fsize = Size(width, height);
-            if self.is_compatible(fsize) {
-                return fsize;
+            if self.is_compatible(gl, fsize) {
+                return Ok(fsize);
             }
         }
-        if !self.is_compatible(size) {
-            panic!("No compatible framebuffer size")
+        if !self.is_compatible(gl, Framebuffer::DEFAULT_SIZE) {
+            let Size(width, height) = Framebuffer::DEFAULT_SIZE;
+            return Err(FramebufferError::NoCompatibleSize(width, height));
         }
-        size
+        Ok(Framebuffer::DEFAULT_SIZE)
+    }
+
+    fn supports_color(&self, gl: &mut GlStateManager, size: &Size) -> bool {
+        self.backend.get_error();
+        gl.bind_texture(self.color_attachment.unwrap());
+        self.backend.tex_image_2d(GL_TEXTURE_2D, 0, 0x8058, size.0, size.1, GL_RGBA, GL_UNSIGNED_BYTE, None);
+        self.backend.get_error() != GL_OUT_OF_MEMORY
+    }
+
+    fn supports_depth(&self, gl: &mut GlStateManager, size: &Size) -> bool {
+        self.backend.get_error();
+        gl.bind_texture(self.depth_attachment.unwrap());
+        self.backend.tex_image_2d(GL_TEXTURE_2D, 0, 0x1902, size.0, size.1, GL_DEPTH_COMPONENT, GL_FLOAT, None);
+        self.backend.get_error() != GL_OUT_OF_MEMORY
     }
 
-    unsafe fn supports_color(&self, size: &Size) -> bool {
-        glGetError();
-        bind_texture(self.color_attachment.unwrap());
-        glTexImage2D(GL_TEXTURE_2D, 0, 0x8058, size.0, size.1, 0, GL_RGBA, GL_UNSIGNED_BYTE, null());
-        return glGetError() != GL_OUT_OF_MEMORY;
+
+    pub fn begin_read(&self, gl: &mut GlStateManager) {
+        gl.bind_texture(self.color_attachment.unwrap());
     }
 
-    unsafe fn supports_depth(&self, size: &Size) -> bool {
-        glGetError();
-        bind_texture(self.depth_attachment.unwrap());
-        glTexImage2D(GL_TEXTURE_2D, 0, 0x1902, size.0, size.1, 0, GL_DEPTH_COMPONENT, GL_FLOAT, null());
-        return glGetError() != GL_OUT_OF_MEMORY;
+    pub fn end_read(&self, gl: &mut GlStateManager) {
+        gl.bind_texture(GL_ZERO);
     }
 
+    pub fn color_texture(&self) -> TextureUnit {
+        self.color_attachment.unwrap()
+    }
 
-    unsafe fn begin_read(&self) {
-        bind_texture(self.color_attachment.unwrap());
+    pub fn depth_texture(&self) -> TextureUnit {
+        self.depth_attachment.unwrap()
     }
 
-    unsafe fn end_read(&self) {
-        bind_texture(GL_ZERO);
+    // Filtering used when the color attachment is sampled
+    pub fn set_filter(&mut self, gl: &mut GlStateManager, linear: bool) {
+        self.filter = if linear { GL_LINEAR.0 as GLint } else { GL_NEAREST.0 as GLint };
+        gl.bind_texture(self.color_attachment.unwrap());
+        self.backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, self.filter);
+        self.backend.tex_parameter_i(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, self.filter);
+        gl.bind_texture(GL_ZERO);
     }
 
-    unsafe fn bind(&self, update_viewport: bool) {
-        glBindFramebuffer(GL_FRAMEBUFFER, self.fbo.unwrap());
+    pub fn bind(&self, gl: &mut GlStateManager, update_viewport: bool) {
+        self.backend.bind_framebuffer(GL_FRAMEBUFFER, self.fbo.unwrap());
         if update_viewport {
-            glViewport(0, 0, self.viewport_width, self.viewport_height);
+            gl.viewport(0, 0, self.viewport_width, self.viewport_height);
         }
     }
 
-    unsafe fn unbind(&self) {
-        glBindFramebuffer(GL_FRAMEBUFFER, 0)
+    pub fn unbind(&self, _gl: &mut GlStateManager) {
+        self.backend.bind_framebuffer(GL_FRAMEBUFFER, 0)
     }
 
-    fn set_clear_color(&mut self, r: f32, g: f32, b: f32, a: f32) {
+    pub fn set_clear_color(&mut self, r: f32, g: f32, b: f32, a: f32) {
         self.clear_color[0] = r;
         self.clear_color[1] = g;
         self.clear_color[2] = b;
         self.clear_color[3] = a;
     }
 
+    pub fn clear(&self, gl: &mut GlStateManager) {
+        self.bind(gl, true);
+        let [r, g, b, a] = self.clear_color;
+        self.backend.clear_color(r, g, b, a);
+        self.backend.clear_depth(1.0);
+        self.backend.clear(GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT);
+        self.unbind(gl);
+    }
+
+    // Draws the color attachment over the whole of the bound framebuffer
+    // with the blit_screen shader, blending as the shader asks unless
+    // no_blend is set. The render state is put back afterwards
+    pub fn draw(&mut self, gl: &mut GlStateManager, shader: &mut Shader, uniforms: &StandardUniforms, width: GLsizei, height: GLsizei, no_blend: bool) {
+        let blend = if no_blend { None } else { shader.blend.state() };
+        let mut gl = gl.push_render_state(&RenderState { blend, ..RenderState::OVERLAY });
+        gl.color_mask(1, 1, 1, 0);
+        gl.viewport(0, 0, width, height);
+
+        let (width, height) = (width as f32, height as f32);
+        // The blit's own projection replaces the world's
+        shader.set_standard_uniforms(uniforms);
+        shader.set_sampler("DiffuseSampler", self.color_attachment.unwrap().0);
+        if let Some(uniform) = shader.uniform("ProjMat") {
+            uniform.set_mat4(&orthographic_gl(0.0, width, height, 0.0, 1000.0, 3000.0));
+        }
+        if let Some(uniform) = shader.uniform("ModelViewMat") {
+            uniform.set_mat4(&Mat4::from_translation(Vec3::new(0.0, 0.0, -2000.0)));
+        }
+        if let Some(uniform) = shader.uniform("ColorModulator") {
+            uniform.set_vec4(Vec4::one());
+        }
+
+        // Only the viewport area of the attachments was rendered to
+        let u = self.viewport_width as f32 / self.texture_width as f32;
+        let v = self.viewport_height as f32 / self.texture_height as f32;
+        self.build_quad(width, height, u, v).expect("blit quad matches BLIT_SCREEN");
+        shader.apply(&mut gl);
+        if let Some((parameters, data)) = self.blit_builder.pop_data() {
+            self.blit_buffer.upload(parameters, data);
+        }
+        self.blit_buffer.draw();
+        shader.unbind();
+        gl.color_mask(1, 1, 1, 1);
+    }
+
+    fn build_quad(&mut self, width: f32, height: f32, u: f32, v: f32) -> Result<(), VertexError> {
+        let builder = &mut self.blit_builder;
+        builder.begin(DrawMode::Quads, VertexFormat::BLIT_SCREEN);
+        builder.vertex(0.0, height, 0.0)?.uv(0.0, 0.0)?.color(255, 255, 255, 255)?.end_vertex()?;
+        builder.vertex(width, height, 0.0)?.uv(u, 0.0)?.color(255, 255, 255, 255)?.end_vertex()?;
+        builder.vertex(width, 0.0, 0.0)?.uv(u, v)?.color(255, 255, 255, 255)?.end_vertex()?;
+        builder.vertex(0.0, 0.0, 0.0)?.uv(0.0, v)?.color(255, 255, 255, 255)?.end_vertex()?;
+        builder.end()
+    }
+}
 
-    unsafe fn draw(&self, width: GLsizei, height: GLsizei, no_blend: bool) {
-        color_mask(1, 1, 1, 0);
-        disable_depth_test();
-        depth_mask(false);
-        viewport(0, 0, width, height);
-        if no_blend {
-            disable_blend();
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        let textures = self.take_attachments();
+        if !textures.is_empty() {
+            self.deleter.delete(&textures);
+        }
+        if let Some(fbo) = self.fbo.take() {
+            self.backend.delete_framebuffer(fbo);
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::recording_gl::RecordingGl;
+
+    fn state_manager() -> (Rc<RecordingGl>, GlStateManager) {
+        let backend = Rc::new(RecordingGl::new());
+        let gl = GlStateManager::new(backend.clone());
+        (backend, gl)
+    }
+
+    #[test]
+    fn allocates_the_requested_size() {
+        let (_, mut gl) = state_manager();
+        let framebuffer = Framebuffer::new(&mut gl, 320, 240).unwrap();
+        assert_eq!(framebuffer.texture_size(), (320, 240));
+        assert_eq!(framebuffer.viewport_size(), (320, 240));
+    }
+
+    #[test]
+    fn falls_back_to_the_default_size() {
+        let (backend, mut gl) = state_manager();
+        backend.set_max_texture_size(1024);
+        let framebuffer = Framebuffer::new(&mut gl, 2000, 100).unwrap();
+        assert_eq!(framebuffer.texture_size(), (845, 480));
+        assert_eq!(framebuffer.viewport_size(), (845, 100));
 
-    unsafe fn get_bound() -> GLint {
-        let mut value = 0;
-        glGetIntegerv(GL_DRAW_FRAMEBUFFER_BINDING, &mut value);
-        value
+        // Fits the texture size limit but not in memory
+        let (backend, mut gl) = state_manager();
+        backend.set_texel_limit(Some(845 * 480));
+        let framebuffer = Framebuffer::new(&mut gl, 1000, 1000).unwrap();
+        assert_eq!(framebuffer.texture_size(), (845, 480));
+        assert_eq!(framebuffer.viewport_size(), (845, 480));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fails_without_a_compatible_size() {
+        let (backend, mut gl) = state_manager();
+        backend.set_texel_limit(Some(100));
+        assert!(matches!(
+            Framebuffer::new(&mut gl, 320, 240),
+            Err(FramebufferError::NoCompatibleSize(845, 480))
+        ));
+    }
+
+    #[test]
+    fn reports_incomplete_framebuffers() {
+        let (backend, mut gl) = state_manager();
+        backend.set_framebuffer_status(GL_FRAMEBUFFER_UNSUPPORTED);
+        assert!(matches!(
+            Framebuffer::new(&mut gl, 320, 240),
+            Err(FramebufferError::Incomplete("GL_FRAMEBUFFER_UNSUPPORTED"))
+        ));
+
+        let (backend, mut gl) = state_manager();
+        backend.set_framebuffer_status(GLenum(0x1234));
+        assert!(matches!(
+            Framebuffer::new(&mut gl, 320, 240),
+            Err(FramebufferError::UnknownStatus(0x1234))
+        ));
+    }
+
+    #[test]
+    fn deletes_attachments_through_the_state_manager() {
+        let (backend, mut gl) = state_manager();
+        let framebuffer = Framebuffer::new(&mut gl, 320, 240).unwrap();
+        let color = framebuffer.color_texture();
+        framebuffer.begin_read(&mut gl);
+        drop(framebuffer);
+        assert_eq!(backend.count("glDeleteTextures"), 0);
+
+        // The next bind deletes them and has to rebind the reused id
+        backend.take_calls();
+        gl.bind_texture(color);
+        let calls = backend.take_calls();
+        assert_eq!(calls[0].function, "glDeleteTextures");
+        assert!(calls[0].args.contains(&(color.0 as i64)));
+        assert_eq!(calls[1].function, "glBindTexture");
+    }
+
+    #[test]
+    fn keeps_the_old_attachments_when_a_resize_fails() {
+        let (backend, mut gl) = state_manager();
+        let mut framebuffer = Framebuffer::new(&mut gl, 320, 240).unwrap();
+        let (color, depth) = (framebuffer.color_texture(), framebuffer.depth_texture());
+        backend.set_framebuffer_status(GL_FRAMEBUFFER_UNSUPPORTED);
+        backend.take_calls();
+        assert!(framebuffer.resize(&mut gl, 640, 480).is_err());
+        assert_eq!(framebuffer.texture_size(), (320, 240));
+        assert_eq!(framebuffer.viewport_size(), (320, 240));
+        assert_eq!((framebuffer.color_texture(), framebuffer.depth_texture()), (color, depth));
+        let calls = backend.take_calls();
+        let deleted = calls.iter().find(|call| call.function == "glDeleteTextures").unwrap();
+        assert!(!deleted.args.contains(&(color.0 as i64)) && !deleted.args.contains(&(depth.0 as i64)));
+        // Attached again in place of the incomplete ones
+        let attached: Vec<_> = calls
+            .iter()
+            .filter(|call| call.function == "glFramebufferTexture2D")
+            .map(|call| call.args[3])
+            .collect();
+        assert_eq!(attached[attached.len() - 2..], [color.0 as i64, depth.0 as i64]);
+
+        backend.set_framebuffer_status(GL_FRAMEBUFFER_COMPLETE);
+        framebuffer.resize(&mut gl, 640, 480).unwrap();
+        assert_eq!(framebuffer.texture_size(), (640, 480));
+        let deleted = backend.take_calls().into_iter().find(|call| call.function == "glDeleteTextures");
+        assert_eq!(deleted.unwrap().args, [color.0 as i64, depth.0 as i64]);
+    }
+
+    #[test]
+    fn puts_the_render_state_back_after_drawing() {
+        let (backend, mut gl) = state_manager();
+        let resources = crate::resources::ResourceManager::new();
+        let mut shader = Shader::load(&gl, &resources, "blit_screen").unwrap();
+        let mut framebuffer = Framebuffer::new(&mut gl, 320, 240).unwrap();
+        gl.enable_depth_test();
+        gl.enable_blend();
+        backend.take_calls();
+        framebuffer.draw(&mut gl, &mut shader, &StandardUniforms::new(), 320, 240, true);
+        let calls = backend.take_calls();
+        let toggles: Vec<_> = calls
+            .iter()
+            .filter(|call| call.function == "glEnable" || call.function == "glDisable")
+            .map(|call| (call.function, call.args[0]))
+            .collect();
+        assert_eq!(
+            toggles,
+            [
+                ("glDisable", GL_BLEND.0 as i64),
+                ("glDisable", GL_DEPTH_TEST.0 as i64),
+                ("glEnable", GL_DEPTH_TEST.0 as i64),
+                ("glEnable", GL_BLEND.0 as i64),
+            ]
+        );
+    }
+}